@@ -49,6 +49,8 @@ ln -sf /etc/systemd/system/ghostly-agent.service \
     "$ROOTFS_DIR/etc/systemd/system/multi-user.target.wants/ghostly-agent.service"
 ln -sf /usr/lib/systemd/system/seatd.service \
     "$ROOTFS_DIR/etc/systemd/system/multi-user.target.wants/seatd.service"
+ln -sf /usr/lib/systemd/system/systemd-networkd.service \
+    "$ROOTFS_DIR/etc/systemd/system/multi-user.target.wants/systemd-networkd.service"
 
 # -------------------------------------------------------
 # Step 4: System configuration
@@ -71,6 +73,22 @@ cat > "$ROOTFS_DIR/etc/fstab" <<'FSTAB'
 /dev/vda    /    ext4    defaults,noatime    0 1
 FSTAB
 
+# DHCP on the QEMU user-mode NIC (agent is reached via hostfwd on macOS/Windows)
+mkdir -p "$ROOTFS_DIR/etc/systemd/network"
+cat > "$ROOTFS_DIR/etc/systemd/network/20-wired.network" <<'NETWORK'
+[Match]
+Name=en* eth*
+
+[Network]
+DHCP=yes
+NETWORK
+
+# Load the virtio vsock transport early so the agent can bind its listener
+mkdir -p "$ROOTFS_DIR/etc/modules-load.d"
+echo "vmw_vsock_virtio_transport" > "$ROOTFS_DIR/etc/modules-load.d/vsock.conf"
+# fw_cfg sysfs: the host passes the agent's authorized keys this way
+echo "qemu_fw_cfg" > "$ROOTFS_DIR/etc/modules-load.d/fw_cfg.conf"
+
 # Serial console getty (debug access via serial)
 mkdir -p "$ROOTFS_DIR/etc/systemd/system/serial-getty@ttyS0.service.d"
 cat > "$ROOTFS_DIR/etc/systemd/system/serial-getty@ttyS0.service.d/autologin.conf" <<'GETTY'
@@ -140,15 +158,17 @@ find "$ROOTFS_DIR/usr/lib/dri/" \( -type f -o -type l \) \
     ! -name 'zink_dri.so' \
     -delete 2>/dev/null || true
 
-# --- Unused kernel modules (keep virtio, drm, gpu + their dependencies) ---
+# --- Unused kernel modules (keep virtio, vsock, drm, gpu + their dependencies) ---
 # drm_kms_helper depends on: fb_sys_fops, sysimgblt, sysfillrect, syscopyarea, i2c
 if [ -d "$ROOTFS_DIR/usr/lib/modules" ]; then
     find "$ROOTFS_DIR/usr/lib/modules" -type f -name '*.ko*' \
         ! -path '*/virtio*' \
+        ! -path '*/vmw_vsock/*' \
         ! -path '*/drm*' \
         ! -path '*/gpu*' \
         ! -path '*/i2c*' \
         ! -path '*/video*' \
+        ! -name 'qemu_fw_cfg.ko*' \
         ! -name 'fb.ko*' \
         ! -name 'fb_sys_fops.ko*' \
         ! -name 'sysimgblt.ko*' \
@@ -0,0 +1,133 @@
+#![cfg(unix)]
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_vsock::{VsockAddr, VsockStream};
+use tracing::{debug, warn};
+
+use crate::keys;
+
+/// fw_cfg blob naming where the host listens: `vsock <cid> <port>` or `tcp <address> <port>`.
+const FW_CFG_EVENTS: &str = "/sys/firmware/qemu_fw_cfg/by_name/opt/virtualghost/events/raw";
+
+/// World-readable copy of the fw_cfg blob, which only root can read, for
+/// events sent by unprivileged guest programs.
+const PUBLISHED_EVENTS: &str = "/run/ghostly-agent/events";
+
+/// How long to wait for the host to take an event.
+const SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Something to tell the host without being asked, one JSON object per line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    BootComplete,
+    GhosttyExited { code: i32 },
+    Notification { title: String, body: String },
+    Error { message: String },
+}
+
+impl Event {
+    /// Parse the arguments of `ghostly-agent event <kind> ...`.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let usage = "usage: ghostly-agent event ghostty-exited <code>|notification <title> [body]|error <message>";
+        let event = match args {
+            [kind, code] if kind == "ghostty-exited" => Self::GhosttyExited {
+                code: exit_code(code)?,
+            },
+            [kind, title] if kind == "notification" => Self::Notification {
+                title: title.clone(),
+                body: String::new(),
+            },
+            [kind, title, body] if kind == "notification" => Self::Notification {
+                title: title.clone(),
+                body: body.clone(),
+            },
+            [kind, message] if kind == "error" => Self::Error {
+                message: message.clone(),
+            },
+            _ => bail!(usage),
+        };
+        Ok(event)
+    }
+}
+
+/// An exit status as systemd's `$EXIT_STATUS` gives it: a number, or a signal
+/// name such as `TERM`, which becomes 128 + its number like in a shell.
+fn exit_code(status: &str) -> Result<i32> {
+    if let Ok(code) = status.parse() {
+        return Ok(code);
+    }
+    let signal: nix::sys::signal::Signal = format!("SIG{status}")
+        .parse()
+        .with_context(|| format!("invalid exit status {status:?}"))?;
+    Ok(128 + signal as i32)
+}
+
+/// Copy where the host listens to [`PUBLISHED_EVENTS`] so any user can send events.
+pub fn publish_address() {
+    let Some((_, address)) = keys::read_sources(FW_CFG_EVENTS, PUBLISHED_EVENTS)
+        .into_iter()
+        .next()
+    else {
+        return;
+    };
+    let path = std::path::Path::new(PUBLISHED_EVENTS);
+    let published = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(path, address));
+    if let Err(e) = published {
+        warn!(path = PUBLISHED_EVENTS, error = %e, "Cannot publish the event address");
+    }
+}
+
+/// Send an event to the host launcher, if it is listening.
+pub async fn send(event: &Event) -> Result<()> {
+    let Some((source, address)) = keys::read_sources(FW_CFG_EVENTS, PUBLISHED_EVENTS)
+        .into_iter()
+        .next()
+    else {
+        bail!("the host did not say where to send events");
+    };
+    let address = String::from_utf8_lossy(&address).trim().to_string();
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+
+    debug!(source, address, ?event, "Sending event to the host");
+    let result = tokio::time::timeout(SEND_TIMEOUT, async {
+        match address.split_whitespace().collect::<Vec<_>>()[..] {
+            ["vsock", cid, port] => {
+                let addr = VsockAddr::new(cid.parse()?, port.parse()?);
+                write_event(VsockStream::connect(addr).await?, &line).await
+            }
+            ["tcp", host, port] => {
+                let port: u16 = port.parse()?;
+                write_event(TcpStream::connect((host, port)).await?, &line).await
+            }
+            [] => bail!("the host is not listening for events"),
+            _ => bail!("unknown event address {address:?}"),
+        }
+    })
+    .await;
+    match result {
+        Ok(sent) => sent,
+        Err(_) => bail!("the host did not take the event within {SEND_TIMEOUT:?}"),
+    }
+}
+
+/// Like [`send`], for events nothing waits on: failures are only logged.
+pub async fn report(event: Event) {
+    if let Err(e) = send(&event).await {
+        debug!(?event, error = %e, "Event not delivered");
+    }
+}
+
+async fn write_event<S: AsyncWrite + Unpin>(mut stream: S, line: &[u8]) -> Result<()> {
+    stream.write_all(line).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,137 @@
+#![cfg(unix)]
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+
+/// How often to look for vCPUs the host hot-added.
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bring vCPUs the host hot-adds online; Linux leaves them offline.
+///
+/// CPUs present when the agent starts are left alone, so one taken offline on
+/// purpose stays that way.
+pub async fn online_added_cpus() {
+    let cpu_dir = Path::new(CPU_DIR);
+    let mut known = cpus(cpu_dir);
+    loop {
+        tokio::time::sleep(SCAN_INTERVAL).await;
+        online_new(cpu_dir, &mut known);
+    }
+}
+
+/// Online the CPUs in `cpu_dir` missing from `known`, and remember them.
+fn online_new(cpu_dir: &Path, known: &mut HashSet<PathBuf>) {
+    for cpu in cpus(cpu_dir) {
+        if !known.contains(&cpu) && online(&cpu) {
+            known.insert(cpu);
+        }
+    }
+}
+
+/// `cpuN` directories in sysfs.
+fn cpus(cpu_dir: &Path) -> HashSet<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(cpu_dir) else {
+        return HashSet::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.strip_prefix("cpu")
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .map(|entry| entry.path())
+        .collect()
+}
+
+/// Online a CPU; `false` if it should be tried again on the next scan.
+fn online(cpu: &Path) -> bool {
+    let control = cpu.join("online");
+    match std::fs::read_to_string(&control) {
+        Ok(state) if state.trim() == "1" => return true,
+        Ok(_) => {}
+        // Not ready yet: the kernel adds the control file after the directory
+        Err(_) => return false,
+    }
+    match std::fs::write(&control, "1") {
+        Ok(()) => info!(cpu = %cpu.display(), "Hot-added CPU online"),
+        Err(e) => warn!(cpu = %cpu.display(), error = %e, "Failed to online hot-added CPU"),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CPU directory as sysfs shows it, with its `online` control if given.
+    fn add_cpu(cpu_dir: &Path, name: &str, online: Option<&str>) -> PathBuf {
+        let cpu = cpu_dir.join(name);
+        std::fs::create_dir(&cpu).unwrap();
+        if let Some(state) = online {
+            std::fs::write(cpu.join("online"), state).unwrap();
+        }
+        cpu
+    }
+
+    fn state(cpu: &Path) -> String {
+        std::fs::read_to_string(cpu.join("online")).unwrap()
+    }
+
+    #[test]
+    fn only_cpu_directories_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let cpu0 = add_cpu(dir.path(), "cpu0", None);
+        let cpu12 = add_cpu(dir.path(), "cpu12", Some("1\n"));
+        for other in ["cpu", "cpufreq", "cpuidle", "cpu1a"] {
+            add_cpu(dir.path(), other, None);
+        }
+        std::fs::write(dir.path().join("possible"), "0-15\n").unwrap();
+
+        assert_eq!(cpus(dir.path()), [cpu0, cpu12].into());
+        assert!(cpus(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn offline_cpus_are_brought_online() {
+        let dir = tempfile::tempdir().unwrap();
+        let offline = add_cpu(dir.path(), "cpu1", Some("0\n"));
+        assert!(online(&offline));
+        assert_eq!(state(&offline), "1");
+
+        let running = add_cpu(dir.path(), "cpu2", Some("1\n"));
+        assert!(online(&running));
+        assert_eq!(state(&running), "1\n");
+
+        // The kernel has not added the control file yet
+        assert!(!online(&add_cpu(dir.path(), "cpu3", None)));
+    }
+
+    #[test]
+    fn only_hot_added_cpus_are_touched() {
+        let dir = tempfile::tempdir().unwrap();
+        add_cpu(dir.path(), "cpu0", None);
+        // Taken offline on purpose before the agent started
+        let parked = add_cpu(dir.path(), "cpu1", Some("0\n"));
+        let mut known = cpus(dir.path());
+
+        let added = add_cpu(dir.path(), "cpu2", Some("0\n"));
+        let pending = add_cpu(dir.path(), "cpu3", None);
+        online_new(dir.path(), &mut known);
+        assert_eq!(state(&parked), "0\n");
+        assert_eq!(state(&added), "1");
+        assert!(known.contains(&added));
+        assert!(!known.contains(&pending));
+
+        // Retried on the next scan, once its control file shows up
+        std::fs::write(pending.join("online"), "0\n").unwrap();
+        online_new(dir.path(), &mut known);
+        assert_eq!(state(&pending), "1");
+        assert!(known.contains(&pending));
+    }
+}
@@ -1,6 +1,16 @@
 use anyhow::Result;
 use tracing::info;
 
+#[cfg(unix)]
+mod events;
+#[cfg(unix)]
+mod hotplug;
+#[cfg(unix)]
+mod keys;
+#[cfg(unix)]
+mod process;
+#[cfg(unix)]
+mod pty;
 #[cfg(unix)]
 mod server;
 
@@ -12,10 +22,30 @@ async fn main() -> Result<()> {
         .with_env_filter("ghostly_agent=debug")
         .init();
 
+    // `ghostly-agent event <kind> ...` sends one event to the host and exits
+    #[cfg(unix)]
+    {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if let Some(("event", args)) = args.split_first().map(|(cmd, rest)| (cmd.as_str(), rest)) {
+            return events::send(&events::Event::from_args(args)?).await;
+        }
+    }
+
     info!("Ghostly Agent starting on vsock port {VSOCK_PORT}");
 
     #[cfg(unix)]
-    server::run(VSOCK_PORT).await?;
+    tokio::spawn(hotplug::online_added_cpus());
+    #[cfg(unix)]
+    events::publish_address();
+
+    #[cfg(unix)]
+    if let Err(e) = server::run(VSOCK_PORT).await {
+        events::report(events::Event::Error {
+            message: format!("ghostly-agent failed: {e:#}"),
+        })
+        .await;
+        return Err(e);
+    }
 
     #[cfg(not(unix))]
     anyhow::bail!("Ghostly Agent only runs on Linux (inside the VirtualGhost VM)");
@@ -0,0 +1,252 @@
+#![cfg(unix)]
+
+use anyhow::{Context, Result};
+use nix::unistd::{Gid, Uid, User};
+use std::ffi::CString;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::process::{Child, Command};
+
+use crate::pty::{self, PtyMaster, Terminal};
+
+/// Account that sessions run as (created by build-rootfs.sh).
+pub const SESSION_USER: &str = "ghostty";
+
+/// Identity a session process runs as, resolved before forking.
+struct SessionUser {
+    name: String,
+    uid: Uid,
+    gid: Gid,
+    groups: Vec<Gid>,
+    home: PathBuf,
+    shell: PathBuf,
+}
+
+impl SessionUser {
+    fn lookup(name: &str) -> Result<Self> {
+        let user = User::from_name(name)?.with_context(|| format!("user {name} not found"))?;
+        let groups = nix::unistd::getgrouplist(&CString::new(name)?, user.gid)
+            .with_context(|| format!("failed to list groups of {name}"))?;
+        Ok(Self {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            groups,
+            home: user.dir,
+            shell: user.shell,
+        })
+    }
+}
+
+/// A session process and the PTY carrying its I/O, if one was requested.
+///
+/// Without a PTY the child's stdin, stdout and stderr are pipes left on [`Child`].
+pub struct Spawned {
+    pub child: Child,
+    pub pty: Option<PtyMaster>,
+}
+
+/// Start a process for a session channel as [`SESSION_USER`].
+///
+/// With `command` the user's shell runs it via `-c` (like `sshd` does for
+/// `exec` requests); without one it starts a login shell.
+pub fn spawn(
+    command: Option<&str>,
+    terminal: Option<&Terminal>,
+    env: &[(String, String)],
+) -> Result<Spawned> {
+    spawn_as(&SessionUser::lookup(SESSION_USER)?, command, terminal, env)
+}
+
+fn spawn_as(
+    user: &SessionUser,
+    command: Option<&str>,
+    terminal: Option<&Terminal>,
+    env: &[(String, String)],
+) -> Result<Spawned> {
+    let mut cmd = Command::new(&user.shell);
+    match command {
+        Some(command) => {
+            cmd.arg("-c").arg(command);
+        }
+        None => {
+            // Leading '-' in argv[0] makes the shell behave as a login shell
+            let shell_name = user
+                .shell
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "sh".into());
+            cmd.arg0(format!("-{shell_name}"));
+        }
+    }
+    cmd.current_dir(&user.home)
+        .env_clear()
+        .envs(env.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .env("HOME", &user.home)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("SHELL", &user.shell)
+        .env("PATH", "/usr/local/sbin:/usr/local/bin:/usr/bin");
+
+    let master = match terminal {
+        Some(terminal) => {
+            let (master, slave) = pty::open(terminal.cols, terminal.rows)?;
+            cmd.env("TERM", &terminal.term)
+                .stdin(Stdio::from(slave.try_clone()?))
+                .stdout(Stdio::from(slave.try_clone()?))
+                .stderr(Stdio::from(slave));
+            Some(master)
+        }
+        None => {
+            cmd.stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            None
+        }
+    };
+
+    let controlling_tty = master.is_some();
+    let (uid, gid, groups) = (user.uid, user.gid, user.groups.clone());
+    // An agent already running as the user has no identity to switch
+    let switch_user = uid != Uid::current();
+    // SAFETY: only async-signal-safe syscalls run between fork and exec; the
+    // group list was resolved above so nothing here allocates.
+    unsafe {
+        cmd.pre_exec(move || {
+            // A new session also gives the process its own group, so closing
+            // the channel can signal everything it started
+            nix::unistd::setsid()?;
+            // stdin is the PTY slave: make it our controlling terminal
+            if controlling_tty && libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if switch_user {
+                nix::unistd::setgroups(&groups)?;
+                nix::unistd::setgid(gid)?;
+                nix::unistd::setuid(uid)?;
+            }
+            Ok(())
+        });
+    }
+
+    let child = cmd
+        .spawn()
+        .with_context(|| format!("failed to spawn {}", user.shell.display()))?;
+
+    Ok(Spawned { child, pty: master })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The user running the tests, with a shell and home of its own.
+    fn test_user(home: &std::path::Path) -> SessionUser {
+        let user = User::from_uid(Uid::current()).unwrap().unwrap();
+        SessionUser {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            groups: Vec::new(),
+            home: home.to_path_buf(),
+            shell: "/bin/sh".into(),
+        }
+    }
+
+    async fn output(spawned: Spawned) -> String {
+        let output = spawned.child.wait_with_output().await.unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[tokio::test]
+    async fn exec_runs_the_command_through_the_shell_in_home() {
+        let home = tempfile::tempdir().unwrap();
+        let user = test_user(home.path());
+        let spawned = spawn_as(&user, Some("pwd; echo \"$0\" >&2; exit 3"), None, &[]).unwrap();
+        assert!(spawned.pty.is_none());
+        let output = spawned.child.wait_with_output().await.unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            home.path().canonicalize().unwrap().to_str().unwrap()
+        );
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), "/bin/sh\n");
+    }
+
+    #[tokio::test]
+    async fn shell_without_a_command_is_a_login_shell() {
+        use tokio::io::AsyncWriteExt;
+
+        let home = tempfile::tempdir().unwrap();
+        let mut spawned = spawn_as(&test_user(home.path()), None, None, &[]).unwrap();
+        let mut stdin = spawned.child.stdin.take().unwrap();
+        stdin.write_all(b"echo \"$0\"\nexit\n").await.unwrap();
+        drop(stdin);
+        assert_eq!(output(spawned).await, "-sh\n");
+    }
+
+    #[tokio::test]
+    async fn environment_is_the_clients_plus_the_users() {
+        let home = tempfile::tempdir().unwrap();
+        let user = test_user(home.path());
+        let env = [
+            ("LANG".to_string(), "C.UTF-8".to_string()),
+            // The session's own variables win over what the client sends
+            ("HOME".to_string(), "/elsewhere".to_string()),
+            ("USER".to_string(), "someone".to_string()),
+        ];
+        let script = "printf '%s\\n' \"$LANG\" \"$HOME\" \"$USER\" \"$LOGNAME\" \"$SHELL\" \
+                      \"$PATH\" \"${TERM-unset}\" \"${CARGO_MANIFEST_DIR-unset}\"";
+        let spawned = spawn_as(&user, Some(script), None, &env).unwrap();
+        let output = output(spawned).await;
+        let home = home.path().display().to_string();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "C.UTF-8",
+                &home,
+                &user.name,
+                &user.name,
+                "/bin/sh",
+                "/usr/local/sbin:/usr/local/bin:/usr/bin",
+                "unset",
+                "unset",
+            ]
+        );
+        // Nothing leaks from the agent's own environment
+        assert!(std::env::var_os("CARGO_MANIFEST_DIR").is_some());
+    }
+
+    #[tokio::test]
+    async fn terminal_sessions_run_on_the_pty() {
+        let home = tempfile::tempdir().unwrap();
+        let terminal = Terminal {
+            term: "xterm-ghostty".to_string(),
+            cols: 120,
+            rows: 40,
+        };
+        let script = "tty -s && echo \"$TERM\" && stty size";
+        let spawned =
+            spawn_as(&test_user(home.path()), Some(script), Some(&terminal), &[]).unwrap();
+        let master = spawned.pty.unwrap();
+        assert!(spawned.child.stdout.is_none());
+
+        let mut output = Vec::new();
+        let mut buf = [0; 256];
+        loop {
+            let n = master.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+        let status = spawned.child.wait_with_output().await.unwrap().status;
+        assert!(status.success());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "xterm-ghostty\r\n40 120\r\n"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+#![cfg(unix)]
+
+use anyhow::{Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::pty::{openpty, Winsize};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+
+/// Master side of a pseudo-terminal, readable and writable from async code.
+pub struct PtyMaster {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl PtyMaster {
+    /// Read output from the PTY. Returns `Ok(0)` once the slave side is closed.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| nix::unistd::read(fd.as_raw_fd(), buf).map_err(io::Error::from))
+            {
+                Ok(Ok(n)) => return Ok(n),
+                // Linux reports EIO on the master once every slave fd is closed
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Ok(0),
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Read whatever output is already buffered without waiting for more.
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match nix::unistd::read(self.fd.get_ref().as_raw_fd(), buf) {
+            Ok(n) => Ok(n),
+            Err(nix::errno::Errno::EAGAIN | nix::errno::Errno::EIO) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write input (keystrokes) to the PTY.
+    pub async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| nix::unistd::write(fd.get_ref(), data).map_err(io::Error::from))
+            {
+                Ok(Ok(n)) => data = &data[n..],
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a new terminal size; the kernel delivers SIGWINCH to the foreground job.
+    pub fn resize(&self, cols: u32, rows: u32) -> io::Result<()> {
+        let ws = winsize(cols, rows);
+        // SAFETY: TIOCSWINSZ reads a `winsize` struct from a valid pointer.
+        let ret = unsafe { libc::ioctl(self.fd.get_ref().as_raw_fd(), libc::TIOCSWINSZ, &ws) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Terminal requested by the client (`pty-req`).
+pub struct Terminal {
+    pub term: String,
+    pub cols: u32,
+    pub rows: u32,
+}
+
+/// Allocate a PTY of the given size, returning the non-blocking master and the slave fd.
+pub fn open(cols: u32, rows: u32) -> Result<(PtyMaster, OwnedFd)> {
+    let pty = openpty(&winsize(cols, rows), None).context("openpty failed")?;
+
+    let flags = OFlag::from_bits_truncate(fcntl(pty.master.as_raw_fd(), FcntlArg::F_GETFL)?);
+    fcntl(
+        pty.master.as_raw_fd(),
+        FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+    )?;
+    let master = PtyMaster {
+        fd: AsyncFd::new(pty.master)?,
+    };
+
+    Ok((master, pty.slave))
+}
+
+fn winsize(cols: u32, rows: u32) -> Winsize {
+    Winsize {
+        ws_row: rows.min(u16::MAX as u32) as u16,
+        ws_col: cols.min(u16::MAX as u32) as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsFd;
+
+    /// The size the terminal on `fd` reports, as (cols, rows).
+    fn size(fd: impl AsFd) -> (u16, u16) {
+        let mut ws = winsize(0, 0);
+        // SAFETY: TIOCGWINSZ writes a `winsize` struct to a valid pointer.
+        let ret = unsafe { libc::ioctl(fd.as_fd().as_raw_fd(), libc::TIOCGWINSZ, &mut ws) };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        (ws.ws_col, ws.ws_row)
+    }
+
+    #[tokio::test]
+    async fn opens_with_the_requested_size() {
+        let (_master, slave) = open(132, 43).unwrap();
+        assert_eq!(size(&slave), (132, 43));
+    }
+
+    #[tokio::test]
+    async fn window_changes_resize_the_terminal() {
+        let (master, slave) = open(80, 24).unwrap();
+        master.resize(200, 60).unwrap();
+        assert_eq!(size(&slave), (200, 60));
+        // Sizes beyond what the kernel can hold are clamped, not wrapped
+        master.resize(100_000, 70_000).unwrap();
+        assert_eq!(size(&slave), (u16::MAX, u16::MAX));
+    }
+
+    #[tokio::test]
+    async fn carries_input_and_output_until_the_slave_closes() {
+        let (master, slave) = open(80, 24).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(master.try_read(&mut buf).unwrap(), 0);
+
+        // Output, with the terminal turning newlines into CRLF
+        nix::unistd::write(&slave, b"hello\n").unwrap();
+        let n = master.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello\r\n");
+
+        // Input, handed to the slave a line at a time
+        master.write_all(b"typed\n").await.unwrap();
+        let mut input = [0; 64];
+        let n = nix::unistd::read(slave.as_raw_fd(), &mut input).unwrap();
+        assert_eq!(&input[..n], b"typed\n");
+
+        drop(slave);
+        // What is left is the echo of the input, then the end of output
+        let mut rest = Vec::new();
+        loop {
+            let n = master.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            rest.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(rest, b"typed\r\n");
+    }
+}
@@ -1,38 +1,153 @@
 #![cfg(unix)]
 
 use anyhow::Result;
-use russh::server::{self, Auth, Msg, Session};
-use russh::{Channel, ChannelId};
+use russh::server::{self, Auth, Handle, Msg, Server as _, Session};
+use russh::{Channel, ChannelId, CryptoVec, MethodSet};
 use ssh_key::public::PublicKey;
 use std::collections::HashMap;
-use tracing::info;
+use std::os::unix::process::ExitStatusExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::process::{Child, ChildStdin};
+use tokio::sync::mpsc;
+use tokio_vsock::{VsockAddr, VsockListener, VMADDR_CID_ANY};
+use tracing::{debug, info, warn};
+
+use crate::events;
+use crate::keys;
+use crate::process;
+use crate::pty::{PtyMaster, Terminal};
+
+/// Extended data type code for stderr (RFC 4254 §5.2).
+const SSH_EXTENDED_DATA_STDERR: u32 = 1;
+
+/// OpenSSH's SFTP server (from the rootfs `openssh` package), run for the `sftp` subsystem.
+const SFTP_SERVER: &str = "/usr/lib/ssh/sftp-server";
+
+/// TCP port the agent listens on for hosts without vsock (QEMU `hostfwd` targets guest :22).
+const TCP_PORT: u16 = 22;
 
 pub async fn run(port: u32) -> Result<()> {
-    info!(port, "SSH server ready (vsock listener not yet implemented)");
-
-    // TODO: Replace with tokio-vsock VsockListener
-    // let listener = VsockListener::bind(libc::VMADDR_CID_ANY, port)?;
-    // loop {
-    //     let (stream, addr) = listener.accept().await?;
-    //     tokio::spawn(handle_connection(stream));
-    // }
-
-    // Placeholder: wait forever
-    tokio::signal::ctrl_c().await?;
-    info!("Shutting down");
-    Ok(())
+    let host_key = keys::load_host_key()?;
+    let config = Arc::new(server::Config {
+        methods: MethodSet::PUBLICKEY,
+        auth_rejection_time: Duration::from_secs(1),
+        auth_rejection_time_initial: Some(Duration::ZERO),
+        keys: vec![host_key],
+        ..Default::default()
+    });
+    let authorized_keys = keys::load_authorized_keys();
+    if authorized_keys.is_empty() {
+        tokio::spawn(events::report(events::Event::Error {
+            message: "the agent got no authorized keys and rejects every login".into(),
+        }));
+    }
+    let mut server = GhostlyServer {
+        authorized_keys: Arc::new(authorized_keys),
+    };
+
+    let mut vsock = match VsockListener::bind(VsockAddr::new(VMADDR_CID_ANY, port)) {
+        Ok(listener) => {
+            info!(port, "Listening on vsock");
+            Some(listener)
+        }
+        Err(e) => {
+            warn!(port, error = %e, "vsock unavailable");
+            None
+        }
+    };
+    let tcp = match TcpListener::bind(("0.0.0.0", TCP_PORT)).await {
+        Ok(listener) => {
+            info!(port = TCP_PORT, "Listening on TCP");
+            Some(listener)
+        }
+        Err(e) => {
+            warn!(port = TCP_PORT, error = %e, "TCP listener unavailable");
+            None
+        }
+    };
+    if vsock.is_none() && tcp.is_none() {
+        anyhow::bail!("no listener could be bound (vsock port {port}, TCP port {TCP_PORT})");
+    }
+    tokio::spawn(events::report(events::Event::BootComplete));
+
+    loop {
+        tokio::select! {
+            accepted = async { vsock.as_mut().unwrap().accept().await }, if vsock.is_some() => {
+                match accepted {
+                    Ok((stream, addr)) => {
+                        info!(cid = addr.cid(), port = addr.port(), "vsock connection");
+                        spawn_session(config.clone(), stream, server.new_client(None));
+                    }
+                    Err(e) => warn!(error = %e, "vsock accept failed"),
+                }
+            }
+            accepted = async { tcp.as_ref().unwrap().accept().await }, if tcp.is_some() => {
+                match accepted {
+                    Ok((stream, addr)) => {
+                        info!(%addr, "TCP connection");
+                        let _ = stream.set_nodelay(true);
+                        spawn_session(config.clone(), stream, server.new_client(Some(addr)));
+                    }
+                    Err(e) => warn!(error = %e, "TCP accept failed"),
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Run the SSH protocol for one connection in the background.
+fn spawn_session<S>(config: Arc<server::Config>, stream: S, handler: GhostlySession)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        match server::run_stream(config, stream, handler).await {
+            Ok(session) => match session.await {
+                Ok(()) => debug!("Session ended"),
+                Err(e) => warn!(error = %e, "Session failed"),
+            },
+            Err(e) => warn!(error = %e, "SSH handshake failed"),
+        }
+    });
 }
 
 struct GhostlyServer {
-    _authorized_keys: Vec<PublicKey>,
+    authorized_keys: Arc<Vec<PublicKey>>,
 }
 
 struct GhostlySession {
+    authorized_keys: Arc<Vec<PublicKey>>,
     channels: HashMap<ChannelId, ChannelState>,
 }
 
+#[derive(Default)]
 struct ChannelState {
-    _pty_requested: bool,
+    pty: Option<Terminal>,
+    env: Vec<(String, String)>,
+    process: Option<RunningProcess>,
+}
+
+/// A process started on a channel.
+struct RunningProcess {
+    input: ProcessInput,
+    pid: Option<u32>,
+}
+
+/// Where channel data for a running process goes.
+enum ProcessInput {
+    Pty(Arc<PtyMaster>),
+    /// Queue feeding the child's stdin pipe; `None` once the client sent EOF.
+    ///
+    /// Writes happen on a separate task so a child that stops reading stdin
+    /// cannot stall the session (and with it, the child's own output).
+    Pipe(Option<mpsc::UnboundedSender<Vec<u8>>>),
 }
 
 impl server::Server for GhostlyServer {
@@ -40,11 +155,91 @@ impl server::Server for GhostlyServer {
 
     fn new_client(&mut self, _peer_addr: Option<std::net::SocketAddr>) -> Self::Handler {
         GhostlySession {
+            authorized_keys: self.authorized_keys.clone(),
             channels: HashMap::new(),
         }
     }
 }
 
+impl GhostlySession {
+    fn check_key(&self, user: &str, public_key: &PublicKey) -> Auth {
+        // Compare key material only; comments differ between host and guest copies
+        if self
+            .authorized_keys
+            .iter()
+            .any(|key| key.key_data() == public_key.key_data())
+        {
+            Auth::Accept
+        } else {
+            warn!(user, fingerprint = %public_key.fingerprint(Default::default()), "Rejected unknown key");
+            Auth::Reject {
+                proceed_with_methods: None,
+            }
+        }
+    }
+
+    fn reply(session: &mut Session, channel: ChannelId, ok: bool) -> Result<()> {
+        if ok {
+            session.channel_success(channel)?;
+        } else {
+            session.channel_failure(channel)?;
+        }
+        Ok(())
+    }
+
+    /// Start a login shell (`command` is `None`) or a command on `channel`.
+    fn start_process(
+        &mut self,
+        channel: ChannelId,
+        command: Option<&str>,
+        session: &mut Session,
+    ) -> Result<()> {
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Self::reply(session, channel, false);
+        };
+        if state.process.is_some() {
+            warn!(?channel, "Process requested twice on one channel");
+            return Self::reply(session, channel, false);
+        }
+
+        let mut spawned = match process::spawn(command, state.pty.as_ref(), &state.env) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                warn!(?channel, error = %e, "Failed to start process");
+                return Self::reply(session, channel, false);
+            }
+        };
+        let pid = spawned.child.id();
+        info!(
+            ?channel,
+            pid,
+            command,
+            pty = spawned.pty.is_some(),
+            "Process started"
+        );
+
+        let handle = session.handle();
+        let input = match spawned.pty {
+            Some(master) => {
+                let master = Arc::new(master);
+                tokio::spawn(forward_pty(handle, channel, master.clone(), spawned.child));
+                ProcessInput::Pty(master)
+            }
+            None => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                if let Some(stdin) = spawned.child.stdin.take() {
+                    tokio::spawn(feed_stdin(stdin, rx));
+                }
+                tokio::spawn(forward_pipes(handle, channel, spawned.child));
+                ProcessInput::Pipe(Some(tx))
+            }
+        };
+        state.process = Some(RunningProcess { input, pid });
+
+        Self::reply(session, channel, true)
+    }
+}
+
 #[async_trait::async_trait]
 impl server::Handler for GhostlySession {
     type Error = anyhow::Error;
@@ -54,33 +249,281 @@ impl server::Handler for GhostlySession {
         channel: Channel<Msg>,
         _session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        self.channels.insert(
-            channel.id(),
-            ChannelState {
-                _pty_requested: false,
-            },
-        );
+        self.channels.insert(channel.id(), ChannelState::default());
         Ok(true)
     }
 
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        Ok(self.check_key(user, public_key))
+    }
+
     async fn auth_publickey(
         &mut self,
-        _user: &str,
-        _public_key: &PublicKey,
+        user: &str,
+        public_key: &PublicKey,
     ) -> Result<Auth, Self::Error> {
-        // TODO: Check against authorized_keys list
-        Ok(Auth::Accept)
+        Ok(self.check_key(user, public_key))
     }
 
-    async fn data(
+    #[allow(clippy::too_many_arguments)]
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(russh::Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Self::reply(session, channel, false);
+        };
+        debug!(?channel, term, col_width, row_height, "PTY requested");
+        state.pty = Some(Terminal {
+            term: term.to_string(),
+            cols: col_width,
+            rows: row_height,
+        });
+        Self::reply(session, channel, true)
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Self::reply(session, channel, false);
+        };
+        state
+            .env
+            .push((variable_name.to_string(), variable_value.to_string()));
+        Self::reply(session, channel, true)
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.start_process(channel, None, session)
+    }
+
+    async fn exec_request(
         &mut self,
         channel: ChannelId,
         data: &[u8],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        // TODO: Forward data to PTY
-        // For now, echo back
-        session.data(channel, data.to_vec().into())?;
+        let Ok(command) = std::str::from_utf8(data) else {
+            warn!(?channel, "Rejected non-UTF-8 command");
+            return Self::reply(session, channel, false);
+        };
+        self.start_process(channel, Some(command), session)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        // Like sshd, hand the subsystem to a helper running as the session user
+        // so transferred files get the right owner and permission checks
+        if name != "sftp" || !std::path::Path::new(SFTP_SERVER).exists() {
+            warn!(?channel, name, "Unsupported subsystem");
+            return Self::reply(session, channel, false);
+        }
+        self.start_process(channel, Some(SFTP_SERVER), session)
+    }
+
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let Some(state) = self.channels.get_mut(&channel) else {
+            return Ok(());
+        };
+        if let Some(ref mut pty) = state.pty {
+            pty.cols = col_width;
+            pty.rows = row_height;
+        }
+        if let Some(RunningProcess {
+            input: ProcessInput::Pty(ref master),
+            ..
+        }) = state.process
+        {
+            if let Err(e) = master.resize(col_width, row_height) {
+                warn!(?channel, error = %e, "Failed to resize PTY");
+            }
+        }
+        Ok(())
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        match self
+            .channels
+            .get(&channel)
+            .and_then(|s| s.process.as_ref())
+            .map(|p| &p.input)
+        {
+            Some(ProcessInput::Pty(master)) => master.write_all(data).await?,
+            Some(ProcessInput::Pipe(Some(stdin))) => {
+                // The child may already have exited and dropped its stdin
+                let _ = stdin.send(data.to_vec());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        match self
+            .channels
+            .get_mut(&channel)
+            .and_then(|s| s.process.as_mut())
+            .map(|p| &mut p.input)
+        {
+            // A PTY has no half-close; deliver end-of-input as the terminal's EOF character
+            Some(ProcessInput::Pty(master)) => master.write_all(&[0x04]).await?,
+            // Dropping the queue closes the child's stdin once pending data is written
+            Some(ProcessInput::Pipe(stdin)) => drop(stdin.take()),
+            None => {}
+        }
+        Ok(())
+    }
+
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(state) = self.channels.remove(&channel) {
+            if let Some(pid) = state.process.and_then(|p| p.pid) {
+                // The process leads its own session; hang up the whole process group
+                let pgid = nix::unistd::Pid::from_raw(-(pid as i32));
+                let _ = nix::sys::signal::kill(pgid, nix::sys::signal::Signal::SIGHUP);
+            }
+        }
         Ok(())
     }
 }
+
+/// Pump PTY output to the channel until the process exits, then report its status.
+async fn forward_pty(handle: Handle, channel: ChannelId, master: Arc<PtyMaster>, mut child: Child) {
+    let mut buf = vec![0u8; 16 * 1024];
+    let status = loop {
+        tokio::select! {
+            read = master.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break child.wait().await,
+                Ok(n) => {
+                    if handle.data(channel, CryptoVec::from_slice(&buf[..n])).await.is_err() {
+                        break child.wait().await;
+                    }
+                }
+            },
+            status = child.wait() => {
+                // Flush output written just before exit
+                while let Ok(n) = master.try_read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    let _ = handle.data(channel, CryptoVec::from_slice(&buf[..n])).await;
+                }
+                break status;
+            }
+        }
+    };
+    finish(handle, channel, status).await;
+}
+
+/// Pump stdout as channel data and stderr as extended data until both close,
+/// then report the exit status.
+async fn forward_pipes(handle: Handle, channel: ChannelId, mut child: Child) {
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    tokio::join!(
+        forward_stream(&handle, channel, stdout, None),
+        forward_stream(&handle, channel, stderr, Some(SSH_EXTENDED_DATA_STDERR)),
+    );
+    let status = child.wait().await;
+    finish(handle, channel, status).await;
+}
+
+async fn forward_stream<R>(handle: &Handle, channel: ChannelId, stream: Option<R>, ext: Option<u32>)
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(mut stream) = stream else {
+        return;
+    };
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        let data = CryptoVec::from_slice(&buf[..n]);
+        let sent = match ext {
+            Some(ext) => handle.extended_data(channel, ext, data).await,
+            None => handle.data(channel, data).await,
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+}
+
+/// Write queued channel data to the child's stdin, closing it when the queue ends.
+async fn feed_stdin(mut stdin: ChildStdin, mut rx: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(data) = rx.recv().await {
+        if stdin.write_all(&data).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Send the exit status and close the channel.
+async fn finish(
+    handle: Handle,
+    channel: ChannelId,
+    status: std::io::Result<std::process::ExitStatus>,
+) {
+    let code = match status {
+        Ok(status) => status
+            .code()
+            .or_else(|| status.signal().map(|sig| 128 + sig))
+            .unwrap_or(255),
+        Err(e) => {
+            warn!(?channel, error = %e, "Failed to wait for process");
+            255
+        }
+    };
+    info!(?channel, code, "Process exited");
+
+    let _ = handle.exit_status_request(channel, code as u32).await;
+    let _ = handle.eof(channel).await;
+    let _ = handle.close(channel).await;
+}
@@ -1,5 +1,8 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::vm::DEFAULT_INSTANCE;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -10,13 +13,21 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Command>,
 
-    /// Number of vCPUs for the VM
-    #[arg(long, default_value_t = 2, global = true)]
-    pub vcpus: u32,
+    /// Number of vCPUs for the VM [default: vcpus in the configuration, 2]
+    #[arg(long, global = true)]
+    pub vcpus: Option<u32>,
+
+    /// Memory in MiB for the VM [default: memory_mib in the configuration, 2048]
+    #[arg(long, global = true)]
+    pub memory: Option<u32>,
 
-    /// Memory in MiB for the VM
-    #[arg(long, default_value_t = 2048, global = true)]
-    pub memory: u32,
+    /// vCPUs the VM can grow to with `cpus --add`
+    #[arg(long, global = true)]
+    pub max_vcpus: Option<u32>,
+
+    /// Memory in MiB the VM can grow to with `mem --add`
+    #[arg(long, global = true)]
+    pub max_memory: Option<u32>,
 
     /// Path to custom kernel image
     #[arg(long, global = true)]
@@ -26,19 +37,112 @@ pub struct Cli {
     #[arg(long, global = true)]
     pub rootfs: Option<PathBuf>,
 
+    /// Keep the VM's changes to the rootfs for its next boot under the same name
+    #[arg(long, global = true, conflicts_with = "ephemeral")]
+    pub persist: bool,
+
+    /// Discard the VM's changes to the rootfs when it exits (the default)
+    #[arg(long, global = true)]
+    pub ephemeral: bool,
+
+    /// Kernel command line, replacing the default one
+    #[arg(long, global = true)]
+    pub cmdline: Option<String>,
+
+    /// Extra kernel command-line argument (repeatable)
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true, global = true)]
+    pub kernel_arg: Vec<String>,
+
+    /// Path to an initramfs for the kernel
+    #[arg(long, global = true)]
+    pub initramfs: Option<PathBuf>,
+
+    /// Boot the rootfs as a disk image through UEFI firmware (OVMF) instead of the kernel directly
+    #[arg(long, global = true)]
+    pub uefi: bool,
+
+    /// Path to UEFI firmware code to boot with (implies --uefi)
+    #[arg(long, global = true)]
+    pub firmware: Option<PathBuf>,
+
     /// PCI address of GPU for VFIO passthrough (e.g., 0000:01:00.0)
     #[arg(long, global = true)]
     pub gpu: Option<String>,
 
+    /// Extra argument passed to QEMU after the generated ones (repeatable)
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true, global = true)]
+    pub qemu_arg: Vec<String>,
+
     /// Enable verbose logging
     #[arg(short, long, global = true)]
     pub verbose: bool,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum Command {
     /// Launch a VM with Ghostty (default)
-    Run,
+    Run(RunArgs),
+
+    /// Run a command inside the running VM and exit with its status
+    Exec(ExecArgs),
+
+    /// Copy files between the host and the running VM (guest paths start with `vm:`)
+    Cp(CpArgs),
+
+    /// Bridge stdin/stdout to the VM's SSH server (for OpenSSH's ProxyCommand)
+    SshProxy(InstanceArgs),
+
+    /// Print an OpenSSH `Host` block for using ssh, scp and rsync with the VM
+    SshConfig(InstanceArgs),
+
+    /// Boot a VM without a display and attach this terminal to a shell inside it
+    Shell(InstanceArgs),
+
+    /// List running and suspended VMs
+    Ps,
+
+    /// Power a running VM off, killing it if the guest does not shut down in time
+    /// (for a suspended VM, discard its saved state)
+    Stop(TargetArgs),
+
+    /// Kill a running VM immediately
+    Kill(TargetArgs),
+
+    /// Pause a running VM's vCPUs, keeping its memory
+    Pause(TargetArgs),
+
+    /// Let a paused VM run again
+    Unpause(TargetArgs),
+
+    /// Show or change how much memory a running VM's guest has
+    Mem(MemArgs),
+
+    /// Show a running VM's vCPUs, or hot-add more
+    Cpus(CpusArgs),
+
+    /// Run the automatic memory balloon policy for a VM (started by `run`)
+    #[command(hide = true)]
+    BalloonPolicy(InstanceArgs),
+
+    /// Stop a background Cloud Hypervisor once its guest powers off (started by `run`)
+    #[command(hide = true)]
+    VmmReaper(InstanceArgs),
+
+    /// Save a running VM's memory and device state to disk and stop it
+    Suspend(TargetArgs),
+
+    /// Restart a suspended VM where it left off
+    Resume(ResumeArgs),
+
+    /// Print a VM's serial console log
+    Logs(LogsArgs),
+
+    /// Attach this terminal to a VM's serial console (Ctrl-] detaches)
+    Console(TargetArgs),
+
+    /// Record a VM's serial console and serve `console` attaches (started by `run`)
+    #[command(hide = true)]
+    SerialLogger(SerialLoggerArgs),
 
     /// Show or edit configuration
     Config {
@@ -51,9 +155,260 @@ pub enum Command {
     Clean,
 }
 
+/// Which VM a command applies to.
+#[derive(Args, Debug, Clone)]
+pub struct InstanceArgs {
+    /// Name of the VM instance
+    #[arg(long, default_value = DEFAULT_INSTANCE, value_parser = parse_name)]
+    pub name: String,
+}
+
+impl Default for InstanceArgs {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_INSTANCE.to_string(),
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TargetArgs {
+    /// Name of the VM instance
+    #[arg(default_value = DEFAULT_INSTANCE, value_parser = parse_name)]
+    pub name: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SerialLoggerArgs {
+    #[command(flatten)]
+    pub instance: InstanceArgs,
+
+    /// Loopback TCP port QEMU connects the serial console to, on hosts without Unix sockets
+    #[arg(long)]
+    pub port: Option<u16>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub instance: InstanceArgs,
+
+    /// Return once the guest agent answers, leaving the VM running in the background
+    #[arg(long)]
+    pub wait_ready: bool,
+
+    /// Also echo the guest's serial console on this terminal
+    #[arg(long, conflicts_with = "wait_ready")]
+    pub console_stdio: bool,
+
+    /// Print the QEMU command line, with its environment, instead of starting the VM
+    #[arg(long, conflicts_with = "wait_ready")]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ResumeArgs {
+    #[command(flatten)]
+    pub target: TargetArgs,
+
+    /// Return once the guest agent answers, leaving the VM running in the background
+    #[arg(long)]
+    pub wait_ready: bool,
+
+    /// Also echo the guest's serial console on this terminal
+    #[arg(long, conflicts_with = "wait_ready")]
+    pub console_stdio: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct MemArgs {
+    #[command(flatten)]
+    pub target: TargetArgs,
+
+    /// Resize the guest to this many MiB through the balloon (at most the memory installed)
+    #[arg(long, value_name = "MIB")]
+    pub set: Option<u32>,
+
+    /// Hot-add a DIMM of this many MiB (a multiple of 128, up to --max-memory)
+    #[arg(long, value_name = "MIB", conflicts_with = "set")]
+    pub add: Option<u32>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CpusArgs {
+    #[command(flatten)]
+    pub target: TargetArgs,
+
+    /// Hot-add this many vCPUs (up to --max-vcpus)
+    #[arg(long, value_name = "COUNT")]
+    pub add: Option<u32>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct LogsArgs {
+    #[command(flatten)]
+    pub target: TargetArgs,
+
+    /// Keep printing new output until the VM stops
+    #[arg(short, long)]
+    pub follow: bool,
+
+    /// Only show output from the last DURATION (e.g. 30s, 10m, 1h30m)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub since: Option<Duration>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ExecArgs {
+    #[command(flatten)]
+    pub instance: InstanceArgs,
+
+    /// Set an environment variable for the command (repeatable)
+    #[arg(short, long = "env", value_name = "KEY=VALUE", value_parser = parse_env)]
+    pub env: Vec<(String, String)>,
+
+    /// Working directory inside the guest
+    #[arg(short, long, value_name = "DIR")]
+    pub workdir: Option<String>,
+
+    /// Don't forward stdin; the command sees end-of-file immediately
+    #[arg(short = 'n', long)]
+    pub no_stdin: bool,
+
+    /// Command and arguments to run
+    #[arg(required = true, trailing_var_arg = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CpArgs {
+    #[command(flatten)]
+    pub instance: InstanceArgs,
+
+    /// Copy directories recursively
+    #[arg(short, long)]
+    pub recursive: bool,
+
+    /// Don't print progress
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Source: `vm:PATH` in the guest, `host:PATH` or a plain path on the host
+    pub source: String,
+
+    /// Destination: `vm:PATH` in the guest, `host:PATH` or a plain path on the host
+    pub destination: String,
+}
+
+/// Instance names become directory names and SSH host aliases.
+fn parse_name(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.len() <= 64
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid && !s.starts_with('-') {
+        Ok(s.to_string())
+    } else {
+        Err(format!("'{s}' is not a valid name: use letters, digits, '-' and '_'"))
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration like 30s, 10m or 1h30m, got '{s}'");
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() || s.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{s}'")),
+    }
+}
+
 impl Cli {
-    pub fn effective_command(&self) -> &Command {
-        static DEFAULT: Command = Command::Run;
-        self.command.as_ref().unwrap_or(&DEFAULT)
+    pub fn effective_command(&self) -> Command {
+        self.command
+            .clone()
+            .unwrap_or_else(|| Command::Run(RunArgs::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_name_accepts_directory_safe_names() {
+        for name in ["default", "work-2", "a_b", "X", &"n".repeat(64)] {
+            assert_eq!(parse_name(name).as_deref(), Ok(name));
+        }
+        for name in [
+            "",
+            "-rf",
+            "a b",
+            "../etc",
+            "a/b",
+            "a.b",
+            "é",
+            &"n".repeat(65),
+        ] {
+            assert!(parse_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn parse_duration_adds_up_units() {
+        let secs = |s| parse_duration(s).map(|d| d.as_secs());
+        assert_eq!(secs("30s"), Ok(30));
+        assert_eq!(secs("10m"), Ok(600));
+        assert_eq!(secs("1h30m"), Ok(5400));
+        assert_eq!(secs("2d"), Ok(172_800));
+        assert_eq!(secs("1m1m"), Ok(120));
+        assert_eq!(secs("0s"), Ok(0));
+        for s in [
+            "",
+            "30",
+            "m",
+            "1h30",
+            "1w",
+            "-1s",
+            "1.5h",
+            "99999999999999999999s",
+        ] {
+            assert!(parse_duration(s).is_err(), "{s:?}");
+        }
+        assert!(parse_duration(&format!("{}d", u64::MAX / 86_400 + 1)).is_err());
+    }
+
+    #[test]
+    fn parse_env_splits_at_the_first_equals_sign() {
+        assert_eq!(parse_env("KEY=value"), Ok(("KEY".into(), "value".into())));
+        assert_eq!(parse_env("KEY="), Ok(("KEY".into(), String::new())));
+        assert_eq!(parse_env("A=b=c"), Ok(("A".into(), "b=c".into())));
+        assert!(parse_env("KEY").is_err());
+        assert!(parse_env("=value").is_err());
     }
 }
@@ -0,0 +1,39 @@
+use crate::config::VirtualGhostConfig;
+use crate::vm::{self, AssetManager};
+use std::path::PathBuf;
+
+pub async fn cmd_config(show: bool) -> anyhow::Result<()> {
+    if show {
+        let config = VirtualGhostConfig::load()?;
+        println!("{}", toml::to_string_pretty(&config)?);
+    } else {
+        println!(
+            "Config file: {}",
+            VirtualGhostConfig::config_path().display()
+        );
+        println!("Cache dir:   {}", VirtualGhostConfig::cache_dir().display());
+    }
+    Ok(())
+}
+
+pub async fn cmd_clean() -> anyhow::Result<()> {
+    let asset_manager = AssetManager::new();
+    // The disks of persistent and suspended VMs only hold their changes to the rootfs
+    let backing_files = vm::kept_backing_files()?;
+    let keep: Vec<PathBuf> = backing_files.iter().map(|(base, _)| base.clone()).collect();
+    let kept = asset_manager.clean_cache(&keep)?;
+    println!("Cache cleaned.");
+    for base in kept {
+        let users: Vec<&str> = backing_files
+            .iter()
+            .filter(|(backing, _)| *backing == base)
+            .map(|(_, name)| name.as_str())
+            .collect();
+        println!(
+            "Kept {}: the disks of {} read from it",
+            base.display(),
+            users.join(", ")
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use crate::cli::LogsArgs;
+use crate::config::VirtualGhostConfig;
+use crate::error::VmError;
+#[cfg(unix)]
+use crate::terminal;
+use crate::vm;
+
+/// Detaches `virtualghost console` from the serial console (Ctrl-]).
+#[cfg(unix)]
+const CONSOLE_ESCAPE: u8 = 0x1d;
+
+/// Print the serial console log of a VM, optionally following it until the VM stops.
+pub async fn cmd_logs(logs: &LogsArgs) -> anyhow::Result<()> {
+    let config = VirtualGhostConfig::load()?;
+    let name = &logs.target.name;
+    let instance = vm::InstanceDir::new(name);
+    if !instance.path().exists() {
+        anyhow::bail!("no logs for VM '{name}'");
+    }
+
+    let log = open_serial_log(&instance, &config);
+    let since = logs.since.map(|ago| {
+        std::time::SystemTime::now()
+            .checked_sub(ago)
+            .unwrap_or(std::time::UNIX_EPOCH)
+    });
+    let mut stdout = std::io::stdout();
+    let result = if logs.follow {
+        let running = || matches!(instance.running_state(), Ok(Some(_)));
+        log.follow(since, &mut stdout, running).await
+    } else {
+        log.print(since, &mut stdout)
+    };
+    match result {
+        // Piped into `head` and the like
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+pub(super) fn open_serial_log(
+    instance: &vm::InstanceDir,
+    config: &VirtualGhostConfig,
+) -> vm::SerialLog {
+    vm::SerialLog::new(
+        instance.serial_log(),
+        config.vm.serial_log_max_kib.saturating_mul(1024),
+        config.vm.serial_log_keep,
+    )
+}
+
+/// Attach the host terminal to a VM's serial console until Ctrl-] or the VM stops.
+pub async fn cmd_console(name: &str) -> anyhow::Result<()> {
+    #[cfg(not(unix))]
+    {
+        let _ = name;
+        anyhow::bail!("`console` needs a Unix host terminal");
+    }
+
+    #[cfg(unix)]
+    {
+        use std::io::IsTerminal;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let instance = vm::InstanceDir::new(name);
+        if instance.running_state()?.is_none() {
+            return Err(VmError::NotRunning(name.to_string()).into());
+        }
+        let stream = tokio::net::UnixStream::connect(instance.console_socket())
+            .await
+            .map_err(|e| anyhow::anyhow!("cannot reach the serial console of '{name}': {e}"))?;
+        let (mut from_guest, mut to_guest) = stream.into_split();
+
+        eprintln!("Connected to the serial console of '{name}'. Press Ctrl-] to detach.");
+        let raw = if std::io::stdin().is_terminal() {
+            Some(terminal::RawMode::enable()?)
+        } else {
+            None
+        };
+
+        let mut stdin = tokio::io::stdin();
+        let mut stdout = tokio::io::stdout();
+        let mut typed = [0u8; 1024];
+        let mut printed = [0u8; 4096];
+        let detached = loop {
+            tokio::select! {
+                read = stdin.read(&mut typed) => {
+                    let n = read?;
+                    let escape = typed[..n].iter().position(|&b| b == CONSOLE_ESCAPE);
+                    to_guest.write_all(&typed[..escape.unwrap_or(n)]).await?;
+                    if n == 0 || escape.is_some() {
+                        break true;
+                    }
+                }
+                read = from_guest.read(&mut printed) => {
+                    let n = read?;
+                    if n == 0 {
+                        break false;
+                    }
+                    stdout.write_all(&printed[..n]).await?;
+                    stdout.flush().await?;
+                }
+            }
+        };
+        drop(raw);
+
+        if detached {
+            eprintln!("\nDetached from '{name}'.");
+        } else {
+            eprintln!("\nThe serial console of '{name}' closed.");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use crate::cli::{CpArgs, ExecArgs, InstanceArgs};
+use crate::config::VirtualGhostConfig;
+use crate::error::VmError;
+use crate::network::{self, GuestTunnel};
+use crate::{ssh, vm};
+use std::time::Duration;
+
+/// Run a command in the guest, relaying its output and returning its exit status.
+pub async fn cmd_exec(exec: &ExecArgs) -> anyhow::Result<i32> {
+    use russh::ChannelMsg;
+    use tokio::io::AsyncWriteExt;
+
+    let instance = vm::InstanceDir::new(&exec.instance.name);
+    let client = connect_agent(&instance).await?;
+
+    let command = exec
+        .command
+        .iter()
+        .map(|arg| ssh::shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut session = ssh::SshSession::exec(
+        client.handle(),
+        &command,
+        &exec.env,
+        exec.workdir.as_deref(),
+    )
+    .await?;
+
+    if exec.no_stdin {
+        session.eof().await?;
+    } else {
+        let mut input = session.writer();
+        tokio::spawn(async move {
+            let mut stdin = tokio::io::stdin();
+            if let Err(e) = tokio::io::copy(&mut stdin, &mut input).await {
+                tracing::debug!(error = %e, "Stopped forwarding stdin");
+            }
+            let _ = input.shutdown().await;
+        });
+    }
+
+    let mut stdout = tokio::io::stdout();
+    let mut stderr = tokio::io::stderr();
+    let mut code = None;
+    while let Some(msg) = session.read().await {
+        match msg {
+            ChannelMsg::Data { data } => {
+                stdout.write_all(&data).await?;
+                stdout.flush().await?;
+            }
+            ChannelMsg::ExtendedData { data, ext: 1 } => {
+                stderr.write_all(&data).await?;
+                stderr.flush().await?;
+            }
+            ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status as i32),
+            ChannelMsg::ExitSignal { signal_name, .. } => {
+                tracing::warn!(signal = ?signal_name, "Command killed by signal");
+                code = Some(255);
+            }
+            ChannelMsg::Failure => anyhow::bail!("the guest refused to run `{command}`"),
+            ChannelMsg::Close => break,
+            _ => {}
+        }
+    }
+
+    Ok(code.unwrap_or_else(|| {
+        tracing::warn!("Guest closed the session without an exit status");
+        255
+    }))
+}
+
+/// Copy files between the host and the guest over SFTP.
+pub async fn cmd_cp(cp: &CpArgs) -> anyhow::Result<()> {
+    use ssh::Location;
+
+    let source = Location::parse(&cp.source);
+    let destination = Location::parse(&cp.destination);
+
+    let instance = vm::InstanceDir::new(&cp.instance.name);
+    let client = connect_agent(&instance).await?;
+    let mut transfer = ssh::FileTransfer::open(client.handle(), cp.recursive, cp.quiet).await?;
+
+    let start = std::time::Instant::now();
+    match (&source, &destination) {
+        (Location::Host(src), Location::Guest(dst)) => transfer.upload(src, dst).await?,
+        (Location::Guest(src), Location::Host(dst)) => transfer.download(src, dst).await?,
+        _ => anyhow::bail!("exactly one of source and destination must be a guest path (vm:PATH)"),
+    }
+
+    let stats = transfer.stats();
+    if !cp.quiet {
+        eprintln!(
+            "Copied {} file(s), {} in {:.1}s",
+            stats.files,
+            ssh::human_bytes(stats.bytes),
+            start.elapsed().as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// Relay stdin/stdout to the guest's SSH server, for use as an OpenSSH `ProxyCommand`.
+pub async fn cmd_ssh_proxy(target: &InstanceArgs) -> anyhow::Result<()> {
+    let instance = vm::InstanceDir::new(&target.name);
+    let (state, agent) = running_agent(&instance)?;
+    let guest = agent.connect_retry(boot_time_left(&state)?).await?;
+    let stdio = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+    GuestTunnel::bridge(guest, stdio).await?;
+    Ok(())
+}
+
+/// Print an OpenSSH `Host` block that reaches the VM through `ssh-proxy`.
+pub fn cmd_ssh_config(target: &InstanceArgs) -> anyhow::Result<()> {
+    let config = VirtualGhostConfig::load()?;
+    let instance = vm::InstanceDir::new(&target.name);
+    let identity = config.ssh.key_path.unwrap_or_else(|| instance.identity());
+    let exe = std::env::current_exe()?;
+
+    println!("Host {}", instance.ssh_alias());
+    println!("    User {}", ssh::GUEST_USER);
+    println!(
+        "    ProxyCommand {} ssh-proxy --name {}",
+        ssh::shell_quote(&exe.to_string_lossy()),
+        instance.name()
+    );
+    println!("    IdentityFile \"{}\"", identity.display());
+    println!("    IdentitiesOnly yes");
+    // The host key changes every boot; `run` rewrites this file with the current one
+    println!(
+        "    UserKnownHostsFile \"{}\"",
+        instance.known_hosts().display()
+    );
+    println!("    HostKeyAlias {}", instance.ssh_alias());
+    println!("    StrictHostKeyChecking yes");
+    Ok(())
+}
+
+/// Open an authenticated SSH connection to the agent of a running VM,
+/// pinning the host key generated for its current boot.
+pub(super) async fn connect_agent(instance: &vm::InstanceDir) -> anyhow::Result<ssh::SshClient> {
+    let (state, agent) = running_agent(instance)?;
+    let key = ssh::KeyManager::load(&state.identity)?;
+    let host_key = ssh::KeyManager::load_public(&instance.host_key_pub())?;
+    let stream = agent.connect_retry(boot_time_left(&state)?).await?;
+    Ok(ssh::SshClient::connect(stream, ssh::GUEST_USER, &key, &host_key).await?)
+}
+
+/// State and guest agent endpoint of a running VM.
+fn running_agent(
+    instance: &vm::InstanceDir,
+) -> anyhow::Result<(vm::InstanceState, network::GuestEndpoint)> {
+    let state = instance
+        .running_state()?
+        .ok_or_else(|| VmError::NotRunning(instance.name().to_string()))?;
+    let Some(agent) = state.agent.clone() else {
+        anyhow::bail!("VM '{}' has no guest agent channel", instance.name());
+    };
+    Ok((state, agent))
+}
+
+/// How long a VM may still be booting: commands issued right after a
+/// background `run` wait for its agent instead of failing, up to the boot timeout.
+fn boot_time_left(state: &vm::InstanceState) -> anyhow::Result<Duration> {
+    let config = VirtualGhostConfig::load()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let booted_for = now.saturating_sub(state.started_at);
+    Ok(Duration::from_secs(
+        config.vm.boot_timeout_secs.saturating_sub(booted_for),
+    ))
+}
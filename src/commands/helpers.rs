@@ -0,0 +1,149 @@
+use crate::cli::{InstanceArgs, SerialLoggerArgs};
+use crate::config::VirtualGhostConfig;
+use crate::error::VmError;
+use crate::vm;
+use std::time::Duration;
+
+use super::console::open_serial_log;
+use super::resources::MIN_GUEST_MIB;
+
+/// How long the serial console logger may take to start, and QEMU to connect to it.
+const SERIAL_LOGGER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Start a hidden `virtualghost <command> --name <instance> [args]` helper process.
+///
+/// It runs as a separate process group so it outlives a `--wait-ready` launcher
+/// and a terminal Ctrl-C meant for the launcher does not reach it.
+pub(super) fn spawn_helper(
+    command: &str,
+    instance: &vm::InstanceDir,
+    args: &[String],
+) -> std::io::Result<tokio::process::Child> {
+    use std::process::Stdio;
+
+    let mut helper = tokio::process::Command::new(std::env::current_exe()?);
+    helper
+        .args([command, "--name", instance.name()])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    #[cfg(unix)]
+    helper.process_group(0);
+    helper.spawn()
+}
+
+/// Start the serial console logger for a VM about to boot and wait until it listens.
+///
+/// Being a helper process, it keeps recording the shutdown after a terminal Ctrl-C.
+#[cfg(unix)]
+pub(super) async fn spawn_serial_logger(instance: &vm::InstanceDir) -> anyhow::Result<()> {
+    let socket = instance.serial_socket();
+    let _ = std::fs::remove_file(&socket);
+    let mut logger = spawn_helper("serial-logger", instance, &[])?;
+
+    let deadline = tokio::time::Instant::now() + SERIAL_LOGGER_TIMEOUT;
+    while !socket.exists() {
+        if let Some(status) = logger.try_wait()? {
+            return Err(VmError::SerialLog(format!("logger exited early ({status})")).into());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            let _ = logger.start_kill();
+            return Err(VmError::SerialLog("logger did not start".into()).into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok(())
+}
+
+/// Start the serial console logger on `port`, where there are no Unix sockets.
+///
+/// The logger creates this boot's log once it listens, so the previous one is
+/// moved aside here first.
+#[cfg(not(unix))]
+pub(super) async fn spawn_serial_logger(
+    instance: &vm::InstanceDir,
+    config: &VirtualGhostConfig,
+    port: u16,
+) -> anyhow::Result<()> {
+    open_serial_log(instance, config).rotate()?;
+    let args = ["--port".to_string(), port.to_string()];
+    let mut logger = spawn_helper("serial-logger", instance, &args)?;
+
+    let deadline = tokio::time::Instant::now() + SERIAL_LOGGER_TIMEOUT;
+    while !instance.serial_log().exists() {
+        if let Some(status) = logger.try_wait()? {
+            return Err(VmError::SerialLog(format!("logger exited early ({status})")).into());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            let _ = logger.start_kill();
+            return Err(VmError::SerialLog("logger did not start".into()).into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok(())
+}
+
+/// Record a VM's serial console and, on Unix, serve console clients until
+/// QEMU disconnects (hidden `serial-logger` command).
+pub async fn cmd_serial_logger(logger: &SerialLoggerArgs) -> anyhow::Result<()> {
+    let config = VirtualGhostConfig::load()?;
+    let instance = vm::InstanceDir::new(&logger.instance.name);
+    let log = open_serial_log(&instance, &config);
+
+    #[cfg(not(unix))]
+    {
+        let Some(port) = logger.port else {
+            anyhow::bail!("the serial console logger needs --port without Unix sockets");
+        };
+        let serial = tokio::net::TcpListener::bind(("127.0.0.1", port)).await?;
+        Ok(vm::record_serial(&log, serial, SERIAL_LOGGER_TIMEOUT).await?)
+    }
+
+    #[cfg(unix)]
+    {
+        // Each boot starts a new file; the previous one becomes serial.log.1
+        log.rotate()?;
+
+        // The console socket goes first: the launcher waits for the serial one
+        let console_socket = instance.console_socket();
+        let _ = std::fs::remove_file(&console_socket);
+        let console = tokio::net::UnixListener::bind(&console_socket)?;
+        let serial = tokio::net::UnixListener::bind(instance.serial_socket())?;
+
+        let result = vm::serve_console(&log, serial, console, SERIAL_LOGGER_TIMEOUT).await;
+        let _ = std::fs::remove_file(instance.serial_socket());
+        let _ = std::fs::remove_file(&console_socket);
+        Ok(result?)
+    }
+}
+
+/// Resize a VM's guest memory with the automatic policy until QEMU exits
+/// (hidden `balloon-policy` command).
+pub async fn cmd_balloon_policy(target: &InstanceArgs) -> anyhow::Result<()> {
+    use vm::balloon::MIB;
+
+    let config = VirtualGhostConfig::load()?;
+    let instance = vm::InstanceDir::new(&target.name);
+    let state = instance
+        .running_state()?
+        .ok_or_else(|| VmError::NotRunning(target.name.clone()))?;
+    let (Some(addr), Some(machine)) = (&state.qmp, &state.machine) else {
+        anyhow::bail!("VM '{}' has no control socket", target.name);
+    };
+    let policy = vm::balloon::BalloonPolicy {
+        min: u64::from(config.vm.balloon_min_mib.max(MIN_GUEST_MIB)) * MIB,
+        max: u64::from(machine.memory_mib) * MIB,
+    };
+    policy.run(addr, || state.is_alive()).await;
+    Ok(())
+}
+
+/// Stop a background Cloud Hypervisor once its guest powers off (hidden `vmm-reaper` command).
+pub async fn cmd_vmm_reaper(target: &InstanceArgs) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    vm::CloudHypervisor::reap_detached(&vm::InstanceDir::new(&target.name)).await?;
+    #[cfg(not(unix))]
+    let _ = target;
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use crate::config::VirtualGhostConfig;
+use crate::error::VmError;
+use crate::vm;
+use std::time::Duration;
+
+use super::resources::connect_control;
+
+/// List running VMs, removing entries whose QEMU has gone away.
+pub async fn cmd_ps() -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    println!(
+        "{:<16} {:>8} {:<10} {:<11} {:<24} {:>9}",
+        "NAME", "PID", "STATE", "MODE", "AGENT", "UPTIME"
+    );
+    for (instance, state) in vm::registry::running()? {
+        let mode = if state.live_launcher().is_some() {
+            "foreground"
+        } else {
+            "background"
+        };
+        println!(
+            "{:<16} {:>8} {:<10} {:<11} {:<24} {:>9}",
+            instance.name(),
+            state.pid.map_or("-".into(), |pid| pid.to_string()),
+            run_status(&state).await,
+            mode,
+            state
+                .agent
+                .as_ref()
+                .map_or("-".into(), |agent| agent.to_string()),
+            format_uptime(now.saturating_sub(state.started_at)),
+        );
+    }
+    for instance in vm::InstanceDir::list()? {
+        if instance.is_suspended() && matches!(instance.running_state(), Ok(None)) {
+            println!(
+                "{:<16} {:>8} {:<10} {:<11} {:<24} {:>9}",
+                instance.name(),
+                "-",
+                "suspended",
+                "-",
+                "-",
+                "-"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The VMM's run state for a VM ("running", "paused", ...), asked over its control socket.
+async fn run_status(state: &vm::InstanceState) -> String {
+    if state.qmp.is_none() && state.api_socket.is_none() {
+        return "-".into();
+    }
+    let query = async {
+        let control = vm::hypervisor::connect_control(state).await?;
+        control.info().await
+    };
+    match tokio::time::timeout(Duration::from_secs(1), query).await {
+        Ok(Ok(info)) => info.state.as_str().into(),
+        _ => "unknown".into(),
+    }
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Stop a running VM: power it off cleanly, or kill its VMM outright when `force` is set.
+pub async fn cmd_stop(name: &str, force: bool) -> anyhow::Result<()> {
+    let config = VirtualGhostConfig::load()?;
+    let instance = vm::InstanceDir::new(name);
+    let Some(state) = instance.running_state()? else {
+        if instance.is_suspended() {
+            instance.discard_suspended();
+            println!("Discarded the saved state of {name}");
+            return Ok(());
+        }
+        return Err(VmError::NotRunning(name.to_string()).into());
+    };
+    let Some(pid) = state.pid else {
+        anyhow::bail!("VM '{name}' has no VMM process");
+    };
+
+    let grace = Duration::from_secs(config.vm.shutdown_timeout_secs);
+    if force {
+        vm::signal_pid(pid, true)?;
+    } else {
+        request_shutdown(&state, pid).await?;
+        // A foreground launcher kills the VMM itself after the grace period; allow it a moment more
+        if !wait_for_exit(&state, grace + Duration::from_secs(5)).await {
+            tracing::warn!(pid, "Guest did not power off in time, killing the VMM");
+            vm::signal_pid(pid, true)?;
+        }
+    }
+
+    if !wait_for_exit(&state, Duration::from_secs(5)).await {
+        anyhow::bail!("the VMM (pid {pid}) is still running");
+    }
+    instance.clear_runtime();
+    println!("{} {name}", if force { "Killed" } else { "Stopped" });
+    Ok(())
+}
+
+/// Ask a VM to power off through whichever channel is available.
+async fn request_shutdown(state: &vm::InstanceState, pid: u32) -> anyhow::Result<()> {
+    // A foreground launcher holds the VMM connection and powers the VM off on SIGTERM
+    if let Some(launcher) = state.live_launcher() {
+        if vm::signal_pid(launcher, false).is_ok() {
+            return Ok(());
+        }
+    }
+
+    match vm::hypervisor::connect_control(state).await {
+        Ok(control) => match control.power_button().await {
+            Ok(()) => return Ok(()),
+            Err(e) => tracing::warn!(error = %e, "ACPI powerdown request failed"),
+        },
+        Err(e) => tracing::warn!(error = %e, "VMM control socket unavailable"),
+    }
+
+    // The VMM exits on SIGTERM, without giving the guest a chance to shut down
+    tracing::warn!("Cannot request a clean shutdown, terminating the VMM");
+    vm::signal_pid(pid, false)?;
+    Ok(())
+}
+
+/// Pause or continue a running VM's vCPUs.
+pub async fn cmd_pause(name: &str, pause: bool) -> anyhow::Result<()> {
+    let state = vm::InstanceDir::new(name)
+        .running_state()?
+        .ok_or_else(|| VmError::NotRunning(name.to_string()))?;
+    let control = vm::hypervisor::connect_control(&state).await?;
+    match (pause, control.info().await?.state) {
+        (true, vm::VmState::Running) => {
+            control.pause().await?;
+            println!("Paused {name}");
+        }
+        (false, vm::VmState::Paused) => {
+            control.resume().await?;
+            println!("Unpaused {name}");
+        }
+        (true, vm::VmState::Paused) => println!("{name} is already paused"),
+        (false, vm::VmState::Running) => println!("{name} is not paused"),
+        (_, state) => anyhow::bail!("VM '{name}' is {}", state.as_str()),
+    }
+    Ok(())
+}
+
+/// Save a running VM's state to its instance directory and stop QEMU.
+pub async fn cmd_suspend(name: &str) -> anyhow::Result<()> {
+    let (instance, state, qmp) = connect_control(name).await?;
+    let (Some(pid), Some(machine)) = (state.pid, &state.machine) else {
+        return Err(VmError::Suspend(format!("VM '{name}' does not record its machine")).into());
+    };
+    // The passed-through GPU's state lives in the hardware, not in QEMU
+    if machine
+        .devices
+        .iter()
+        .any(|device| device.starts_with("vfio-pci"))
+    {
+        return Err(VmError::Suspend("VMs with GPU passthrough cannot be saved".into()).into());
+    }
+    if vm::hotplug::has_hotplugged(&qmp).await? {
+        return Err(VmError::Suspend(
+            "hot-added vCPUs or memory would be missing when resuming".into(),
+        )
+        .into());
+    }
+
+    let image = instance.suspend_image();
+    eprintln!("Saving VM state…");
+    let saved = vm::suspend::save_state(&qmp, &image).await?;
+    instance.save_suspend(&vm::suspend::SuspendInfo {
+        machine: machine.clone(),
+        qemu_version: qmp.version(),
+        identity: state.identity.clone(),
+        vsock_cid: state.vsock_cid,
+        rootfs_modified: vm::suspend::modified(std::path::Path::new(&machine.rootfs)),
+        saved_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    })?;
+
+    // From here on the guest lives in the state file
+    qmp.quit().await?;
+    if !wait_for_exit(&state, Duration::from_secs(10)).await {
+        tracing::warn!(pid, "QEMU did not quit, killing it");
+        vm::signal_pid(pid, true)?;
+    }
+    if !wait_for_exit(&state, Duration::from_secs(5)).await {
+        anyhow::bail!("QEMU (pid {pid}) is still running");
+    }
+    instance.clear_runtime();
+    println!(
+        "Suspended {name} ({} MiB of memory saved to {})",
+        saved / (1024 * 1024),
+        image.display()
+    );
+    Ok(())
+}
+
+/// Poll until the VM's VMM is gone, or its PID taken by another process;
+/// returns `false` if it outlives `timeout`.
+async fn wait_for_exit(state: &vm::InstanceState, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while state.is_alive() {
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
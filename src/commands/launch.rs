@@ -0,0 +1,454 @@
+use crate::cli::Cli;
+use crate::config::VirtualGhostConfig;
+use crate::error::VmError;
+#[cfg(unix)]
+use crate::vfio;
+use crate::vm::Hypervisor;
+use crate::{network, ssh, vm};
+use std::time::Duration;
+
+use super::console::open_serial_log;
+use super::helpers::{spawn_helper, spawn_serial_logger};
+use super::plan::{plan_launch, LaunchPlan};
+
+/// How long to keep retrying the VMM's control socket (QMP or REST API) after it starts.
+const VMM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a launched VM shares the launcher's terminal.
+pub(super) struct LaunchOptions {
+    /// Instance name; must not be in use by a running VM.
+    pub(super) name: String,
+    /// Echo the guest's serial console on stdout.
+    pub(super) console_stdio: bool,
+    /// Boot without a display window.
+    pub(super) headless: bool,
+    /// Load the state saved by `suspend` instead of booting.
+    pub(super) resume: bool,
+    /// Only plan the launch: claim nothing, clean nothing up and write no caches.
+    pub(super) dry_run: bool,
+}
+
+/// A VM started by this invocation, with what is needed to reach and stop it.
+pub(super) struct LaunchedVm {
+    pub(super) vmm: Box<dyn Hypervisor>,
+    pub(super) instance: vm::InstanceDir,
+    pub(super) state: vm::InstanceState,
+    pub(super) host_key: ssh_key::PublicKey,
+    pub(super) grace: Duration,
+    pub(super) boot_timeout: Duration,
+    pub(super) console_echo: Option<ConsoleEcho>,
+    /// What the guest agent reports while this launcher supervises the VM.
+    pub(super) events: Option<network::AgentEventListener>,
+}
+
+/// Copies the serial console log to stdout while the VM runs.
+pub(super) struct ConsoleEcho {
+    running: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ConsoleEcho {
+    fn start(log: vm::SerialLog) -> Self {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Only this boot's output, not the rotated logs of earlier ones
+        let since = std::time::SystemTime::now();
+        let running = std::sync::Arc::new(AtomicBool::new(true));
+        let flag = running.clone();
+        let task = tokio::spawn(async move {
+            let keep_going = || flag.load(Ordering::Relaxed);
+            if let Err(e) = log
+                .follow(Some(since), &mut std::io::stdout(), keep_going)
+                .await
+            {
+                tracing::debug!(error = %e, "Stopped echoing the serial console");
+            }
+        });
+        Self { running, task }
+    }
+
+    /// Print what is left in the log and stop.
+    pub(super) async fn finish(self) {
+        self.running
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        let _ = self.task.await;
+    }
+}
+
+impl LaunchedVm {
+    /// Wait for the guest agent to answer.
+    ///
+    /// Returns `false` if a shutdown signal arrived first. In that case, and when
+    /// the VMM exits or the boot times out, the VM is stopped before returning.
+    pub(super) async fn wait_ready(&mut self) -> anyhow::Result<bool> {
+        let Some(endpoint) = self.state.agent.clone() else {
+            anyhow::bail!(
+                "waiting for the guest needs an agent channel (vsock or SSH port forward)"
+            );
+        };
+
+        let serial_log = self.instance.serial_log();
+        let status = self
+            .events
+            .as_ref()
+            .map(network::AgentEventListener::status);
+        let ready = vm::wait_until_ready(endpoint, self.boot_timeout, Some(serial_log), status);
+        let ready = tokio::select! {
+            ready = ready => ready,
+            status = self.vmm.wait() => {
+                let status = status?;
+                let outcome = self.exited(status, false).await;
+                let report = outcome.report(&self.instance.serial_log());
+                let error = anyhow::Error::from(VmError::ProcessExited(status.code()));
+                return Err(error.context(report));
+            }
+            signal = shutdown_signal() => {
+                tracing::info!(signal, "Shutting down VM");
+                let status = self.stop().await?;
+                tracing::info!(?status, "VMM exited");
+                return Ok(false);
+            }
+        };
+
+        if let Err(e) = ready {
+            tracing::error!("Guest did not become ready, shutting down VM");
+            self.stop().await?;
+            return Err(e.into());
+        }
+        Ok(true)
+    }
+
+    /// Classify how the VM ended and remove the runtime state.
+    pub(super) async fn exited(
+        &mut self,
+        status: std::process::ExitStatus,
+        stop_requested: bool,
+    ) -> vm::VmOutcome {
+        let mut events = self.vmm.events().await;
+        events.ghostty_exit = self
+            .events
+            .as_ref()
+            .and_then(|listener| listener.status().borrow().ghostty_exit);
+        self.instance.clear_runtime();
+        // `suspend` saves the state before asking QEMU to quit
+        if status.success() && self.instance.is_suspended() {
+            return vm::VmOutcome::Suspended;
+        }
+        vm::VmOutcome::classify(status, &events, stop_requested)
+    }
+
+    /// Power the VM off and remove its runtime state.
+    pub(super) async fn stop(&mut self) -> anyhow::Result<std::process::ExitStatus> {
+        let status = stop_vm(&mut self.vmm, self.grace).await?;
+        self.instance.clear_runtime();
+        Ok(status)
+    }
+}
+
+/// Prepare assets and credentials, then start the VMM for the named instance.
+pub(super) async fn launch(cli: &Cli, options: &LaunchOptions) -> anyhow::Result<LaunchedVm> {
+    let LaunchPlan {
+        config,
+        instance,
+        qemu_config,
+        gpu,
+        suspended,
+        machine,
+        vsock_cid,
+        ssh_port,
+        efi_vars_template,
+        overlay,
+        registry_lock,
+    } = plan_launch(cli, options)?;
+
+    #[cfg(unix)]
+    if let Some(ref gpu) = gpu {
+        tracing::info!(pci_addr = %gpu.pci_address, "Preparing GPU for VFIO passthrough");
+        vfio::prepare_passthrough(gpu)?;
+    }
+
+    tracing::info!(
+        kernel = %qemu_config.kernel_path,
+        rootfs = %qemu_config.rootfs_path,
+        vcpus = config.vm.vcpus,
+        memory_mib = config.vm.memory_mib,
+        accel = ?qemu_config.accel,
+        gpu = ?config.vm.gpu_pci_address,
+        "Starting VirtualGhost"
+    );
+
+    instance.create()?;
+    if let Some(template) = efi_vars_template {
+        if !instance.efi_vars().exists() {
+            std::fs::copy(&template, instance.efi_vars())?;
+        }
+    }
+    overlay.prepare(suspended.is_some())?;
+
+    let (identity, host_key) = match &suspended {
+        // The resumed guest still trusts the credentials it booted with
+        Some(info) => (
+            info.identity.clone(),
+            ssh::KeyManager::load(&instance.host_key())?,
+        ),
+        None => issue_credentials(&instance, &config)?,
+    };
+
+    // The serial console goes to a logger process that timestamps and rotates
+    // it, and keeps recording after this launcher hands the VM off
+    let kind = config.vm.hypervisor;
+    #[cfg(unix)]
+    let console_echo = {
+        if kind == vm::HypervisorKind::Qemu {
+            spawn_serial_logger(&instance).await?;
+        } else {
+            // Other VMMs write the log themselves, one file per boot
+            open_serial_log(&instance, &config).rotate()?;
+        }
+        // Echo by following the log; the VMM's stdio is not involved
+        options
+            .console_stdio
+            .then(|| ConsoleEcho::start(open_serial_log(&instance, &config)))
+    };
+    #[cfg(not(unix))]
+    let console_echo = {
+        if let Some(port) = qemu_config.serial_tcp_port {
+            spawn_serial_logger(&instance, &config, port).await?;
+        }
+        options
+            .console_stdio
+            .then(|| ConsoleEcho::start(open_serial_log(&instance, &config)))
+    };
+
+    let events = listen_for_agent_events(kind, vsock_cid, &instance, suspended.is_some()).await?;
+
+    let vmm: Box<dyn Hypervisor> = match &suspended {
+        // Only QEMU can load a saved state, as planning checked
+        Some(info) => {
+            let mut qemu = vm::QemuProcess::spawn(&qemu_config, &instance).await?;
+            qemu.wait_ready(VMM_CONNECT_TIMEOUT).await?;
+            if let Err(e) = resume_guest(qemu.qmp(), info).await {
+                let _ = qemu.kill().await;
+                let _ = qemu.wait().await;
+                instance.clear_runtime();
+                return Err(e.context("QEMU could not load the saved state"));
+            }
+            instance.clear_suspend();
+            tracing::info!("Guest resumed");
+            Box::new(qemu)
+        }
+        None => {
+            let mut vmm = vm::hypervisor::spawn(kind, &qemu_config, &instance).await?;
+            if let Err(e) = vmm.wait_ready(VMM_CONNECT_TIMEOUT).await {
+                let _ = vmm.kill().await;
+                let _ = vmm.wait().await;
+                instance.clear_runtime();
+                return Err(anyhow::Error::from(e).context(format!("{kind} did not start the VM")));
+            }
+            tracing::info!("{kind} running — waiting for the guest to boot");
+            vmm
+        }
+    };
+
+    // Let `exec`, `ps`, `stop` and friends find the VM while it runs
+    let state = vm::InstanceState {
+        pid: vmm.id(),
+        pid_identity: vmm.id().and_then(vm::process_identity),
+        launcher_pid: Some(std::process::id()),
+        launcher_identity: vm::process_identity(std::process::id()),
+        qmp: qemu_config
+            .control_qmp
+            .clone()
+            .filter(|_| kind == vm::HypervisorKind::Qemu),
+        vsock_cid,
+        ssh_port,
+        agent: vmm.agent_endpoint(config.ssh.vsock_port),
+        identity,
+        started_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        machine: Some(machine),
+        hypervisor: kind,
+        api_socket: (kind != vm::HypervisorKind::Qemu).then(|| instance.api_socket()),
+    };
+    instance.save_state(&state)?;
+    drop(registry_lock);
+
+    if config.vm.balloon_auto {
+        spawn_helper("balloon-policy", &instance, &[])?;
+    }
+
+    Ok(LaunchedVm {
+        vmm,
+        instance,
+        state,
+        host_key: host_key.public_key().clone(),
+        grace: Duration::from_secs(config.vm.shutdown_timeout_secs),
+        boot_timeout: Duration::from_secs(config.vm.boot_timeout_secs),
+        console_echo,
+        events,
+    })
+}
+
+/// Listen for events the guest agent sends on its own, and write where to for
+/// it to read via fw_cfg. Events are advisory: without a listener the guest
+/// is told nothing and boots all the same.
+///
+/// A `resuming` guest read the address when it booted, so the listener goes
+/// back to it.
+async fn listen_for_agent_events(
+    kind: vm::HypervisorKind,
+    vsock_cid: Option<u32>,
+    instance: &vm::InstanceDir,
+    resuming: bool,
+) -> anyhow::Result<Option<network::AgentEventListener>> {
+    let previous = if resuming {
+        std::fs::read_to_string(instance.agent_events()).ok()
+    } else {
+        None
+    };
+    let port = previous.as_deref().and_then(network::guest_port);
+    let transport = match (kind, vsock_cid) {
+        (vm::HypervisorKind::Qemu, Some(cid)) => network::EventTransport::Vsock { cid, port },
+        (vm::HypervisorKind::Qemu, None) => network::EventTransport::Tcp {
+            port: port.and_then(|port| u16::try_from(port).ok()),
+        },
+        _ => network::EventTransport::HybridVsock {
+            socket: instance.vsock_socket(),
+        },
+    };
+    let listener = match network::AgentEventListener::bind(&transport).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            tracing::warn!(error = %e, "Cannot listen for guest agent events");
+            None
+        }
+    };
+    if previous.is_none() {
+        let address = listener
+            .as_ref()
+            .map_or("", network::AgentEventListener::guest_address);
+        std::fs::write(instance.agent_events(), address)?;
+    }
+    Ok(listener)
+}
+
+/// Show the guest's notifications and errors while the VM runs.
+pub(super) async fn report_agent_events(
+    mut events: tokio::sync::broadcast::Receiver<network::AgentEvent>,
+) {
+    use network::AgentEvent;
+    use tokio::sync::broadcast::error::RecvError;
+
+    loop {
+        match events.recv().await {
+            // Readiness and the exit outcome act on these
+            Ok(AgentEvent::BootComplete | AgentEvent::GhosttyExited { .. }) => {}
+            Ok(AgentEvent::Notification { title, body }) if body.is_empty() => {
+                eprintln!("{title}")
+            }
+            Ok(AgentEvent::Notification { title, body }) => eprintln!("{title}: {body}"),
+            Ok(AgentEvent::Error { message }) => tracing::error!("Guest: {message}"),
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::debug!(skipped, "Missed guest agent events")
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Generate the credentials handed to a freshly booting guest, returning the
+/// client identity and the guest's host key.
+fn issue_credentials(
+    instance: &vm::InstanceDir,
+    config: &VirtualGhostConfig,
+) -> anyhow::Result<(std::path::PathBuf, ssh_key::PrivateKey)> {
+    // Only this key may log in to the guest agent. It is handed to the guest via
+    // fw_cfg at boot; the private half stays on the host for later connections.
+    let identity = match config.ssh.key_path {
+        Some(ref path) => path.clone(),
+        None => {
+            let path = instance.identity();
+            ssh::KeyManager::save(&ssh::KeyManager::generate_ephemeral()?, &path)?;
+            path
+        }
+    };
+    std::fs::write(
+        instance.authorized_keys(),
+        ssh::KeyManager::authorized_key(&ssh::KeyManager::load(&identity)?)?,
+    )?;
+
+    // The guest's host key is generated here too, so connections can pin it
+    // instead of trusting whatever answers on the vsock/TCP channel.
+    let host_key = ssh::KeyManager::generate_ephemeral()?;
+    ssh::KeyManager::save(&host_key, &instance.host_key())?;
+    std::fs::write(
+        instance.host_key_pub(),
+        ssh::KeyManager::authorized_key(&host_key)?,
+    )?;
+    // Same pin for stock OpenSSH clients using the `ssh-config` block
+    std::fs::write(
+        instance.known_hosts(),
+        format!(
+            "{} {}",
+            instance.ssh_alias(),
+            ssh::KeyManager::authorized_key(&host_key)?
+        ),
+    )?;
+    Ok((identity, host_key))
+}
+
+/// Load the saved state into a QEMU started with `-incoming` and let the guest run.
+async fn resume_guest(
+    qmp: Option<&vm::QmpClient>,
+    info: &vm::suspend::SuspendInfo,
+) -> anyhow::Result<()> {
+    let Some(qmp) = qmp else {
+        anyhow::bail!("resuming needs QMP, which is unavailable");
+    };
+    if qmp.version() != info.qemu_version {
+        return Err(VmError::Suspend(format!(
+            "the state was saved by QEMU {}, this is QEMU {}",
+            info.qemu_version,
+            qmp.version()
+        ))
+        .into());
+    }
+    tracing::info!("Loading the saved VM state");
+    vm::suspend::finish_incoming(qmp).await?;
+    Ok(())
+}
+
+/// Power the VM off gracefully; a second Ctrl-C/SIGTERM during the grace period kills the VMM.
+pub(super) async fn stop_vm(
+    vmm: &mut Box<dyn Hypervisor>,
+    grace: Duration,
+) -> anyhow::Result<std::process::ExitStatus> {
+    let status = tokio::select! {
+        status = vmm.shutdown(grace) => status?,
+        signal = shutdown_signal() => {
+            tracing::warn!(signal, "Received second signal, killing the VMM");
+            vmm.kill().await?;
+            vmm.wait().await?
+        }
+    };
+    Ok(status)
+}
+
+/// Resolve once the launcher is asked to stop (Ctrl-C, or SIGTERM on Unix).
+pub(super) async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => return "SIGINT",
+                _ = sigterm.recv() => return "SIGTERM",
+            }
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
@@ -0,0 +1,17 @@
+mod config;
+mod console;
+mod guest;
+mod helpers;
+mod instances;
+mod launch;
+mod plan;
+mod resources;
+mod run;
+
+pub use config::{cmd_clean, cmd_config};
+pub use console::{cmd_console, cmd_logs};
+pub use guest::{cmd_cp, cmd_exec, cmd_ssh_config, cmd_ssh_proxy};
+pub use helpers::{cmd_balloon_policy, cmd_serial_logger, cmd_vmm_reaper};
+pub use instances::{cmd_pause, cmd_ps, cmd_stop, cmd_suspend};
+pub use resources::{cmd_cpus, cmd_mem};
+pub use run::{cmd_resume, cmd_run, cmd_shell};
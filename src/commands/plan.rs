@@ -0,0 +1,771 @@
+use crate::cli::Cli;
+use crate::config::{VirtualGhostConfig, VmSettings};
+use crate::error::VmError;
+use crate::vm::AssetManager;
+use crate::{ssh, vfio, vm};
+
+use super::launch::LaunchOptions;
+
+/// fw_cfg entry the guest agent reads its authorized public keys from.
+const GUEST_AUTHORIZED_KEYS: &str = "opt/virtualghost/authorized_keys";
+
+/// fw_cfg entry carrying the host-generated private host key for the guest agent.
+const GUEST_HOST_KEY: &str = "opt/virtualghost/host_key";
+
+/// fw_cfg entry telling the guest agent where to send its events.
+const GUEST_EVENTS: &str = "opt/virtualghost/events";
+
+/// What a launch will run, worked out before anything is started or handed
+/// to the guest: the QEMU command line and what it refers to.
+pub(super) struct LaunchPlan {
+    pub(super) config: VirtualGhostConfig,
+    pub(super) instance: vm::InstanceDir,
+    pub(super) qemu_config: vm::QemuConfig,
+    /// GPU to bind to vfio-pci before QEMU starts.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(super) gpu: Option<vfio::GpuDevice>,
+    /// What `suspend` saved, when resuming.
+    pub(super) suspended: Option<vm::suspend::SuspendInfo>,
+    pub(super) machine: vm::suspend::MachineSignature,
+    pub(super) vsock_cid: Option<u32>,
+    pub(super) ssh_port: Option<u16>,
+    /// Copied to the instance as its UEFI variable store on first boot.
+    pub(super) efi_vars_template: Option<std::path::PathBuf>,
+    /// Created, or checked when kept from an earlier boot, once the instance directory exists.
+    pub(super) overlay: vm::Overlay,
+    /// Held until the launched VM's state is written; not taken by a dry run.
+    pub(super) registry_lock: Option<vm::registry::RegistryLock>,
+}
+
+/// Resolve the configuration, assets and per-VM paths into the QEMU command
+/// line for the named instance.
+pub(super) fn plan_launch(cli: &Cli, options: &LaunchOptions) -> anyhow::Result<LaunchPlan> {
+    let mut config = VirtualGhostConfig::load()?;
+
+    // Apply CLI overrides; sizing waits until a saved machine is known
+    apply_overrides(&mut config.vm, cli);
+    let kind = config.vm.hypervisor;
+    if kind != vm::HypervisorKind::Qemu {
+        if options.resume {
+            return Err(VmError::Unsupported(format!("{kind} cannot resume a saved state")).into());
+        }
+        if config.vm.balloon_auto {
+            tracing::warn!("The automatic balloon policy needs QEMU; {kind} VMs keep their memory");
+            config.vm.balloon_auto = false;
+        }
+    }
+
+    // Resolve asset paths
+    let asset_manager = AssetManager::new();
+    let kernel_path = config
+        .vm
+        .kernel_path
+        .clone()
+        .unwrap_or_else(|| asset_manager.kernel_path());
+    let rootfs_path = config
+        .vm
+        .rootfs_path
+        .clone()
+        .unwrap_or_else(|| asset_manager.rootfs_path());
+
+    // Extract embedded assets (kernel, rootfs, QEMU) if not already cached;
+    // the QEMU binary has to be in place to probe it
+    if let Err(e) = asset_manager.ensure_assets() {
+        // UEFI boots a bootloader from the disk, not our kernel
+        let need_kernel = config.vm.kernel_path.is_none()
+            && !config.vm.uefi
+            && !asset_manager.kernel_path().exists();
+        let need_rootfs = config.vm.rootfs_path.is_none() && !asset_manager.rootfs_path().exists();
+        let need_qemu = kind == vm::HypervisorKind::Qemu
+            && config.vm.qemu_bin.is_none()
+            && !asset_manager.qemu_bin_path().exists();
+
+        if need_kernel || need_rootfs {
+            anyhow::bail!(
+                "No kernel/rootfs available: {e}\n\
+                 Provide --kernel and --rootfs paths, or place assets in cache."
+            );
+        }
+        if need_qemu {
+            anyhow::bail!(
+                "No QEMU binary available: {e}\n\
+                 Set qemu_bin in config, or place QEMU files in assets/qemu/ and rebuild."
+            );
+        }
+        tracing::warn!("Non-critical asset extraction issue: {e}");
+    }
+
+    // GPU passthrough (Linux only); the device is bound to vfio-pci at launch
+    #[cfg(unix)]
+    let gpu = match config.vm.gpu_pci_address {
+        Some(ref pci_addr) => Some(vfio::discover_gpu(pci_addr)?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let gpu = None;
+    #[cfg(not(unix))]
+    if config.vm.gpu_pci_address.is_some() {
+        anyhow::bail!("GPU passthrough requires Linux with KVM and IOMMU support");
+    }
+
+    // Build QEMU configuration; other VMMs are described by the same one
+    let qemu_bin = match kind {
+        vm::HypervisorKind::Qemu => config
+            .vm
+            .qemu_bin
+            .clone()
+            .unwrap_or_else(|| asset_manager.qemu_bin_path()),
+        vm::HypervisorKind::CloudHypervisor => config
+            .vm
+            .cloud_hypervisor_bin
+            .clone()
+            .unwrap_or_else(|| "cloud-hypervisor".into()),
+        vm::HypervisorKind::Firecracker => config
+            .vm
+            .firecracker_bin
+            .clone()
+            .unwrap_or_else(|| "firecracker".into()),
+    };
+
+    // Per-VM directory: QMP socket, serial log, SSH credentials and runtime state.
+    // The name and CID stay claimed until the launch writes the VM's state.
+    let registry_lock = (!options.dry_run).then(vm::registry::lock).transpose()?;
+    let instance = vm::InstanceDir::new(&options.name);
+    let running = if options.dry_run {
+        // Stale state is left for the next real launch to remove
+        instance
+            .load_state()
+            .ok()
+            .filter(vm::InstanceState::is_alive)
+    } else {
+        instance.running_state()?
+    };
+    if running.is_some() {
+        return Err(VmError::AlreadyRunning(options.name.clone()).into());
+    }
+
+    // A suspended guest has to be resumed or discarded before the instance boots afresh
+    let suspended = if options.resume {
+        Some(instance.load_suspend()?)
+    } else if instance.is_suspended() {
+        return Err(VmError::Suspended(options.name.clone()).into());
+    } else {
+        None
+    };
+    apply_sizing(
+        &mut config.vm,
+        cli,
+        suspended.as_ref().map(|info| &info.machine),
+    )?;
+
+    // Boot from a copy-on-write overlay so the rootfs itself is never written;
+    // Firecracker only reads raw images and gets a full copy instead
+    let overlay = vm::Overlay::new(
+        &instance,
+        &rootfs_path,
+        kind == vm::HypervisorKind::Firecracker,
+        config.vm.persist,
+    )
+    .map_err(|e| anyhow::anyhow!("cannot read the rootfs {}: {e}", rootfs_path.display()))?;
+
+    let mut qemu_config = vm::QemuConfig::new(
+        qemu_bin,
+        config.vm.vcpus,
+        config.vm.memory_mib,
+        &kernel_path.to_string_lossy(),
+        &overlay.path().to_string_lossy(),
+    );
+    qemu_config.cpus.max_vcpus = config.vm.max_vcpus.unwrap_or(config.vm.vcpus);
+    if let Some(max) = config.vm.max_memory_mib {
+        qemu_config.max_memory_mib = max;
+        qemu_config.memory_slots = config.vm.memory_slots;
+    }
+    // If using embedded QEMU, point it to the extracted share/ directory
+    if kind == vm::HypervisorKind::Qemu && config.vm.qemu_bin.is_none() {
+        qemu_config.qemu_data_dir = Some(asset_manager.qemu_data_dir());
+    }
+    qemu_config.rootfs_format = overlay.format();
+
+    // Boot through UEFI firmware, or hand QEMU the kernel directly
+    let mut efi_vars_template = None;
+    if config.vm.uefi {
+        if config.vm.cmdline.is_some()
+            || !config.vm.extra_cmdline.is_empty()
+            || config.vm.initramfs_path.is_some()
+        {
+            tracing::warn!("UEFI boot ignores the kernel command line and initramfs");
+        }
+        let (code, vars) = match (kind, &config.vm.firmware_path) {
+            (vm::HypervisorKind::Qemu, _) => find_firmware(&config, &qemu_config)?,
+            (vm::HypervisorKind::Firecracker, _) => {
+                return Err(VmError::Unsupported(
+                    "Firecracker boots kernels directly; it has no UEFI firmware".into(),
+                )
+                .into())
+            }
+            // Cloud Hypervisor's firmware keeps no variable store
+            (_, Some(code)) => (code.clone(), None),
+            (_, None) => anyhow::bail!(
+                "{kind} needs its own UEFI firmware; point --firmware at CLOUDHV.fd \
+                 or rust-hypervisor-firmware"
+            ),
+        };
+        qemu_config.firmware = Some(vm::Firmware {
+            code,
+            vars: vars.is_some().then(|| instance.efi_vars()),
+        });
+        efi_vars_template = vars;
+    } else {
+        direct_boot(&config.vm, &mut qemu_config);
+    }
+    qemu_config.qmp_socket = instance.qmp_socket();
+
+    qemu_config
+        .fw_cfg
+        .push((GUEST_AUTHORIZED_KEYS.into(), instance.authorized_keys()));
+    qemu_config
+        .fw_cfg
+        .push((GUEST_HOST_KEY.into(), instance.host_key()));
+    qemu_config
+        .fw_cfg
+        .push((GUEST_EVENTS.into(), instance.agent_events()));
+
+    // On Windows, find free TCP ports for QMP
+    #[cfg(not(unix))]
+    {
+        qemu_config.qmp_tcp_port = Some(vm::registry::allocate_port()?);
+        qemu_config.control_qmp = Some(vm::QmpAddress::Tcp(vm::registry::allocate_port()?));
+    }
+    #[cfg(unix)]
+    {
+        qemu_config.control_qmp = Some(vm::QmpAddress::Unix(instance.control_socket()));
+    }
+    if let Some(ref gpu) = gpu {
+        for device_config in gpu.to_device_configs() {
+            qemu_config.gpu_passthrough.push(device_config.path);
+        }
+    }
+
+    // Use vsock on Linux (direct host-guest channel), TCP port forwarding elsewhere.
+    // Each VM gets its own CID or port so several can run side by side.
+    let (vsock_cid, ssh_port) = if cfg!(target_os = "linux") {
+        let preferred = suspended.as_ref().and_then(|info| info.vsock_cid);
+        let cid = if options.dry_run {
+            // Allocating claims the CID on /dev/vhost-vsock; a dry run only shows one
+            preferred.unwrap_or(vm::registry::FIRST_GUEST_CID)
+        } else {
+            vm::registry::allocate_cid(&vm::registry::running()?, preferred)?
+        };
+        (Some(cid), None)
+    } else {
+        (None, Some(vm::registry::allocate_port()?))
+    };
+    qemu_config.vsock_cid = vsock_cid.map(u64::from);
+    qemu_config.ssh_port_forward = ssh_port;
+
+    // GPU passthrough: no virtual display, Cage uses the physical GPU
+    if options.headless || !qemu_config.gpu_passthrough.is_empty() {
+        qemu_config.display = vm::DisplayMode::None;
+    }
+
+    // The serial console goes to a logger process, over a Unix socket or,
+    // elsewhere, a loopback TCP port
+    #[cfg(unix)]
+    {
+        qemu_config.serial_socket = Some(instance.serial_socket());
+    }
+    #[cfg(not(unix))]
+    {
+        qemu_config.serial_tcp_port = Some(vm::registry::allocate_port()?);
+    }
+
+    // Settle on devices and backends this QEMU actually has, or check that
+    // Firecracker can run the VM at all
+    match kind {
+        vm::HypervisorKind::Qemu => {
+            vm::QemuCapabilities::probe(&qemu_config, !options.dry_run)?.apply(&mut qemu_config)?;
+        }
+        #[cfg(unix)]
+        vm::HypervisorKind::Firecracker => {
+            vm::Firecracker::vm_config(&qemu_config, &instance)?;
+        }
+        _ => {}
+    }
+
+    qemu_config.extra_args = config.vm.extra_args.clone();
+    for conflict in qemu_config.conflicting_extra_args() {
+        tracing::warn!(
+            arg = %conflict,
+            "Extra QEMU argument overlaps one VirtualGhost already sets"
+        );
+    }
+
+    let machine = qemu_config.machine_signature();
+    if let Some(info) = &suspended {
+        info.check(&machine)?;
+        if !instance.suspend_image().exists() {
+            return Err(VmError::Suspend(format!(
+                "the saved state {} is missing",
+                instance.suspend_image().display()
+            ))
+            .into());
+        }
+        qemu_config.incoming = Some(vm::suspend::file_uri(
+            &info.qemu_version,
+            &instance.suspend_image(),
+        )?);
+    }
+
+    Ok(LaunchPlan {
+        config,
+        instance,
+        qemu_config,
+        gpu,
+        suspended,
+        machine,
+        vsock_cid,
+        ssh_port,
+        efi_vars_template,
+        overlay,
+        registry_lock,
+    })
+}
+
+/// Override the configuration with the flags that set up the VM, other than its size.
+fn apply_overrides(settings: &mut VmSettings, cli: &Cli) {
+    if let Some(ref kernel) = cli.kernel {
+        settings.kernel_path = Some(kernel.clone());
+    }
+    if let Some(ref rootfs) = cli.rootfs {
+        settings.rootfs_path = Some(rootfs.clone());
+    }
+    if let Some(ref gpu) = cli.gpu {
+        settings.gpu_pci_address = Some(gpu.clone());
+    }
+    if let Some(ref cmdline) = cli.cmdline {
+        settings.cmdline = Some(cmdline.clone());
+    }
+    settings
+        .extra_cmdline
+        .extend(cli.kernel_arg.iter().cloned());
+    if let Some(ref initramfs) = cli.initramfs {
+        settings.initramfs_path = Some(initramfs.clone());
+    }
+    if let Some(ref firmware) = cli.firmware {
+        settings.firmware_path = Some(firmware.clone());
+    }
+    settings.uefi |= cli.uefi || cli.firmware.is_some();
+    settings.persist = (settings.persist || cli.persist) && !cli.ephemeral;
+    settings.extra_args.extend(cli.qemu_arg.iter().cloned());
+}
+
+/// Hand QEMU the kernel command line and initramfs: the cmdline replaces the
+/// default one, extra kernel arguments are appended to it.
+fn direct_boot(settings: &VmSettings, qemu_config: &mut vm::QemuConfig) {
+    if let Some(ref cmdline) = settings.cmdline {
+        qemu_config.cmdline = cmdline.clone();
+    }
+    for arg in &settings.extra_cmdline {
+        qemu_config.cmdline.push(' ');
+        qemu_config.cmdline.push_str(arg);
+    }
+    qemu_config.initramfs_path = settings
+        .initramfs_path
+        .as_ref()
+        .map(|path| path.to_string_lossy().into_owned());
+}
+
+/// Size the VM from the configuration and `--vcpus`, `--memory`, `--max-vcpus`
+/// and `--max-memory`. A resumed VM keeps the size it was `saved` with, which
+/// the flags may only repeat.
+fn apply_sizing(
+    settings: &mut VmSettings,
+    cli: &Cli,
+    saved: Option<&vm::suspend::MachineSignature>,
+) -> anyhow::Result<()> {
+    if let Some(saved) = saved {
+        let conflicts: Vec<String> = [
+            ("--vcpus", cli.vcpus, saved.cpus.boot_vcpus),
+            ("--memory", cli.memory, saved.memory_mib),
+            ("--max-vcpus", cli.max_vcpus, saved.cpus.max_vcpus),
+            ("--max-memory", cli.max_memory, saved.max_memory_mib),
+        ]
+        .into_iter()
+        .filter_map(|(flag, given, saved)| {
+            given
+                .filter(|given| *given != saved)
+                .map(|given| format!("{flag} {given} (saved with {saved})"))
+        })
+        .collect();
+        if !conflicts.is_empty() {
+            return Err(VmError::Suspend(format!(
+                "the VM resumes with the size it was suspended with, not {}",
+                conflicts.join(", ")
+            ))
+            .into());
+        }
+        settings.vcpus = saved.cpus.boot_vcpus;
+        settings.max_vcpus = Some(saved.cpus.max_vcpus);
+        settings.memory_mib = saved.memory_mib;
+        settings.max_memory_mib = (saved.memory_slots > 0).then_some(saved.max_memory_mib);
+        settings.memory_slots = saved.memory_slots;
+        return Ok(());
+    }
+
+    if let Some(vcpus) = cli.vcpus {
+        settings.vcpus = vcpus;
+    }
+    if let Some(memory) = cli.memory {
+        settings.memory_mib = memory;
+    }
+    if cli.max_vcpus.is_some() {
+        settings.max_vcpus = cli.max_vcpus;
+    }
+    if cli.max_memory.is_some() {
+        settings.max_memory_mib = cli.max_memory;
+    }
+    if let Some(max) = settings.max_vcpus.filter(|max| *max < settings.vcpus) {
+        anyhow::bail!(
+            "max_vcpus ({max}) must be at least vcpus ({})",
+            settings.vcpus
+        );
+    }
+    if let Some(max) = settings
+        .max_memory_mib
+        .filter(|max| *max < settings.memory_mib)
+    {
+        anyhow::bail!(
+            "max_memory_mib ({max}) must be at least memory_mib ({})",
+            settings.memory_mib
+        );
+    }
+    Ok(())
+}
+
+/// UEFI firmware code and, unless custom code comes without one, the template
+/// for its variable store. Defaults to the OVMF build in QEMU's data directory.
+fn find_firmware(
+    config: &VirtualGhostConfig,
+    qemu_config: &vm::QemuConfig,
+) -> anyhow::Result<(std::path::PathBuf, Option<std::path::PathBuf>)> {
+    // A system QEMU keeps its data in ../share/qemu relative to the binary
+    let data_dir = qemu_config.qemu_data_dir.clone().unwrap_or_else(|| {
+        qemu_config
+            .qemu_bin
+            .parent()
+            .and_then(|bin_dir| bin_dir.parent())
+            .map(|prefix| prefix.join("share").join("qemu"))
+            .unwrap_or_default()
+    });
+    let (bundled_code, bundled_vars) = vm::Firmware::bundled(&data_dir);
+    let (code, vars) = match &config.vm.firmware_path {
+        Some(code) => (code.clone(), config.vm.firmware_vars_path.clone()),
+        None => (
+            bundled_code,
+            Some(config.vm.firmware_vars_path.clone().unwrap_or(bundled_vars)),
+        ),
+    };
+    for path in std::iter::once(&code).chain(&vars) {
+        if !path.exists() {
+            anyhow::bail!(
+                "UEFI firmware {} not found; point --firmware (and firmware_vars_path) \
+                 at an OVMF build",
+                path.display()
+            );
+        }
+    }
+    Ok((code, vars))
+}
+
+/// Print the command line a launch would run, ready to paste into a shell,
+/// and for a VMM configured over its API, the requests that set up the VM.
+pub(super) fn print_launch_command(plan: &LaunchPlan) -> anyhow::Result<()> {
+    let qemu_config = &plan.qemu_config;
+    #[cfg(unix)]
+    {
+        let api = match plan.config.vm.hypervisor {
+            vm::HypervisorKind::Qemu => None,
+            vm::HypervisorKind::CloudHypervisor => {
+                let vm_config = vm::CloudHypervisor::vm_config(qemu_config, &plan.instance);
+                Some((
+                    vm::CloudHypervisor::args(&plan.instance),
+                    vec![(
+                        "/api/v1/vm.create".to_string(),
+                        serde_json::to_value(vm_config)?,
+                    )],
+                ))
+            }
+            vm::HypervisorKind::Firecracker => Some((
+                vm::Firecracker::args(&plan.instance),
+                vm::Firecracker::vm_config(qemu_config, &plan.instance)?.requests()?,
+            )),
+        };
+        if let Some((args, requests)) = api {
+            let bin = qemu_config.qemu_bin.to_string_lossy().into_owned();
+            let words: Vec<String> = std::iter::once(bin)
+                .chain(args)
+                .map(|word| ssh::shell_quote(&word).into_owned())
+                .collect();
+            println!("{}", words.join(" "));
+            for (path, body) in requests {
+                println!("# PUT {path}");
+                println!("{}", serde_json::to_string_pretty(&body)?);
+            }
+            return Ok(());
+        }
+    }
+
+    let mut words: Vec<String> = vm::QemuProcess::environment(qemu_config)
+        .into_iter()
+        .map(|(name, value)| format!("{name}={}", ssh::shell_quote(&value)))
+        .collect();
+    words.push(ssh::shell_quote(&qemu_config.qemu_bin.to_string_lossy()).into_owned());
+
+    // One option per line, with its value
+    let mut lines = vec![words.join(" ")];
+    for arg in qemu_config.to_args() {
+        let quoted = ssh::shell_quote(&arg).into_owned();
+        let is_value = !arg.starts_with('-') && lines.len() > 1;
+        match lines.last_mut() {
+            Some(line) if is_value => {
+                line.push(' ');
+                line.push_str(&quoted);
+            }
+            _ => lines.push(quoted),
+        }
+    }
+    println!("{}", lines.join(" \\\n    "));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(std::iter::once("virtualghost").chain(args.iter().copied()))
+    }
+
+    /// A VM suspended after `run --vcpus 4 --memory 4096 --max-memory 8192`.
+    fn saved() -> vm::suspend::MachineSignature {
+        vm::suspend::MachineSignature {
+            qemu_bin: "qemu-system-x86_64".into(),
+            accel: "kvm".into(),
+            cpus: vm::CpusConfig {
+                boot_vcpus: 4,
+                max_vcpus: 4,
+            },
+            memory_mib: 4096,
+            max_memory_mib: 8192,
+            memory_slots: 8,
+            kernel: "vmlinux".into(),
+            rootfs: "rootfs.ext4".into(),
+            firmware: None,
+            devices: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn flags_override_the_configuration() {
+        let mut settings = VmSettings {
+            vcpus: 6,
+            ..VmSettings::default()
+        };
+        apply_sizing(&mut settings, &cli(&["run", "--memory", "1024"]), None).unwrap();
+        assert_eq!((settings.vcpus, settings.memory_mib), (6, 1024));
+        assert_eq!(settings.max_vcpus, None);
+
+        let error = apply_sizing(&mut settings, &cli(&["run", "--max-vcpus", "2"]), None)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "max_vcpus (2) must be at least vcpus (6)");
+    }
+
+    #[test]
+    fn resuming_keeps_the_saved_size() {
+        let mut settings = VmSettings::default();
+        apply_sizing(&mut settings, &cli(&["resume"]), Some(&saved())).unwrap();
+        assert_eq!((settings.vcpus, settings.max_vcpus), (4, Some(4)));
+        assert_eq!(settings.memory_mib, 4096);
+        assert_eq!(settings.max_memory_mib, Some(8192));
+        assert_eq!(settings.memory_slots, 8);
+
+        // Repeating the original flags is fine
+        let repeated = cli(&["resume", "--vcpus", "4", "--max-memory", "8192"]);
+        apply_sizing(&mut VmSettings::default(), &repeated, Some(&saved())).unwrap();
+    }
+
+    #[test]
+    fn resuming_refuses_a_different_size() {
+        let different = cli(&[
+            "resume",
+            "--vcpus",
+            "2",
+            "--memory",
+            "4096",
+            "--max-vcpus",
+            "8",
+        ]);
+        let error = apply_sizing(&mut VmSettings::default(), &different, Some(&saved()))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.ends_with("not --vcpus 2 (saved with 4), --max-vcpus 8 (saved with 4)"),
+            "{error}"
+        );
+    }
+
+    fn qemu_config() -> vm::QemuConfig {
+        vm::QemuConfig::new(
+            "/usr/bin/qemu-system-x86_64".into(),
+            2,
+            2048,
+            "/cache/vmlinux",
+            "/cache/rootfs.ext4",
+        )
+    }
+
+    /// The kernel command line a launch with `args` hands QEMU.
+    fn booted_cmdline(settings: VmSettings, args: &[&str]) -> String {
+        let mut settings = settings;
+        apply_overrides(&mut settings, &cli(args));
+        let mut qemu_config = qemu_config();
+        direct_boot(&settings, &mut qemu_config);
+        let args = qemu_config.to_args();
+        let append = args.iter().position(|arg| arg == "-append").unwrap();
+        args[append + 1].clone()
+    }
+
+    #[test]
+    fn cmdline_replaces_the_default_and_kernel_args_append() {
+        let default = "console=ttyS0 root=/dev/vda rw quiet";
+        assert_eq!(booted_cmdline(VmSettings::default(), &["run"]), default);
+        assert_eq!(
+            booted_cmdline(
+                VmSettings::default(),
+                &[
+                    "run",
+                    "--kernel-arg",
+                    "loglevel=7",
+                    "--kernel-arg",
+                    "nokaslr"
+                ]
+            ),
+            format!("{default} loglevel=7 nokaslr")
+        );
+        assert_eq!(
+            booted_cmdline(
+                VmSettings::default(),
+                &["run", "--cmdline", "console=hvc0 root=/dev/vda1"]
+            ),
+            "console=hvc0 root=/dev/vda1"
+        );
+
+        // Configured arguments come first, then those given on the command line
+        let configured = VmSettings {
+            cmdline: Some("console=hvc0 root=/dev/vda1".into()),
+            extra_cmdline: vec!["mitigations=off".into()],
+            ..VmSettings::default()
+        };
+        assert_eq!(
+            booted_cmdline(configured, &["run", "--kernel-arg", "nokaslr"]),
+            "console=hvc0 root=/dev/vda1 mitigations=off nokaslr"
+        );
+    }
+
+    #[test]
+    fn initramfs_is_passed_as_initrd() {
+        let mut settings = VmSettings::default();
+        apply_overrides(
+            &mut settings,
+            &cli(&["run", "--initramfs", "/boot/initrd.img"]),
+        );
+        let mut qemu_config = qemu_config();
+        direct_boot(&settings, &mut qemu_config);
+        let args = qemu_config.to_args();
+        let initrd = args.iter().position(|arg| arg == "-initrd").unwrap();
+        assert_eq!(args[initrd + 1], "/boot/initrd.img");
+    }
+
+    #[test]
+    fn firmware_implies_uefi() {
+        let mut settings = VmSettings::default();
+        apply_overrides(&mut settings, &cli(&["run"]));
+        assert!(!settings.uefi);
+        apply_overrides(
+            &mut settings,
+            &cli(&["run", "--firmware", "/fw/OVMF_CODE.fd"]),
+        );
+        assert!(settings.uefi);
+        assert_eq!(settings.firmware_path, Some("/fw/OVMF_CODE.fd".into()));
+    }
+
+    #[test]
+    fn firmware_is_found_next_to_qemu_or_where_configured() {
+        let dir = tempfile::tempdir().unwrap();
+        let touch = |path: &std::path::Path| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        };
+        let mut config = VirtualGhostConfig::default();
+        let mut qemu_config = qemu_config();
+        qemu_config.qemu_bin = dir.path().join("bin").join("qemu-system-x86_64");
+
+        // A system QEMU: ../share/qemu relative to the binary
+        let share = dir.path().join("share").join("qemu");
+        let error = find_firmware(&config, &qemu_config)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "UEFI firmware {} not found; point --firmware (and firmware_vars_path) \
+                 at an OVMF build",
+                share.join("edk2-x86_64-code.fd").display()
+            )
+        );
+        touch(&share.join("edk2-x86_64-code.fd"));
+        let error = find_firmware(&config, &qemu_config)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("edk2-i386-vars.fd not found"), "{error}");
+        touch(&share.join("edk2-i386-vars.fd"));
+        assert_eq!(
+            find_firmware(&config, &qemu_config).unwrap(),
+            (
+                share.join("edk2-x86_64-code.fd"),
+                Some(share.join("edk2-i386-vars.fd"))
+            )
+        );
+
+        // The embedded QEMU's extracted data directory
+        let data = dir.path().join("data");
+        qemu_config.qemu_data_dir = Some(data.clone());
+        touch(&data.join("edk2-x86_64-code.fd"));
+        touch(&data.join("edk2-i386-vars.fd"));
+        let (code, vars) = find_firmware(&config, &qemu_config).unwrap();
+        assert_eq!(code, data.join("edk2-x86_64-code.fd"));
+        assert_eq!(vars, Some(data.join("edk2-i386-vars.fd")));
+
+        // Custom code keeps no variables unless given a template for them
+        let custom = dir.path().join("OVMF_CODE.fd");
+        touch(&custom);
+        config.vm.firmware_path = Some(custom.clone());
+        assert_eq!(
+            find_firmware(&config, &qemu_config).unwrap(),
+            (custom.clone(), None)
+        );
+        let custom_vars = dir.path().join("OVMF_VARS.fd");
+        config.vm.firmware_vars_path = Some(custom_vars.clone());
+        let error = find_firmware(&config, &qemu_config)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("OVMF_VARS.fd not found"), "{error}");
+        touch(&custom_vars);
+        assert_eq!(
+            find_firmware(&config, &qemu_config).unwrap(),
+            (custom, Some(custom_vars))
+        );
+    }
+}
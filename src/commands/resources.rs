@@ -0,0 +1,112 @@
+use crate::cli::{CpusArgs, MemArgs};
+use crate::config::VirtualGhostConfig;
+use crate::error::VmError;
+use crate::vm;
+use std::time::Duration;
+
+/// Smallest guest memory size the balloon may shrink a VM to, in MiB.
+pub(super) const MIN_GUEST_MIB: u32 = 128;
+
+/// How long `mem --set` waits for the guest to reach the new size.
+const BALLOON_SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connect to the QMP monitor a running VM keeps free for other invocations.
+pub(super) async fn connect_control(
+    name: &str,
+) -> anyhow::Result<(vm::InstanceDir, vm::InstanceState, vm::QmpClient)> {
+    let instance = vm::InstanceDir::new(name);
+    let state = instance
+        .running_state()?
+        .ok_or_else(|| VmError::NotRunning(name.to_string()))?;
+    if state.hypervisor != vm::HypervisorKind::Qemu {
+        return Err(VmError::Unsupported(format!(
+            "VM '{name}' runs on {}; this needs QEMU",
+            state.hypervisor
+        ))
+        .into());
+    }
+    let Some(addr) = &state.qmp else {
+        anyhow::bail!("VM '{name}' has no control socket");
+    };
+    let qmp = vm::QmpClient::connect(addr).await?;
+    Ok((instance, state, qmp))
+}
+
+/// Show a running VM's guest memory, resize it through the balloon or hot-add a DIMM.
+pub async fn cmd_mem(mem: &MemArgs) -> anyhow::Result<()> {
+    use vm::balloon::MIB;
+
+    let name = &mem.target.name;
+    let (_, state, qmp) = connect_control(name).await?;
+    if let Some(mib) = mem.add {
+        vm::hotplug::add_dimm(&qmp, mib, state.machine.as_ref()).await?;
+    }
+    // What the guest booted with plus hot-added DIMMs
+    let installed = match &state.machine {
+        Some(machine) => {
+            Some(u64::from(machine.memory_mib) * MIB + vm::hotplug::dimm_memory(&qmp).await?)
+        }
+        None => None,
+    };
+
+    if let Some(mib) = mem.set {
+        let target = u64::from(mib) * MIB;
+        if let Some(installed) = installed.filter(|installed| target > *installed) {
+            return Err(VmError::Balloon(format!(
+                "VM '{name}' has {} MiB installed and cannot grow beyond that (see `mem --add`)",
+                installed / MIB
+            ))
+            .into());
+        }
+        if mib < MIN_GUEST_MIB {
+            return Err(VmError::Balloon(format!(
+                "refusing to shrink the guest below {MIN_GUEST_MIB} MiB"
+            ))
+            .into());
+        }
+        qmp.balloon(target).await?;
+
+        // The guest hands memory over gradually
+        let deadline = tokio::time::Instant::now() + BALLOON_SETTLE_TIMEOUT;
+        while qmp.query_balloon().await?.abs_diff(target) >= MIB
+            && tokio::time::Instant::now() < deadline
+        {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        if VirtualGhostConfig::load()?.vm.balloon_auto {
+            tracing::warn!("The automatic balloon policy may resize the guest again");
+        }
+    }
+
+    let stats = vm::balloon::stats(&qmp).await?;
+    print!("{name}: {} MiB", stats.actual / MIB);
+    if let Some(installed) = installed {
+        print!(" of {} MiB", installed / MIB);
+    }
+    if let Some(available) = stats.available {
+        print!(" ({} MiB available in the guest)", available / MIB);
+    }
+    println!();
+    if let Some(machine) = state
+        .machine
+        .filter(|machine| machine.max_memory_mib > machine.memory_mib)
+    {
+        println!(
+            "Hot-add up to {} MiB in {} slots with `mem --add`",
+            machine.max_memory_mib, machine.memory_slots
+        );
+    }
+    Ok(())
+}
+
+/// Show a running VM's vCPUs, or hot-add more.
+pub async fn cmd_cpus(cpus: &CpusArgs) -> anyhow::Result<()> {
+    let name = &cpus.target.name;
+    let (_, _, qmp) = connect_control(name).await?;
+    if let Some(count) = cpus.add {
+        vm::hotplug::add_vcpus(&qmp, count).await?;
+    }
+    let count = vm::hotplug::cpu_count(&qmp).await?;
+    println!("{name}: {} vCPUs (max {})", count.present, count.max);
+    Ok(())
+}
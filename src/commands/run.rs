@@ -0,0 +1,227 @@
+use crate::cli::{Cli, InstanceArgs, ResumeArgs, RunArgs};
+#[cfg(unix)]
+use crate::terminal;
+use crate::{network, ssh, vm};
+
+#[cfg(unix)]
+use super::guest::connect_agent;
+use super::helpers::spawn_helper;
+use super::launch::{
+    launch, report_agent_events, shutdown_signal, stop_vm, LaunchOptions, LaunchedVm,
+};
+use super::plan::{plan_launch, print_launch_command};
+
+/// Run a VM until it exits, returning the launcher's exit status (see [`vm::VmOutcome`]).
+pub async fn cmd_run(cli: &Cli, run: &RunArgs) -> anyhow::Result<i32> {
+    let options = LaunchOptions {
+        name: run.instance.name.clone(),
+        console_stdio: run.console_stdio,
+        headless: false,
+        resume: false,
+        dry_run: run.dry_run,
+    };
+    if run.dry_run {
+        print_launch_command(&plan_launch(cli, &options)?)?;
+        return Ok(0);
+    }
+    supervise(cli, &options, run.wait_ready).await
+}
+
+/// Continue a suspended VM, supervising it like `run` does.
+pub async fn cmd_resume(cli: &Cli, resume: &ResumeArgs) -> anyhow::Result<i32> {
+    let options = LaunchOptions {
+        name: resume.target.name.clone(),
+        console_stdio: resume.console_stdio,
+        headless: false,
+        resume: true,
+        dry_run: false,
+    };
+    supervise(cli, &options, resume.wait_ready).await
+}
+
+/// Launch a VM, then either hand it off once ready or run it in the foreground until it exits.
+async fn supervise(cli: &Cli, options: &LaunchOptions, wait_ready: bool) -> anyhow::Result<i32> {
+    let mut vm = launch(cli, options).await?;
+
+    if wait_ready {
+        if !vm.wait_ready().await? {
+            return Ok(0);
+        }
+
+        // Hand the VM off to run in the background
+        let LaunchedVm {
+            vmm,
+            instance,
+            mut state,
+            host_key,
+            ..
+        } = vm;
+        let pid = vmm.detach();
+        state.launcher_pid = None;
+        state.launcher_identity = None;
+        instance.save_state(&state)?;
+        // Nothing else would stop a Cloud Hypervisor whose guest powered off
+        if state.hypervisor == vm::HypervisorKind::CloudHypervisor {
+            spawn_helper("vmm-reaper", &instance, &[])?;
+        }
+
+        println!(
+            "VM '{}' ready (pid {})",
+            instance.name(),
+            pid.map_or("?".into(), |p| p.to_string())
+        );
+        if let Some(addr) = &state.qmp {
+            println!("QMP:         {addr}");
+        }
+        if let Some(endpoint) = &state.agent {
+            println!("Guest agent: {endpoint}");
+        }
+        println!("Identity:    {}", state.identity.display());
+        println!("Host key:    {}", host_key.fingerprint(Default::default()));
+        println!("Serial log:  {}", instance.serial_log().display());
+        println!("Instance:    {}", instance.path().display());
+        return Ok(0);
+    }
+
+    // Report readiness in the background while Ghostty runs; a timeout is logged, not fatal
+    let readiness = vm.state.agent.clone().map(|endpoint| {
+        let (boot_timeout, serial_log) = (vm.boot_timeout, vm.instance.serial_log());
+        let status = vm.events.as_ref().map(network::AgentEventListener::status);
+        tokio::spawn(async move {
+            if let Err(e) =
+                vm::wait_until_ready(endpoint, boot_timeout, Some(serial_log), status).await
+            {
+                tracing::error!("{e}");
+            }
+        })
+    });
+
+    let reporter = vm
+        .events
+        .as_ref()
+        .map(|events| tokio::spawn(report_agent_events(events.subscribe())));
+
+    // Wait for the VM process to exit (user closes Ghostty), or power it off on Ctrl-C/SIGTERM
+    let (status, stop_requested) = tokio::select! {
+        status = vm.vmm.wait() => (status?, false),
+        signal = shutdown_signal() => {
+            tracing::info!(signal, "Shutting down VM");
+            (stop_vm(&mut vm.vmm, vm.grace).await?, true)
+        }
+    };
+    tracing::info!(?status, hypervisor = %vm.vmm.kind(), "VMM exited");
+
+    if let Some(readiness) = readiness {
+        readiness.abort();
+    }
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    if let Some(echo) = vm.console_echo.take() {
+        echo.finish().await;
+    }
+
+    let outcome = vm.exited(status, stop_requested).await;
+    if outcome.is_clean() {
+        tracing::info!("{}", outcome.summary());
+    } else {
+        eprintln!("{}", outcome.report(&vm.instance.serial_log()));
+    }
+    Ok(outcome.exit_code())
+}
+
+/// Boot a headless VM, attach the host terminal to a login shell in it and
+/// power the VM off once the shell exits. Returns the shell's exit status.
+pub async fn cmd_shell(cli: &Cli, target: &InstanceArgs) -> anyhow::Result<i32> {
+    #[cfg(not(unix))]
+    {
+        let _ = (cli, target);
+        anyhow::bail!("`shell` needs a Unix host terminal");
+    }
+
+    #[cfg(unix)]
+    {
+        let options = LaunchOptions {
+            name: target.name.clone(),
+            console_stdio: false,
+            headless: true,
+            resume: false,
+            dry_run: false,
+        };
+        eprintln!("Booting VM…");
+        let mut vm = launch(cli, &options).await?;
+        if !vm.wait_ready().await? {
+            return Ok(130);
+        }
+
+        let result = attach_shell(&mut vm).await;
+        if vm.vmm.id().is_some() {
+            let status = vm.stop().await?;
+            tracing::info!(?status, "VMM exited");
+        }
+        result
+    }
+}
+
+/// Relay the host terminal to a PTY session in the guest until the shell exits.
+#[cfg(unix)]
+async fn attach_shell(vm: &mut LaunchedVm) -> anyhow::Result<i32> {
+    use russh::ChannelMsg;
+    use std::io::IsTerminal;
+    use tokio::io::AsyncWriteExt;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let client = connect_agent(&vm.instance).await?;
+    let (cols, rows) = terminal::size().unwrap_or((80, 24));
+    let mut session = ssh::SshSession::open(client.handle(), cols, rows).await?;
+
+    let raw = if std::io::stdin().is_terminal() {
+        Some(terminal::RawMode::enable()?)
+    } else {
+        None
+    };
+
+    let mut input = session.writer();
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let _ = tokio::io::copy(&mut stdin, &mut input).await;
+        let _ = input.shutdown().await;
+    });
+
+    let mut stdout = tokio::io::stdout();
+    let mut winch = signal(SignalKind::window_change())?;
+    let stop = shutdown_signal();
+    tokio::pin!(stop);
+
+    let mut code = None;
+    loop {
+        tokio::select! {
+            msg = session.read() => match msg {
+                Some(ChannelMsg::Data { data }) => {
+                    stdout.write_all(&data).await?;
+                    stdout.flush().await?;
+                }
+                Some(ChannelMsg::ExitStatus { exit_status }) => code = Some(exit_status as i32),
+                Some(ChannelMsg::Close) | None => break,
+                _ => {}
+            },
+            _ = winch.recv() => {
+                if let Some((cols, rows)) = terminal::size() {
+                    session.resize(cols, rows).await?;
+                }
+            }
+            status = vm.vmm.wait() => {
+                let outcome = vm.exited(status?, false).await;
+                drop(raw);
+                eprintln!("\n{}", outcome.report(&vm.instance.serial_log()));
+                return Ok(outcome.exit_code());
+            }
+            signal = &mut stop => {
+                tracing::info!(signal, "Shutting down VM");
+                return Ok(130);
+            }
+        }
+    }
+
+    Ok(code.unwrap_or(255))
+}
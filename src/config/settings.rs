@@ -1,23 +1,68 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::vm::HypervisorKind;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct VirtualGhostConfig {
+    #[serde(default)]
     pub vm: VmSettings,
+    #[serde(default)]
     pub ssh: SshSettings,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VmSettings {
     pub vcpus: u32,
     pub memory_mib: u32,
+    /// vCPUs the VM can grow to by hot-adding them; defaults to `vcpus` (no hotplug).
+    pub max_vcpus: Option<u32>,
+    /// Memory in MiB the VM can grow to by hot-adding DIMMs; defaults to `memory_mib` (no hotplug).
+    pub max_memory_mib: Option<u32>,
+    /// DIMM slots available for hot-added memory.
+    pub memory_slots: u32,
     pub kernel_path: Option<PathBuf>,
     pub rootfs_path: Option<PathBuf>,
+    /// Keep each VM's changes to the rootfs across boots, in its instance directory.
+    pub persist: bool,
+    /// Kernel command line replacing the default one.
+    pub cmdline: Option<String>,
+    /// Arguments appended to the kernel command line.
+    pub extra_cmdline: Vec<String>,
+    pub initramfs_path: Option<PathBuf>,
+    /// Boot the rootfs as a disk image through UEFI firmware instead of the kernel directly.
+    pub uefi: bool,
+    /// UEFI firmware code; defaults to the OVMF build shipped with QEMU.
+    pub firmware_path: Option<PathBuf>,
+    /// Template for each VM's UEFI variable store; defaults to the one shipped with QEMU.
+    pub firmware_vars_path: Option<PathBuf>,
     pub qemu_bin: Option<PathBuf>,
+    /// VMM running the guest: `qemu`, `cloud-hypervisor` or `firecracker`.
+    pub hypervisor: HypervisorKind,
+    /// Cloud Hypervisor binary; defaults to `cloud-hypervisor` on the PATH.
+    pub cloud_hypervisor_bin: Option<PathBuf>,
+    /// Firecracker binary; defaults to `firecracker` on the PATH.
+    pub firecracker_bin: Option<PathBuf>,
     pub gpu_pci_address: Option<String>,
+    /// Seconds to wait for the guest to power off after ACPI shutdown before killing QEMU.
+    pub shutdown_timeout_secs: u64,
+    /// Seconds to wait for the guest agent to answer after QEMU starts.
+    pub boot_timeout_secs: u64,
+    /// Size at which the serial console log is rotated, in KiB.
+    pub serial_log_max_kib: u64,
+    /// Rotated serial console logs to keep besides the current one.
+    pub serial_log_keep: usize,
+    /// Shrink the guest through the memory balloon while it is idle, growing it back on demand.
+    pub balloon_auto: bool,
+    /// Smallest size the automatic balloon policy shrinks the guest to, in MiB.
+    pub balloon_min_mib: u32,
+    /// Arguments passed to QEMU verbatim, after the ones VirtualGhost generates.
+    pub extra_args: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SshSettings {
     pub key_path: Option<PathBuf>,
     pub vsock_port: u32,
@@ -46,23 +91,53 @@ impl VirtualGhostConfig {
             .map(|dirs| dirs.cache_dir().to_path_buf())
             .unwrap_or_else(|| PathBuf::from(".cache"))
     }
+
+    /// Root of the per-VM state directories (credentials, serial log, runtime state).
+    pub fn instances_dir() -> PathBuf {
+        directories::ProjectDirs::from("com", "virtualghost", "VirtualGhost")
+            .map(|dirs| dirs.data_local_dir().join("instances"))
+            .unwrap_or_else(|| PathBuf::from("instances"))
+    }
+}
+
+impl Default for VmSettings {
+    fn default() -> Self {
+        Self {
+            vcpus: 2,
+            memory_mib: 2048,
+            max_vcpus: None,
+            max_memory_mib: None,
+            memory_slots: 8,
+            kernel_path: None,
+            rootfs_path: None,
+            persist: false,
+            cmdline: None,
+            extra_cmdline: Vec::new(),
+            initramfs_path: None,
+            uefi: false,
+            firmware_path: None,
+            firmware_vars_path: None,
+            qemu_bin: None,
+            hypervisor: HypervisorKind::Qemu,
+            cloud_hypervisor_bin: None,
+            firecracker_bin: None,
+            gpu_pci_address: None,
+            shutdown_timeout_secs: 30,
+            boot_timeout_secs: 120,
+            serial_log_max_kib: 1024,
+            serial_log_keep: 3,
+            balloon_auto: false,
+            balloon_min_mib: 512,
+            extra_args: Vec::new(),
+        }
+    }
 }
 
-impl Default for VirtualGhostConfig {
+impl Default for SshSettings {
     fn default() -> Self {
         Self {
-            vm: VmSettings {
-                vcpus: 2,
-                memory_mib: 2048,
-                kernel_path: None,
-                rootfs_path: None,
-                qemu_bin: None,
-                gpu_pci_address: None,
-            },
-            ssh: SshSettings {
-                key_path: None,
-                vsock_port: 52,
-            },
+            key_path: None,
+            vsock_port: 52,
         }
     }
 }
@@ -24,8 +24,14 @@ pub enum VmError {
     #[error("failed to spawn QEMU process: {0}")]
     SpawnFailed(std::io::Error),
 
-    #[error("VM boot timed out")]
-    BootTimeout,
+    #[error(
+        "VM boot timed out after {timeout_secs}s waiting for the guest agent{}",
+        format_serial_tail(.serial_tail)
+    )]
+    BootTimeout {
+        timeout_secs: u64,
+        serial_tail: Vec<String>,
+    },
 
     #[error("QMP error: {0}")]
     QmpError(String),
@@ -41,6 +47,51 @@ pub enum VmError {
 
     #[error("GPU device not found: {0}")]
     GpuNotFound(String),
+
+    #[error("no running VM '{0}' (start one with `virtualghost run --wait-ready`)")]
+    NotRunning(String),
+
+    #[error("instance state is unreadable: {0}")]
+    InstanceState(String),
+
+    #[error("VM '{0}' is already running (stop it with `virtualghost stop {0}`)")]
+    AlreadyRunning(String),
+
+    #[error("no free {0} for another VM")]
+    Exhausted(&'static str),
+
+    #[error("serial console logger failed: {0}")]
+    SerialLog(String),
+
+    #[error("VM '{0}' is suspended (continue it with `virtualghost resume {0}`, or discard it with `virtualghost stop {0}`)")]
+    Suspended(String),
+
+    #[error("VM '{0}' has no saved state (save one with `virtualghost suspend {0}`)")]
+    NotSuspended(String),
+
+    #[error("cannot suspend or resume: {0}")]
+    Suspend(String),
+
+    #[error("memory balloon: {0}")]
+    Balloon(String),
+
+    #[error("hotplug failed: {0}")]
+    Hotplug(String),
+
+    #[error("cannot probe QEMU: {0}")]
+    Probe(String),
+
+    #[error("{0}")]
+    Unsupported(String),
+
+    #[error("failed to spawn {0}: {1}")]
+    VmmSpawnFailed(String, std::io::Error),
+
+    #[error("VMM API request failed: {0}")]
+    Api(String),
+
+    #[error("disk overlay: {0}")]
+    Overlay(String),
 }
 
 #[allow(dead_code)]
@@ -52,11 +103,17 @@ pub enum SshError {
     #[error("SSH authentication failed")]
     AuthFailed,
 
+    #[error("guest host key mismatch: expected {expected}, got {actual}")]
+    HostKeyMismatch { expected: String, actual: String },
+
     #[error("SSH channel error: {0}")]
     ChannelError(String),
 
     #[error("key generation failed: {0}")]
     KeyGeneration(String),
+
+    #[error("file transfer failed: {0}")]
+    Transfer(String),
 }
 
 #[allow(dead_code)]
@@ -65,6 +122,9 @@ pub enum NetworkError {
     #[error("vsock connection failed: {0}")]
     VsockConnectionFailed(String),
 
+    #[error("vsock is unavailable on this host: {0}")]
+    VsockUnavailable(String),
+
     #[error("tunnel error: {0}")]
     TunnelError(String),
 }
@@ -78,3 +138,16 @@ pub enum ConfigError {
     #[error("config file error: {0}")]
     FileError(String),
 }
+
+/// Render the last serial console lines as an indented block for error messages.
+fn format_serial_tail(lines: &[String]) -> String {
+    if lines.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("\n  last serial console output:");
+    for line in lines {
+        out.push_str("\n    ");
+        out.push_str(line);
+    }
+    out
+}
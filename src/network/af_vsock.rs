@@ -0,0 +1,51 @@
+#![cfg(target_os = "linux")]
+
+use crate::error::{NetworkError, VirtualGhostError};
+use nix::libc;
+use std::path::Path;
+use tokio_vsock::{VsockAddr, VsockStream};
+use tracing::debug;
+
+/// Host device QEMU's vhost-vsock backend opens; created by the vhost_vsock module.
+const VHOST_VSOCK_DEVICE: &str = "/dev/vhost-vsock";
+
+pub struct AfVsockConnection {
+    stream: VsockStream,
+}
+
+impl AfVsockConnection {
+    /// Connect to a guest port over an `AF_VSOCK` socket, as served by QEMU's
+    /// vhost-vsock device.
+    ///
+    /// Unlike the hybrid vsock of Cloud Hypervisor and Firecracker there is no
+    /// handshake: the kernel routes the connection to `(cid, port)` directly.
+    /// A host without vsock support fails with
+    /// [`NetworkError::VsockUnavailable`], which retrying cannot fix.
+    pub async fn connect(cid: u32, port: u32) -> Result<Self, VirtualGhostError> {
+        debug!(cid, port, "Connecting to guest via AF_VSOCK");
+
+        let stream = VsockStream::connect(VsockAddr::new(cid, port))
+            .await
+            .map_err(|e| match e.raw_os_error() {
+                // ENODEV also comes back briefly while QEMU sets up the device
+                Some(libc::EAFNOSUPPORT) => vsock_unavailable(cid, port, &e),
+                Some(libc::ENODEV) if !Path::new(VHOST_VSOCK_DEVICE).exists() => {
+                    vsock_unavailable(cid, port, &e)
+                }
+                _ => NetworkError::VsockConnectionFailed(format!("vsock:{cid}:{port}: {e}")),
+            })?;
+
+        debug!(cid, port, "Vsock connection established");
+        Ok(Self { stream })
+    }
+
+    pub fn into_stream(self) -> VsockStream {
+        self.stream
+    }
+}
+
+fn vsock_unavailable(cid: u32, port: u32, e: &std::io::Error) -> NetworkError {
+    NetworkError::VsockUnavailable(format!(
+        "vsock:{cid}:{port}: {e} (is the vhost_vsock module loaded?)"
+    ))
+}
@@ -0,0 +1,406 @@
+use crate::error::{NetworkError, VirtualGhostError};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Vsock port the guest sends events to under Cloud Hypervisor and Firecracker,
+/// which forward it to the Unix socket `<vsock socket>_<port>` on the host.
+pub const HYBRID_EVENTS_PORT: u32 = 53;
+
+/// The host's vsock CID, as seen from any guest.
+const HOST_CID: u32 = 2;
+
+/// The host's address on QEMU's user-mode network.
+const USER_NET_HOST: &str = "10.0.2.2";
+
+/// Longest event line accepted; the guest is not trusted to be well-behaved.
+const MAX_EVENT_LEN: u64 = 64 * 1024;
+
+/// Something the guest agent reports on its own initiative, one JSON object
+/// per line: `{"event":"ghostty-exited","code":0}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum AgentEvent {
+    /// The agent is up and serving SSH.
+    BootComplete,
+    /// The kiosk session ended with Cage's exit status, which is Ghostty's.
+    GhosttyExited { code: i32 },
+    /// A message for the user, e.g. from `ghostly-agent event notification` in a guest script.
+    Notification {
+        title: String,
+        #[serde(default)]
+        body: String,
+    },
+    /// Something went wrong in the guest.
+    Error { message: String },
+}
+
+/// What the guest agent has reported so far about the guest's lifecycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AgentStatus {
+    /// [`AgentEvent::BootComplete`] arrived.
+    pub boot_complete: bool,
+    /// Exit status from the last [`AgentEvent::GhosttyExited`].
+    pub ghostty_exit: Option<i32>,
+}
+
+/// How the guest reaches the launcher's event listener, per backend.
+#[derive(Debug, Clone)]
+pub enum EventTransport {
+    /// AF_VSOCK on `port`, or a free one, accepting connections from guest
+    /// `cid` only — QEMU on Linux.
+    Vsock { cid: u32, port: Option<u32> },
+    /// The Unix socket a hybrid vsock VMM forwards guest connections to
+    /// [`HYBRID_EVENTS_PORT`] to — Cloud Hypervisor and Firecracker.
+    HybridVsock { socket: PathBuf },
+    /// Loopback TCP on `port`, or a free one, reached through QEMU user networking.
+    Tcp { port: Option<u16> },
+}
+
+/// The port in an address from [`AgentEventListener::guest_address`], to
+/// listen on again for a guest that was told it before.
+pub fn guest_port(guest_address: &str) -> Option<u32> {
+    match guest_address.split_whitespace().collect::<Vec<_>>()[..] {
+        ["vsock" | "tcp", _, port] => port.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Accepts the guest agent's connections and fans the events they carry out
+/// to every subscriber, keeping track of the [`AgentStatus`] they add up to.
+pub struct AgentEventListener {
+    events: broadcast::Sender<AgentEvent>,
+    status: watch::Receiver<AgentStatus>,
+    guest_address: String,
+    socket: Option<PathBuf>,
+    task: JoinHandle<()>,
+    recorder: JoinHandle<()>,
+}
+
+impl AgentEventListener {
+    /// Start listening; the guest has to be told [`Self::guest_address`] before it boots.
+    pub async fn bind(transport: &EventTransport) -> Result<Self, VirtualGhostError> {
+        let (events, _) = broadcast::channel(64);
+        let sender = events.clone();
+        let (status_tx, status) = watch::channel(AgentStatus::default());
+        let mut received = events.subscribe();
+        let (guest_address, socket, task) = match transport {
+            #[cfg(target_os = "linux")]
+            EventTransport::Vsock { cid, port } => {
+                use tokio_vsock::{VsockAddr, VsockListener, VMADDR_CID_ANY};
+
+                let unavailable = |e: std::io::Error| {
+                    NetworkError::VsockUnavailable(format!("event listener: {e}"))
+                };
+                let port = port.unwrap_or(nix::libc::VMADDR_PORT_ANY);
+                let addr = VsockAddr::new(VMADDR_CID_ANY, port);
+                let mut listener = VsockListener::bind(addr).map_err(unavailable)?;
+                let port = listener.local_addr().map_err(unavailable)?.port();
+                info!(port, "Listening for guest agent events on vsock");
+                let cid = *cid;
+                let task = tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, peer)) if peer.cid() == cid => {
+                                tokio::spawn(read_events(stream, sender.clone()));
+                            }
+                            Ok((_, peer)) => {
+                                debug!(cid = peer.cid(), "Ignoring events from another guest")
+                            }
+                            Err(e) => warn!(error = %e, "Event listener accept failed"),
+                        }
+                    }
+                });
+                (format!("vsock {HOST_CID} {port}"), None, task)
+            }
+            #[cfg(not(target_os = "linux"))]
+            EventTransport::Vsock { .. } => {
+                return Err(NetworkError::VsockUnavailable(
+                    "AF_VSOCK is only supported on Linux hosts".into(),
+                )
+                .into())
+            }
+            #[cfg(unix)]
+            EventTransport::HybridVsock { socket } => {
+                let path = PathBuf::from(format!("{}_{HYBRID_EVENTS_PORT}", socket.display()));
+                let _ = std::fs::remove_file(&path);
+                let listener = tokio::net::UnixListener::bind(&path).map_err(|e| {
+                    NetworkError::TunnelError(format!("event listener {}: {e}", path.display()))
+                })?;
+                info!(socket = %path.display(), "Listening for guest agent events");
+                let task = tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => {
+                                tokio::spawn(read_events(stream, sender.clone()));
+                            }
+                            Err(e) => warn!(error = %e, "Event listener accept failed"),
+                        }
+                    }
+                });
+                (
+                    format!("vsock {HOST_CID} {HYBRID_EVENTS_PORT}"),
+                    Some(path),
+                    task,
+                )
+            }
+            #[cfg(not(unix))]
+            EventTransport::HybridVsock { .. } => {
+                return Err(
+                    NetworkError::TunnelError("hybrid vsock needs a Unix host".into()).into(),
+                )
+            }
+            EventTransport::Tcp { port } => {
+                let listener = tokio::net::TcpListener::bind(("127.0.0.1", port.unwrap_or(0)))
+                    .await
+                    .map_err(|e| NetworkError::TunnelError(format!("event listener: {e}")))?;
+                let port = listener.local_addr()?.port();
+                info!(port, "Listening for guest agent events on TCP");
+                let task = tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => {
+                                tokio::spawn(read_events(stream, sender.clone()));
+                            }
+                            Err(e) => warn!(error = %e, "Event listener accept failed"),
+                        }
+                    }
+                });
+                (format!("tcp {USER_NET_HOST} {port}"), None, task)
+            }
+        };
+        // Subscribed before the guest can connect, so no event is missed
+        let recorder = tokio::spawn(async move {
+            loop {
+                let event = match received.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                status_tx.send_modify(|status| match event {
+                    AgentEvent::BootComplete => status.boot_complete = true,
+                    AgentEvent::GhosttyExited { code } => status.ghostty_exit = Some(code),
+                    _ => {}
+                });
+            }
+        });
+        Ok(Self {
+            events,
+            status,
+            guest_address,
+            socket,
+            task,
+            recorder,
+        })
+    }
+
+    /// Where the guest connects, as `<vsock|tcp> <host> <port>`; handed to the
+    /// agent via fw_cfg.
+    pub fn guest_address(&self) -> &str {
+        &self.guest_address
+    }
+
+    /// Events received from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentEvent> {
+        self.events.subscribe()
+    }
+
+    /// What the agent has reported since the listener started, updated as events arrive.
+    pub fn status(&self) -> watch::Receiver<AgentStatus> {
+        self.status.clone()
+    }
+}
+
+impl Drop for AgentEventListener {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.recorder.abort();
+        if let Some(socket) = &self.socket {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
+
+/// Forward the events on one connection until the guest closes it.
+async fn read_events<S>(stream: S, events: broadcast::Sender<AgentEvent>)
+where
+    S: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(stream.take(MAX_EVENT_LEN));
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                debug!(error = %e, "Guest agent event connection failed");
+                return;
+            }
+        }
+        // Every line gets the same allowance
+        reader.get_mut().set_limit(MAX_EVENT_LEN);
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<AgentEvent>(&line) {
+            Ok(event) => {
+                debug!(?event, "Guest agent event");
+                // Nobody listening is fine: events are advisory
+                let _ = events.send(event);
+            }
+            Err(e) => {
+                debug!(line = line.trim_end(), error = %e, "Ignoring unknown guest agent event")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    /// Send `lines` to `listener` the way the guest agent does, one connection each.
+    async fn send(listener: &AgentEventListener, lines: &[&str]) {
+        let port: u16 = listener
+            .guest_address()
+            .rsplit_once(' ')
+            .unwrap()
+            .1
+            .parse()
+            .unwrap();
+        for line in lines {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+            stream.write_all(line.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn status_tracks_boot_and_ghostty_exit() {
+        let listener = AgentEventListener::bind(&EventTransport::Tcp { port: None })
+            .await
+            .unwrap();
+        assert!(listener.guest_address().starts_with("tcp 10.0.2.2 "));
+        let mut status = listener.status();
+        assert_eq!(*status.borrow(), AgentStatus::default());
+
+        send(&listener, &["{\"event\":\"boot-complete\"}\n"]).await;
+        let booted = tokio::time::timeout(
+            Duration::from_secs(5),
+            status.wait_for(|status| status.boot_complete),
+        );
+        assert!(booted.await.unwrap().is_ok());
+
+        send(
+            &listener,
+            &[
+                "not json\n{\"event\":\"notification\",\"title\":\"hi\"}\n",
+                "{\"event\":\"ghostty-exited\",\"code\":2}\n",
+            ],
+        )
+        .await;
+        let exited = tokio::time::timeout(
+            Duration::from_secs(5),
+            status.wait_for(|status| status.ghostty_exit.is_some()),
+        );
+        assert!(exited.await.unwrap().is_ok());
+        assert_eq!(
+            *status.borrow(),
+            AgentStatus {
+                boot_complete: true,
+                ghostty_exit: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn guest_port_reads_listener_addresses() {
+        assert_eq!(guest_port("vsock 2 1024"), Some(1024));
+        assert_eq!(guest_port("tcp 10.0.2.2 40000\n"), Some(40000));
+        assert_eq!(guest_port(""), None);
+        assert_eq!(guest_port("tcp 10.0.2.2"), None);
+        assert_eq!(guest_port("udp 10.0.2.2 53"), None);
+    }
+
+    #[tokio::test]
+    async fn listens_again_where_the_guest_was_told() {
+        let first = AgentEventListener::bind(&EventTransport::Tcp { port: None })
+            .await
+            .unwrap();
+        let address = first.guest_address().to_string();
+        let port = guest_port(&address).and_then(|port| u16::try_from(port).ok());
+        drop(first);
+
+        // The aborted accept task releases the port once the runtime gets to it
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        let again = loop {
+            match AgentEventListener::bind(&EventTransport::Tcp { port }).await {
+                Ok(listener) => break listener,
+                Err(e) if tokio::time::Instant::now() >= deadline => panic!("{e}"),
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        assert_eq!(again.guest_address(), address);
+    }
+
+    #[test]
+    fn events_use_the_guest_wire_format() {
+        let cases = [
+            (AgentEvent::BootComplete, r#"{"event":"boot-complete"}"#),
+            (
+                AgentEvent::GhosttyExited { code: 1 },
+                r#"{"event":"ghostty-exited","code":1}"#,
+            ),
+            (
+                AgentEvent::Notification {
+                    title: "Build".into(),
+                    body: "done".into(),
+                },
+                r#"{"event":"notification","title":"Build","body":"done"}"#,
+            ),
+            (
+                AgentEvent::Error {
+                    message: "no GPU".into(),
+                },
+                r#"{"event":"error","message":"no GPU"}"#,
+            ),
+        ];
+        for (event, json) in cases {
+            assert_eq!(serde_json::to_string(&event).unwrap(), json);
+            assert_eq!(serde_json::from_str::<AgentEvent>(json).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn notifications_may_leave_out_the_body() {
+        let event: AgentEvent =
+            serde_json::from_str(r#"{"event":"notification","title":"Build"}"#).unwrap();
+        assert_eq!(
+            event,
+            AgentEvent::Notification {
+                title: "Build".into(),
+                body: String::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_events_are_rejected() {
+        for json in [
+            r#"{"event":"reboot"}"#,
+            r#"{"event":"ghostty-exited"}"#,
+            r#"{"event":"ghostty-exited","code":"0"}"#,
+            r#"{"code":0}"#,
+            "boot-complete",
+        ] {
+            assert!(serde_json::from_str::<AgentEvent>(json).is_err(), "{json}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::error::{NetworkError, VirtualGhostError};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tracing::debug;
+
+/// How long a single probe waits for the agent's SSH banner.
+const BANNER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Delay between connection attempts while the guest boots.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Host-side address of the guest agent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum GuestEndpoint {
+    /// AF_VSOCK `(cid, port)` — QEMU vhost-vsock on Linux.
+    Vsock { cid: u32, port: u32 },
+    /// Loopback TCP port forwarded to the guest by QEMU user networking.
+    Tcp { port: u16 },
+    /// Guest vsock port behind a host Unix socket, reached with a
+    /// `CONNECT <port>` handshake — Cloud Hypervisor and Firecracker.
+    #[serde(rename = "hybrid-vsock")]
+    HybridVsock { socket: PathBuf, port: u32 },
+}
+
+impl std::fmt::Display for GuestEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vsock { cid, port } => write!(f, "vsock:{cid}:{port}"),
+            Self::Tcp { port } => write!(f, "tcp:127.0.0.1:{port}"),
+            Self::HybridVsock { socket, port } => {
+                write!(f, "hybrid-vsock:{}:{port}", socket.display())
+            }
+        }
+    }
+}
+
+/// Byte stream to the guest agent, whichever transport carries it.
+pub trait GuestStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> GuestStream for T {}
+
+impl GuestEndpoint {
+    /// Open a connection to the agent's SSH server.
+    pub async fn connect(&self) -> Result<Box<dyn GuestStream>, VirtualGhostError> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::Vsock { cid, port } => Ok(Box::new(
+                super::AfVsockConnection::connect(*cid, *port)
+                    .await?
+                    .into_stream(),
+            )),
+            #[cfg(not(target_os = "linux"))]
+            Self::Vsock { .. } => Err(NetworkError::VsockConnectionFailed(format!(
+                "{self}: AF_VSOCK is only supported on Linux hosts"
+            ))
+            .into()),
+            Self::Tcp { port } => {
+                let stream = tokio::net::TcpStream::connect(("127.0.0.1", *port))
+                    .await
+                    .map_err(|e| NetworkError::TunnelError(format!("{self}: {e}")))?;
+                let _ = stream.set_nodelay(true);
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            Self::HybridVsock { socket, port } => Ok(Box::new(
+                super::VsockConnection::connect(socket, *port)
+                    .await?
+                    .into_stream(),
+            )),
+            #[cfg(not(unix))]
+            Self::HybridVsock { .. } => Err(NetworkError::VsockConnectionFailed(format!(
+                "{self}: hybrid vsock needs a Unix host"
+            ))
+            .into()),
+        }
+    }
+
+    /// Connect, retrying until `timeout` elapses.
+    ///
+    /// While the guest boots, the agent refuses or resets connections (or the
+    /// VMM's socket is not there yet); a zero `timeout` makes a single attempt.
+    pub async fn connect_retry(
+        &self,
+        timeout: Duration,
+    ) -> Result<Box<dyn GuestStream>, VirtualGhostError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match self.connect().await {
+                Ok(stream) => return Ok(stream),
+                Err(e @ VirtualGhostError::Network(NetworkError::VsockUnavailable(_))) => {
+                    return Err(e)
+                }
+                Err(e) if tokio::time::Instant::now() + CONNECT_RETRY_INTERVAL >= deadline => {
+                    return Err(e)
+                }
+                Err(e) => debug!(endpoint = %self, error = %e, "Guest agent not accepting connections yet"),
+            }
+            tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Check whether the agent is accepting connections by reading its SSH banner.
+    ///
+    /// A bare TCP connect is not enough: QEMU's user-mode `hostfwd` accepts
+    /// connections on the host side before anything listens in the guest.
+    pub async fn probe(&self) -> Result<(), VirtualGhostError> {
+        read_banner(self.connect().await?, self).await
+    }
+}
+
+async fn read_banner<S>(mut stream: S, endpoint: &GuestEndpoint) -> Result<(), VirtualGhostError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 4];
+    let result = tokio::time::timeout(BANNER_TIMEOUT, stream.read_exact(&mut buf)).await;
+    match result {
+        Ok(Ok(_)) if &buf == b"SSH-" => Ok(()),
+        Ok(Ok(_)) => Err(NetworkError::TunnelError(format!(
+            "{endpoint}: unexpected banner {:?}",
+            String::from_utf8_lossy(&buf)
+        ))
+        .into()),
+        Ok(Err(e)) => {
+            debug!(%endpoint, error = %e, "Agent closed the connection");
+            Err(NetworkError::TunnelError(format!("{endpoint}: {e}")).into())
+        }
+        Err(_) => Err(NetworkError::TunnelError(format!("{endpoint}: no banner")).into()),
+    }
+}
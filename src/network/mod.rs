@@ -1,9 +1,19 @@
 #![allow(dead_code, unused_imports)]
 
+#[cfg(target_os = "linux")]
+mod af_vsock;
+mod agent_events;
+mod endpoint;
 mod tunnel;
 #[cfg(unix)]
 mod vsock;
 
+#[cfg(target_os = "linux")]
+pub use af_vsock::AfVsockConnection;
+pub use agent_events::{
+    guest_port, AgentEvent, AgentEventListener, AgentStatus, EventTransport, HYBRID_EVENTS_PORT,
+};
+pub use endpoint::{GuestEndpoint, GuestStream};
 pub use tunnel::GuestTunnel;
 #[cfg(unix)]
 pub use vsock::VsockConnection;
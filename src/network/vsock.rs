@@ -4,20 +4,21 @@ use crate::error::{NetworkError, VirtualGhostError};
 use std::path::{Path, PathBuf};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UnixStream;
-use tracing::info;
+use tracing::debug;
 
 pub struct VsockConnection {
     stream: UnixStream,
 }
 
 impl VsockConnection {
-    /// Connect to the guest via Firecracker's vsock Unix socket.
+    /// Connect to the guest via a hybrid vsock Unix socket.
     ///
-    /// Firecracker maps guest vsock ports to a host-side Unix socket.
+    /// Firecracker and Cloud Hypervisor map guest vsock ports to a host-side
+    /// Unix socket.
     /// The host sends `CONNECT <port>\n` and receives `OK <id>\n` on success,
     /// after which the stream becomes a bidirectional byte pipe to the guest.
     pub async fn connect(uds_path: &Path, port: u32) -> Result<Self, VirtualGhostError> {
-        info!(socket = %uds_path.display(), port, "Connecting to guest via vsock");
+        debug!(socket = %uds_path.display(), port, "Connecting to guest via vsock");
 
         let mut stream = UnixStream::connect(uds_path).await.map_err(|e| {
             NetworkError::VsockConnectionFailed(format!(
@@ -32,13 +33,20 @@ impl VsockConnection {
             NetworkError::VsockConnectionFailed(format!("handshake write failed: {e}"))
         })?;
 
-        // Read the response (expect "OK <id>\n")
-        let mut buf = [0u8; 64];
-        let n = stream.read(&mut buf).await.map_err(|e| {
-            NetworkError::VsockConnectionFailed(format!("handshake read failed: {e}"))
-        })?;
+        // Read the response (expect "OK <id>\n") a byte at a time: whatever
+        // follows the newline is the guest's, e.g. an SSH banner
+        let mut buf = Vec::new();
+        loop {
+            let byte = stream.read_u8().await.map_err(|e| {
+                NetworkError::VsockConnectionFailed(format!("handshake read failed: {e}"))
+            })?;
+            if byte == b'\n' || buf.len() >= 64 {
+                break;
+            }
+            buf.push(byte);
+        }
 
-        let response = String::from_utf8_lossy(&buf[..n]);
+        let response = String::from_utf8_lossy(&buf);
         if !response.starts_with("OK") {
             return Err(NetworkError::VsockConnectionFailed(format!(
                 "vsock handshake rejected: {response}"
@@ -46,7 +54,7 @@ impl VsockConnection {
             .into());
         }
 
-        info!(port, "Vsock connection established");
+        debug!(port, "Vsock connection established");
         Ok(Self { stream })
     }
 
@@ -2,10 +2,18 @@ use crate::error::{SshError, VirtualGhostError};
 use russh::*;
 use ssh_key::private::PrivateKey;
 use ssh_key::public::PublicKey;
-use std::sync::Arc;
-use tracing::info;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
 
-pub(crate) struct ClientHandler;
+/// Account the guest agent runs sessions as.
+pub const GUEST_USER: &str = "ghostty";
+
+pub(crate) struct ClientHandler {
+    /// Host key the launcher generated for the guest and passed in at boot.
+    expected: PublicKey,
+    /// Fingerprint of the key actually presented, recorded on mismatch.
+    mismatch: Arc<Mutex<Option<String>>>,
+}
 
 #[async_trait::async_trait]
 impl client::Handler for ClientHandler {
@@ -13,10 +21,19 @@ impl client::Handler for ClientHandler {
 
     async fn check_server_key(
         &mut self,
-        _server_public_key: &PublicKey,
+        server_public_key: &PublicKey,
     ) -> Result<bool, Self::Error> {
-        // Accept all host keys — the guest agent uses ephemeral keys too
-        Ok(true)
+        if server_public_key.key_data() == self.expected.key_data() {
+            return Ok(true);
+        }
+        let actual = server_public_key.fingerprint(Default::default()).to_string();
+        warn!(
+            expected = %self.expected.fingerprint(Default::default()),
+            actual,
+            "Guest host key mismatch"
+        );
+        *self.mismatch.lock().unwrap() = Some(actual);
+        Ok(false)
     }
 }
 
@@ -27,20 +44,38 @@ pub struct SshClient {
 impl SshClient {
     /// Connect to the guest SSH server over an already-established stream.
     /// The stream is typically a vsock Unix socket connection.
+    ///
+    /// The server must present `host_key`; anything else fails with
+    /// [`SshError::HostKeyMismatch`].
     pub async fn connect<S>(
         stream: S,
         user: &str,
         key: &PrivateKey,
+        host_key: &PublicKey,
     ) -> Result<Self, VirtualGhostError>
     where
         S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
     {
         let config = Arc::new(client::Config::default());
-        let handler = ClientHandler;
+        let mismatch = Arc::new(Mutex::new(None));
+        let handler = ClientHandler {
+            expected: host_key.clone(),
+            mismatch: mismatch.clone(),
+        };
 
-        let mut session = client::connect_stream(config, stream, handler)
-            .await
-            .map_err(|e| SshError::ConnectionFailed(e.to_string()))?;
+        let mut session = match client::connect_stream(config, stream, handler).await {
+            Ok(session) => session,
+            Err(e) => {
+                if let Some(actual) = mismatch.lock().unwrap().take() {
+                    return Err(SshError::HostKeyMismatch {
+                        expected: host_key.fingerprint(Default::default()).to_string(),
+                        actual,
+                    }
+                    .into());
+                }
+                return Err(SshError::ConnectionFailed(e.to_string()).into());
+            }
+        };
 
         let auth_result = session
             .authenticate_publickey(user, Arc::new(key.clone()))
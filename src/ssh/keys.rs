@@ -1,5 +1,8 @@
 use crate::error::{SshError, VirtualGhostError};
 use ssh_key::private::PrivateKey;
+use ssh_key::public::PublicKey;
+use ssh_key::LineEnding;
+use std::path::Path;
 use tracing::info;
 
 pub struct KeyManager;
@@ -12,4 +15,34 @@ impl KeyManager {
             .map_err(|e| SshError::KeyGeneration(e.to_string()))?;
         Ok(key)
     }
+
+    /// Load an OpenSSH private key from disk.
+    pub fn load(path: &Path) -> Result<PrivateKey, VirtualGhostError> {
+        PrivateKey::read_openssh_file(path).map_err(|e| {
+            SshError::KeyGeneration(format!("failed to read key {}: {e}", path.display())).into()
+        })
+    }
+
+    /// Load an OpenSSH public key (e.g. a pinned guest host key) from disk.
+    pub fn load_public(path: &Path) -> Result<PublicKey, VirtualGhostError> {
+        PublicKey::read_openssh_file(path).map_err(|e| {
+            SshError::KeyGeneration(format!("failed to read key {}: {e}", path.display())).into()
+        })
+    }
+
+    /// Write a private key in OpenSSH format (mode 0600 on Unix).
+    pub fn save(key: &PrivateKey, path: &Path) -> Result<(), VirtualGhostError> {
+        key.write_openssh_file(path, LineEnding::LF).map_err(|e| {
+            SshError::KeyGeneration(format!("failed to write key {}: {e}", path.display())).into()
+        })
+    }
+
+    /// Render the public half of `key` as an `authorized_keys` line.
+    pub fn authorized_key(key: &PrivateKey) -> Result<String, VirtualGhostError> {
+        let line = key
+            .public_key()
+            .to_openssh()
+            .map_err(|e| SshError::KeyGeneration(e.to_string()))?;
+        Ok(format!("{line}\n"))
+    }
 }
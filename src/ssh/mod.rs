@@ -3,7 +3,9 @@
 mod client;
 mod keys;
 mod session;
+mod transfer;
 
-pub use client::SshClient;
+pub use client::{SshClient, GUEST_USER};
 pub use keys::KeyManager;
-pub use session::SshSession;
+pub use session::{shell_quote, SshSession};
+pub use transfer::{human_bytes, FileTransfer, Location, TransferStats};
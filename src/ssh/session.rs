@@ -1,6 +1,8 @@
 use crate::error::{SshError, VirtualGhostError};
 use russh::{client, Channel, ChannelMsg};
-use tracing::info;
+use std::borrow::Cow;
+use tokio::io::AsyncWrite;
+use tracing::{debug, info};
 
 pub struct SshSession {
     channel: Channel<client::Msg>,
@@ -33,6 +35,45 @@ impl SshSession {
         Ok(Self { channel })
     }
 
+    /// Run `command` without a PTY; stdout arrives as [`ChannelMsg::Data`] and
+    /// stderr as [`ChannelMsg::ExtendedData`] with `ext == 1`.
+    ///
+    /// `env` is set before the command starts and `cwd`, if given, becomes its
+    /// working directory. The agent acknowledges the request with
+    /// [`ChannelMsg::Success`] or refuses it with [`ChannelMsg::Failure`].
+    pub async fn exec<H: client::Handler>(
+        handle: &client::Handle<H>,
+        command: &str,
+        env: &[(String, String)],
+        cwd: Option<&str>,
+    ) -> Result<Self, VirtualGhostError> {
+        let channel = handle
+            .channel_open_session()
+            .await
+            .map_err(|e| SshError::ChannelError(e.to_string()))?;
+
+        for (name, value) in env {
+            channel
+                .set_env(false, name.as_str(), value.as_str())
+                .await
+                .map_err(|e| SshError::ChannelError(format!("env request failed: {e}")))?;
+        }
+
+        // SSH has no notion of a working directory; let the guest shell change into it
+        let command = match cwd {
+            Some(dir) => format!("cd {} && {command}", shell_quote(dir)),
+            None => command.to_string(),
+        };
+        channel
+            .exec(true, command.as_str())
+            .await
+            .map_err(|e| SshError::ChannelError(format!("exec request failed: {e}")))?;
+
+        debug!(command, "SSH exec session opened");
+
+        Ok(Self { channel })
+    }
+
     /// Send data (keystrokes) to the remote shell.
     pub async fn write(&self, data: &[u8]) -> Result<(), VirtualGhostError> {
         self.channel
@@ -51,8 +92,91 @@ impl SshSession {
         Ok(())
     }
 
+    /// Signal end of input (the remote process sees EOF on stdin).
+    pub async fn eof(&self) -> Result<(), VirtualGhostError> {
+        self.channel
+            .eof()
+            .await
+            .map_err(|e| SshError::ChannelError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Independent writer for the channel's input, usable while [`Self::read`]
+    /// is pending. Shutting it down sends EOF.
+    pub fn writer(&self) -> impl AsyncWrite + Send + Unpin + 'static {
+        self.channel.make_writer()
+    }
+
     /// Wait for the next message from the channel.
     pub async fn read(&mut self) -> Option<ChannelMsg> {
         self.channel.wait().await
     }
 }
+
+/// Quote `arg` for a POSIX shell, leaving plain words untouched.
+pub fn shell_quote(arg: &str) -> Cow<'_, str> {
+    let plain = !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_./=:,+@%".contains(&b));
+    if plain {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_words_are_left_alone() {
+        for arg in ["ls", "-la", "/usr/bin/env", "KEY=value", "a,b:c+d@e%f"] {
+            assert!(matches!(shell_quote(arg), Cow::Borrowed(_)), "{arg}");
+        }
+    }
+
+    #[test]
+    fn everything_else_is_single_quoted() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("naïve"), "'naïve'");
+    }
+
+    /// What `sh` makes of the quoted arguments is what went in.
+    #[cfg(unix)]
+    #[test]
+    fn the_shell_reads_back_the_original() {
+        let args = [
+            "",
+            "a b",
+            "it's",
+            "$(reboot)",
+            "`id`",
+            "tab\there",
+            "new\nline",
+            "*",
+            "\\",
+        ];
+        let script = args
+            .iter()
+            .map(|arg| format!("printf '%s\\0' {}", shell_quote(arg)))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let output = std::process::Command::new("sh")
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let printed: Vec<_> = output.stdout.split(|&b| b == 0).collect();
+        let expected: Vec<_> = args
+            .iter()
+            .map(|arg| arg.as_bytes())
+            .chain([&b""[..]])
+            .collect();
+        assert_eq!(printed, expected);
+    }
+}
@@ -0,0 +1,498 @@
+use crate::error::{SshError, VirtualGhostError};
+use russh::client;
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::FileAttributes;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+/// Bytes per SFTP read/write request (OpenSSH's sftp-server accepts up to 256 KiB).
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Minimum delay between redraws of the live progress line.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// One side of a copy: a path on the host or inside the guest.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Host(PathBuf),
+    /// Guest path; relative paths start from the session user's home.
+    Guest(String),
+}
+
+impl Location {
+    /// Parse `vm:path` as a guest path and `host:path` or a bare path as a host path.
+    pub fn parse(s: &str) -> Self {
+        match s.strip_prefix("vm:") {
+            Some(path) => Self::Guest(path.to_string()),
+            None => Self::Host(PathBuf::from(s.strip_prefix("host:").unwrap_or(s))),
+        }
+    }
+}
+
+/// Totals for a finished copy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TransferStats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Copies files between the host and the guest over the agent's SFTP subsystem,
+/// preserving permission bits.
+pub struct FileTransfer {
+    sftp: SftpSession,
+    recursive: bool,
+    progress: Progress,
+    stats: TransferStats,
+}
+
+impl FileTransfer {
+    /// Start an SFTP session on the SSH connection.
+    pub async fn open<H: client::Handler>(
+        handle: &client::Handle<H>,
+        recursive: bool,
+        quiet: bool,
+    ) -> Result<Self, VirtualGhostError> {
+        let channel = handle
+            .channel_open_session()
+            .await
+            .map_err(|e| SshError::ChannelError(e.to_string()))?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .map_err(|e| SshError::ChannelError(format!("SFTP subsystem request failed: {e}")))?;
+        let sftp = SftpSession::new(channel.into_stream())
+            .await
+            .map_err(|e| SshError::Transfer(format!("SFTP handshake failed: {e}")))?;
+        info!("SFTP session opened");
+
+        Ok(Self {
+            sftp,
+            recursive,
+            progress: Progress::new(!quiet),
+            stats: TransferStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    /// Copy `src` on the host to `dst` in the guest. Like `cp`, an existing
+    /// destination directory receives `src` under its own name.
+    pub async fn upload(&mut self, src: &Path, dst: &str) -> Result<(), VirtualGhostError> {
+        let meta = std::fs::metadata(src).map_err(|e| local_error(src, e))?;
+        let dst = if self.guest_is_dir(dst).await {
+            guest_join(dst, &local_name(src)?)
+        } else {
+            dst.to_string()
+        };
+
+        if !meta.is_dir() {
+            return self.upload_file(src, &dst, local_mode(&meta)).await;
+        }
+        if !self.recursive {
+            return Err(SshError::Transfer(format!(
+                "{} is a directory (use --recursive)",
+                src.display()
+            ))
+            .into());
+        }
+
+        // Walk iteratively; directory modes are applied last so read-only
+        // directories don't block copying their contents
+        let mut pending = vec![(src.to_path_buf(), dst)];
+        let mut dir_modes = Vec::new();
+        while let Some((local, remote)) = pending.pop() {
+            if !self.guest_is_dir(&remote).await {
+                self.sftp
+                    .create_dir(remote.as_str())
+                    .await
+                    .map_err(|e| guest_error(&remote, e))?;
+            }
+            let meta = std::fs::metadata(&local).map_err(|e| local_error(&local, e))?;
+            dir_modes.push((remote.clone(), local_mode(&meta)));
+
+            for entry in std::fs::read_dir(&local).map_err(|e| local_error(&local, e))? {
+                let entry = entry.map_err(|e| local_error(&local, e))?;
+                let path = entry.path();
+                let file_type = entry.file_type().map_err(|e| local_error(&path, e))?;
+                let target = guest_join(&remote, &entry.file_name().to_string_lossy());
+                if file_type.is_dir() {
+                    pending.push((path, target));
+                } else if file_type.is_file() {
+                    let meta = entry.metadata().map_err(|e| local_error(&path, e))?;
+                    self.upload_file(&path, &target, local_mode(&meta)).await?;
+                } else {
+                    warn!(path = %path.display(), "Skipping symlink or special file");
+                }
+            }
+        }
+
+        for (remote, mode) in dir_modes.into_iter().rev() {
+            self.set_guest_mode(&remote, mode).await?;
+        }
+        Ok(())
+    }
+
+    /// Copy `src` in the guest to `dst` on the host. Like `cp`, an existing
+    /// destination directory receives `src` under its own name.
+    pub async fn download(&mut self, src: &str, dst: &Path) -> Result<(), VirtualGhostError> {
+        let src = guest_path(src);
+        let meta = self
+            .sftp
+            .metadata(src)
+            .await
+            .map_err(|e| guest_error(src, e))?;
+        let dst = if dst.is_dir() {
+            dst.join(self.guest_name(src).await?)
+        } else {
+            dst.to_path_buf()
+        };
+
+        if !meta.is_dir() {
+            return self.download_file(src, &dst, guest_mode(&meta)).await;
+        }
+        if !self.recursive {
+            return Err(
+                SshError::Transfer(format!("{src} is a directory (use --recursive)")).into(),
+            );
+        }
+
+        let mut pending = vec![(src.to_string(), dst, guest_mode(&meta))];
+        let mut dir_modes = Vec::new();
+        while let Some((remote, local, mode)) = pending.pop() {
+            if !local.is_dir() {
+                std::fs::create_dir(&local).map_err(|e| local_error(&local, e))?;
+            }
+            dir_modes.push((local.clone(), mode));
+
+            let entries = self
+                .sftp
+                .read_dir(remote.as_str())
+                .await
+                .map_err(|e| guest_error(&remote, e))?;
+            for entry in entries {
+                let name = entry.file_name();
+                let meta = entry.metadata();
+                let source = guest_join(&remote, &name);
+                let target = local.join(&name);
+                if meta.is_dir() {
+                    pending.push((source, target, guest_mode(&meta)));
+                } else if meta.is_regular() {
+                    self.download_file(&source, &target, guest_mode(&meta))
+                        .await?;
+                } else {
+                    warn!(path = source, "Skipping symlink or special file");
+                }
+            }
+        }
+
+        for (local, mode) in dir_modes.into_iter().rev() {
+            set_local_mode(&local, mode)?;
+        }
+        Ok(())
+    }
+
+    async fn upload_file(
+        &mut self,
+        src: &Path,
+        dst: &str,
+        mode: u32,
+    ) -> Result<(), VirtualGhostError> {
+        let mut reader = tokio::fs::File::open(src)
+            .await
+            .map_err(|e| local_error(src, e))?;
+        let total = reader
+            .metadata()
+            .await
+            .map_err(|e| local_error(src, e))?
+            .len();
+        let mut writer = self
+            .sftp
+            .create(dst)
+            .await
+            .map_err(|e| guest_error(dst, e))?;
+
+        let label = src.display().to_string();
+        let bytes = self
+            .copy(&mut reader, &mut writer, &label, total)
+            .await
+            .map_err(|e| SshError::Transfer(format!("{label} -> vm:{dst}: {e}")))?;
+        writer
+            .shutdown()
+            .await
+            .map_err(|e| SshError::Transfer(format!("vm:{dst}: {e}")))?;
+        self.set_guest_mode(dst, mode).await?;
+
+        debug!(src = label, dst, bytes, "Uploaded file");
+        Ok(())
+    }
+
+    async fn download_file(
+        &mut self,
+        src: &str,
+        dst: &Path,
+        mode: u32,
+    ) -> Result<(), VirtualGhostError> {
+        let mut reader = self.sftp.open(src).await.map_err(|e| guest_error(src, e))?;
+        let total = reader
+            .metadata()
+            .await
+            .map_err(|e| guest_error(src, e))?
+            .len();
+        let mut writer = tokio::fs::File::create(dst)
+            .await
+            .map_err(|e| local_error(dst, e))?;
+
+        let label = format!("vm:{src}");
+        let bytes = self
+            .copy(&mut reader, &mut writer, &label, total)
+            .await
+            .map_err(|e| SshError::Transfer(format!("{label} -> {}: {e}", dst.display())))?;
+        writer.flush().await.map_err(|e| local_error(dst, e))?;
+        set_local_mode(dst, mode)?;
+
+        debug!(src, dst = %dst.display(), bytes, "Downloaded file");
+        Ok(())
+    }
+
+    async fn copy<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        label: &str,
+        total: u64,
+    ) -> std::io::Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut done = 0u64;
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buf[..n]).await?;
+            done += n as u64;
+            self.progress.update(label, done, total);
+        }
+        self.progress.finish(label, done);
+        self.stats.files += 1;
+        self.stats.bytes += done;
+        Ok(done)
+    }
+
+    async fn guest_is_dir(&self, path: &str) -> bool {
+        self.sftp
+            .metadata(guest_path(path))
+            .await
+            .is_ok_and(|meta| meta.is_dir())
+    }
+
+    /// Last component of a guest path, resolving `.`/`..` through the server.
+    async fn guest_name(&self, path: &str) -> Result<String, VirtualGhostError> {
+        let resolved = self
+            .sftp
+            .canonicalize(guest_path(path))
+            .await
+            .map_err(|e| guest_error(path, e))?;
+        match resolved.rsplit('/').next() {
+            Some(name) if !name.is_empty() => Ok(name.to_string()),
+            _ => Err(SshError::Transfer(format!("vm:{path} has no file name")).into()),
+        }
+    }
+
+    async fn set_guest_mode(&self, path: &str, mode: u32) -> Result<(), VirtualGhostError> {
+        let attrs = FileAttributes {
+            permissions: Some(mode),
+            ..FileAttributes::empty()
+        };
+        self.sftp
+            .set_metadata(path, attrs)
+            .await
+            .map_err(|e| guest_error(path, e))
+    }
+}
+
+/// Live progress on stderr when it is a terminal, one line per file otherwise.
+struct Progress {
+    enabled: bool,
+    live: bool,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            live: enabled && std::io::stderr().is_terminal(),
+            last_draw: None,
+        }
+    }
+
+    fn update(&mut self, label: &str, done: u64, total: u64) {
+        if !self.live
+            || self
+                .last_draw
+                .is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        let percent = (done * 100).checked_div(total).unwrap_or(100).min(100);
+        eprint!(
+            "\r\x1b[K{label}  {} / {}  {percent}%",
+            human_bytes(done),
+            human_bytes(total)
+        );
+    }
+
+    fn finish(&mut self, label: &str, bytes: u64) {
+        if !self.enabled {
+            return;
+        }
+        self.last_draw = None;
+        if self.live {
+            eprint!("\r\x1b[K");
+        }
+        eprintln!("{label}  {}", human_bytes(bytes));
+    }
+}
+
+/// Format a byte count with binary units, e.g. `12.3 MiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// An empty guest path means the session user's home directory.
+fn guest_path(path: &str) -> &str {
+    if path.is_empty() {
+        "."
+    } else {
+        path
+    }
+}
+
+fn guest_join(dir: &str, name: &str) -> String {
+    match dir.trim_end_matches('/') {
+        "" if dir.starts_with('/') => format!("/{name}"),
+        "" => name.to_string(),
+        dir => format!("{dir}/{name}"),
+    }
+}
+
+fn local_name(path: &Path) -> Result<String, VirtualGhostError> {
+    let resolved = std::fs::canonicalize(path).map_err(|e| local_error(path, e))?;
+    resolved
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| SshError::Transfer(format!("{} has no file name", path.display())).into())
+}
+
+fn guest_mode(meta: &FileAttributes) -> u32 {
+    meta.permissions.unwrap_or(0o644) & 0o7777
+}
+
+#[cfg(unix)]
+fn local_mode(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn local_mode(meta: &std::fs::Metadata) -> u32 {
+    match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn set_local_mode(path: &Path, mode: u32) -> Result<(), VirtualGhostError> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .map_err(|e| local_error(path, e))
+}
+
+#[cfg(not(unix))]
+fn set_local_mode(path: &Path, mode: u32) -> Result<(), VirtualGhostError> {
+    let mut permissions = std::fs::metadata(path)
+        .map_err(|e| local_error(path, e))?
+        .permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    std::fs::set_permissions(path, permissions).map_err(|e| local_error(path, e))
+}
+
+fn local_error(path: &Path, e: std::io::Error) -> VirtualGhostError {
+    SshError::Transfer(format!("{}: {e}", path.display())).into()
+}
+
+fn guest_error(path: &str, e: russh_sftp::client::error::Error) -> VirtualGhostError {
+    SshError::Transfer(format!("vm:{path}: {e}")).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations_default_to_the_host() {
+        assert_eq!(
+            Location::parse("vm:/etc/hosts"),
+            Location::Guest("/etc/hosts".into())
+        );
+        assert_eq!(
+            Location::parse("vm:notes.txt"),
+            Location::Guest("notes.txt".into())
+        );
+        assert_eq!(Location::parse("vm:"), Location::Guest(String::new()));
+        assert_eq!(
+            Location::parse("host:vm:odd"),
+            Location::Host("vm:odd".into())
+        );
+        assert_eq!(
+            Location::parse("./vm:odd"),
+            Location::Host("./vm:odd".into())
+        );
+        assert_eq!(
+            Location::parse("/tmp/out"),
+            Location::Host("/tmp/out".into())
+        );
+        assert_eq!(Location::parse("host:"), Location::Host("".into()));
+    }
+
+    #[test]
+    fn guest_paths_resolve_from_home() {
+        assert_eq!(guest_path(""), ".");
+        assert_eq!(guest_path("src"), "src");
+        assert_eq!(guest_join("/", "a"), "/a");
+        assert_eq!(guest_join("", "a"), "a");
+        assert_eq!(guest_join("dir/", "a"), "dir/a");
+        assert_eq!(guest_join("/srv", "a"), "/srv/a");
+    }
+
+    #[test]
+    fn byte_counts_use_binary_units() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(5 << 30), "5.0 GiB");
+    }
+}
@@ -0,0 +1,44 @@
+#![cfg(unix)]
+
+use nix::sys::termios::{self, SetArg, Termios};
+use std::io;
+use std::os::fd::AsRawFd;
+
+/// Puts the host terminal (stdin) in raw mode; the previous settings come back on drop.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        let stdin = io::stdin();
+        let original = termios::tcgetattr(&stdin)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &raw)?;
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Size of the host terminal as `(columns, rows)`, if stdout is a terminal.
+pub fn size() -> Option<(u32, u32)> {
+    let mut ws: nix::libc::winsize = unsafe { std::mem::zeroed() };
+    // SAFETY: TIOCGWINSZ writes a `winsize` struct through a valid pointer.
+    let ret = unsafe {
+        nix::libc::ioctl(
+            io::stdout().as_raw_fd(),
+            nix::libc::TIOCGWINSZ,
+            &mut ws,
+        )
+    };
+    if ret < 0 || ws.ws_col == 0 || ws.ws_row == 0 {
+        return None;
+    }
+    Some((ws.ws_col as u32, ws.ws_row as u32))
+}
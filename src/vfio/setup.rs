@@ -2,9 +2,8 @@
 
 use crate::error::{VmError, VirtualGhostError};
 use std::fs;
-use std::path::{Path, PathBuf};
-use tracing::{info, warn};
-use walkdir::WalkDir;
+use std::path::Path;
+use tracing::info;
 
 use super::GpuDevice;
 
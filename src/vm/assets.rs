@@ -1,6 +1,6 @@
 use crate::config::VirtualGhostConfig;
 use crate::error::{VmError, VirtualGhostError};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::info;
 
 #[cfg(has_embedded_kernel)]
@@ -73,6 +73,7 @@ impl AssetManager {
 
     /// Stream-decompress an embedded zstd blob directly to a file.
     /// Avoids loading the full decompressed data into memory.
+    #[cfg_attr(not(any(has_embedded_kernel, has_embedded_rootfs)), allow(dead_code))]
     fn stream_decompress_to_file(
         compressed: &[u8],
         dest: &std::path::Path,
@@ -192,11 +193,80 @@ impl AssetManager {
         .into())
     }
 
-    pub fn clean_cache(&self) -> Result<(), VirtualGhostError> {
-        if self.cache_dir.exists() {
-            std::fs::remove_dir_all(&self.cache_dir).map_err(VirtualGhostError::Io)?;
-            info!(path = %self.cache_dir.display(), "Cleaned asset cache");
+    /// Remove the cache except for the files in `keep`, which are returned if
+    /// they are in it.
+    pub fn clean_cache(&self, keep: &[PathBuf]) -> Result<Vec<PathBuf>, VirtualGhostError> {
+        if !self.cache_dir.exists() {
+            return Ok(Vec::new());
         }
-        Ok(())
+        // `keep` holds canonical paths, such as overlays' backing files
+        let cache_dir = std::fs::canonicalize(&self.cache_dir)?;
+        let keep: Vec<PathBuf> = keep
+            .iter()
+            .filter(|path| path.starts_with(&cache_dir) && path.exists())
+            .cloned()
+            .collect();
+        remove_all_except(&cache_dir, &keep)?;
+        info!(path = %self.cache_dir.display(), "Cleaned asset cache");
+        Ok(keep)
+    }
+}
+
+/// Remove `path`, and everything under it if it is a directory, except `keep`.
+fn remove_all_except(path: &Path, keep: &[PathBuf]) -> std::io::Result<()> {
+    if keep.iter().any(|kept| kept == path) {
+        return Ok(());
+    }
+    if !keep.iter().any(|kept| kept.starts_with(path)) {
+        return if std::fs::symlink_metadata(path)?.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        };
+    }
+    for entry in std::fs::read_dir(path)? {
+        remove_all_except(&entry?.path(), keep)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleaning_keeps_only_what_is_asked_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().canonicalize().unwrap().join("cache");
+        for file in [
+            "vmlinux",
+            "rootfs.ext4",
+            "qemu/share/bios.bin",
+            "custom/base.img",
+        ] {
+            let path = cache_dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+        let assets = AssetManager {
+            cache_dir: cache_dir.clone(),
+        };
+
+        let keep = [
+            cache_dir.join("rootfs.ext4"),
+            cache_dir.join("custom/base.img"),
+            cache_dir.join("gone.img"),
+            dir.path().join("elsewhere.img"),
+        ];
+        let kept = assets.clean_cache(&keep).unwrap();
+        assert_eq!(kept, keep[..2]);
+        for path in &keep[..2] {
+            assert!(path.exists(), "{}", path.display());
+        }
+        assert!(!cache_dir.join("vmlinux").exists());
+        assert!(!cache_dir.join("qemu").exists());
+
+        assert!(assets.clean_cache(&[]).unwrap().is_empty());
+        assert!(!cache_dir.exists());
     }
 }
@@ -0,0 +1,184 @@
+use crate::error::VirtualGhostError;
+use serde_json::json;
+use std::time::Duration;
+use tracing::{debug, info};
+
+use super::hotplug::dimm_memory;
+use super::qmp::{QmpAddress, QmpClient};
+
+/// QOM path of the balloon device added by [`super::QemuConfig::to_args`].
+pub const BALLOON_DEVICE: &str = "/machine/peripheral/balloon0";
+
+pub const MIB: u64 = 1024 * 1024;
+
+/// How often the guest reports its memory statistics, and the policy looks at them.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The guest is shrunk by at most this much per round, and only once it has this much to spare.
+const SHRINK_STEP: u64 = 256 * MIB;
+
+/// Least memory left available in the guest beyond what it uses.
+const MIN_HEADROOM: u64 = 256 * MIB;
+
+/// Guest memory as seen through the balloon, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct BalloonStats {
+    /// Memory the guest has after the balloon.
+    pub actual: u64,
+    /// Memory the guest could still hand out (`MemAvailable`), once it reports it.
+    pub available: Option<u64>,
+}
+
+/// Ask the guest to report its memory statistics through the balloon.
+pub async fn enable_stats(qmp: &QmpClient) -> Result<(), VirtualGhostError> {
+    qmp.qom_set(
+        BALLOON_DEVICE,
+        "guest-stats-polling-interval",
+        json!(STATS_INTERVAL.as_secs()),
+    )
+    .await
+}
+
+/// Current balloon size and the guest's last memory report.
+pub async fn stats(qmp: &QmpClient) -> Result<BalloonStats, VirtualGhostError> {
+    let actual = qmp.query_balloon().await?;
+    // Fails until the guest sends its first report; statistics it does not report read as -1
+    let available = match qmp.qom_get(BALLOON_DEVICE, "guest-stats").await {
+        Ok(stats) => stats["stats"]["stat-available-memory"]
+            .as_u64()
+            .filter(|bytes| *bytes != u64::MAX),
+        Err(e) => {
+            debug!(error = %e, "No guest memory statistics yet");
+            None
+        }
+    };
+    Ok(BalloonStats { actual, available })
+}
+
+/// Sizes the guest by its available memory: shrinks it while much of its
+/// memory sits idle and grows it back before it runs short.
+#[derive(Debug, Clone, Copy)]
+pub struct BalloonPolicy {
+    /// Smallest size to shrink the guest to, in bytes.
+    pub min: u64,
+    /// Memory the guest booted with, in bytes; hot-added DIMMs come on top.
+    pub max: u64,
+}
+
+impl BalloonPolicy {
+    /// New size for the guest, if it should change.
+    pub fn target(&self, stats: &BalloonStats) -> Option<u64> {
+        let available = stats.available?;
+        let used = stats.actual.saturating_sub(available);
+        let wanted = (used + (used / 4).max(MIN_HEADROOM)).clamp(self.min.min(self.max), self.max);
+        if wanted > stats.actual {
+            // Grow at once: the guest is about to run short
+            Some(wanted)
+        } else if stats.actual - wanted >= SHRINK_STEP {
+            // Shrink in steps, so a burst of activity soon after finds some memory left
+            Some((stats.actual - SHRINK_STEP).max(wanted))
+        } else {
+            None
+        }
+    }
+
+    /// Apply the policy to the VM behind the control monitor at `addr` for as
+    /// long as `running` holds.
+    pub async fn run(&self, addr: &QmpAddress, mut running: impl FnMut() -> bool) {
+        let mut reporting = false;
+        while running() {
+            if let Err(e) = self.adjust(addr, &mut reporting).await {
+                debug!(error = %e, "Balloon policy round failed");
+            }
+            tokio::time::sleep(STATS_INTERVAL).await;
+        }
+    }
+
+    async fn adjust(
+        &self,
+        addr: &QmpAddress,
+        reporting: &mut bool,
+    ) -> Result<(), VirtualGhostError> {
+        // Connect for each round only, leaving the control monitor free for other commands
+        let qmp = QmpClient::connect(addr).await?;
+        if !*reporting {
+            enable_stats(&qmp).await?;
+            *reporting = true;
+        }
+        let stats = stats(&qmp).await?;
+        let installed = Self {
+            max: self.max + dimm_memory(&qmp).await?,
+            ..*self
+        };
+        if let Some(target) = installed.target(&stats) {
+            info!(
+                from_mib = stats.actual / MIB,
+                to_mib = target / MIB,
+                "Resizing guest memory"
+            );
+            qmp.balloon(target).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_follows_available_memory() {
+        let policy = BalloonPolicy {
+            min: 1024 * MIB,
+            max: 4096 * MIB,
+        };
+        // Balloon size and available memory reported by the guest, and the expected target, in MiB
+        let cases: [(&str, u64, Option<u64>, Option<u64>); 10] = [
+            ("no statistics yet", 4096, None, None),
+            ("nothing available at the ceiling", 4096, Some(0), None),
+            ("nothing available", 2048, Some(0), Some(2560)),
+            (
+                "grows by a quarter of the used memory",
+                3072,
+                Some(100),
+                Some(3715),
+            ),
+            (
+                "grows no further than the ceiling",
+                3584,
+                Some(0),
+                Some(4096),
+            ),
+            ("idle guest shrinks by a step", 4096, Some(3996), Some(3840)),
+            ("last step stops at the floor", 1280, Some(1200), Some(1024)),
+            ("within a step of the floor", 1100, Some(1000), None),
+            ("within a step of the wanted size", 2048, Some(600), None),
+            ("a step above the wanted size", 2048, Some(700), Some(1792)),
+        ];
+        for (name, actual, available, target) in cases {
+            let stats = BalloonStats {
+                actual: actual * MIB,
+                available: available.map(|mib| mib * MIB),
+            };
+            assert_eq!(policy.target(&stats), target.map(|mib| mib * MIB), "{name}");
+        }
+    }
+
+    #[test]
+    fn floor_above_the_ceiling_keeps_the_ceiling() {
+        let policy = BalloonPolicy {
+            min: 8192 * MIB,
+            max: 4096 * MIB,
+        };
+        let idle = BalloonStats {
+            actual: 4096 * MIB,
+            available: Some(4000 * MIB),
+        };
+        assert_eq!(policy.target(&idle), None);
+        let shrunk = BalloonStats {
+            actual: 2048 * MIB,
+            available: Some(2000 * MIB),
+        };
+        assert_eq!(policy.target(&shrunk), Some(4096 * MIB));
+    }
+}
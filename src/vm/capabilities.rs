@@ -0,0 +1,516 @@
+use crate::config::VirtualGhostConfig;
+use crate::error::{VirtualGhostError, VmError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, warn};
+
+use super::config::{Accelerator, DisplayMode, QemuConfig};
+use super::process::QemuProcess;
+
+/// Display backends a VM window can use, most preferred first.
+const WINDOWED_DISPLAYS: [DisplayMode; 3] =
+    [DisplayMode::Gtk, DisplayMode::Sdl, DisplayMode::Cocoa];
+
+/// What a QEMU binary supports, as far as the arguments we generate go.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QemuCapabilities {
+    /// Version reported by `-version`, e.g. `8.2.2`.
+    pub version: String,
+    /// Device models listed by `-device help`.
+    pub devices: BTreeSet<String>,
+    /// Display backends listed by `-display help`.
+    pub displays: BTreeSet<String>,
+    /// Accelerators listed by `-accel help`; whether the host can use them is another matter.
+    pub accels: BTreeSet<String>,
+}
+
+impl QemuCapabilities {
+    /// Ask `config.qemu_bin` what it supports, or reuse what an earlier probe
+    /// of the same binary found. A fresh probe is cached when `save` is set.
+    pub fn probe(config: &QemuConfig, save: bool) -> Result<Self, VirtualGhostError> {
+        Self::probe_cached(config, &VirtualGhostConfig::cache_dir(), save)
+    }
+
+    fn probe_cached(
+        config: &QemuConfig,
+        cache_dir: &Path,
+        save: bool,
+    ) -> Result<Self, VirtualGhostError> {
+        let bin = &config.qemu_bin;
+        let cache = cache_file(cache_dir, bin)?;
+        if let Some(capabilities) = std::fs::read_to_string(&cache)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+        {
+            return Ok(capabilities);
+        }
+
+        let run = |args: &[&str]| -> Result<String, VirtualGhostError> {
+            let output = Command::new(bin)
+                .args(args)
+                .envs(QemuProcess::environment(config))
+                .stdin(std::process::Stdio::null())
+                .output()
+                .map_err(|e| VmError::Probe(format!("cannot run {}: {e}", bin.display())))?;
+            if !output.status.success() {
+                return Err(VmError::Probe(format!(
+                    "`{} {}` failed ({}): {}",
+                    bin.display(),
+                    args.join(" "),
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+                .into());
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+
+        let version_text = run(&["-version"])?;
+        let version = version_text
+            .split_once("version ")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .ok_or_else(|| {
+                VmError::Probe(format!(
+                    "{} does not look like QEMU: {}",
+                    bin.display(),
+                    version_text.trim()
+                ))
+            })?
+            .to_string();
+        let capabilities = Self {
+            version,
+            devices: parse_devices(&run(&["-device", "help"])?),
+            displays: parse_list(&run(&["-display", "help"])?),
+            accels: parse_list(&run(&["-accel", "help"])?),
+        };
+        debug!(bin = %bin.display(), ?capabilities, "Probed QEMU");
+        if !save {
+            return Ok(capabilities);
+        }
+
+        // Only a cache: a failed write costs another probe next time
+        let json = serde_json::to_string_pretty(&capabilities)
+            .map_err(|e| VmError::Probe(e.to_string()))?;
+        if let Err(e) = cache
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(&cache, json))
+        {
+            debug!(path = %cache.display(), error = %e, "Failed to cache QEMU capabilities");
+        }
+        Ok(capabilities)
+    }
+
+    pub fn has_device(&self, model: &str) -> bool {
+        self.devices.contains(model)
+    }
+
+    /// Fit `config` to this QEMU: fall back where it lacks something we can
+    /// do without, and fail when it lacks something the VM needs.
+    pub fn apply(&self, config: &mut QemuConfig) -> Result<(), VirtualGhostError> {
+        let bin = config.qemu_bin.clone();
+        let missing = |what: &str, hint: &str| -> VirtualGhostError {
+            VmError::Unsupported(format!(
+                "QEMU {} at {} has no {what}; {hint}",
+                self.version,
+                bin.display()
+            ))
+            .into()
+        };
+
+        if !self.accels.contains(config.accel.as_arg()) {
+            if !self.accels.contains(Accelerator::Tcg.as_arg()) {
+                return Err(missing(
+                    &format!("{} or tcg accelerator", config.accel.as_arg()),
+                    "use a QEMU built for this host",
+                ));
+            }
+            warn!(
+                accel = config.accel.as_arg(),
+                "QEMU lacks the accelerator, falling back to software emulation (tcg)"
+            );
+            config.accel = Accelerator::Tcg;
+            config.gl = false;
+        }
+
+        if !self.has_device("virtio-blk-pci") {
+            return Err(missing("virtio-blk-pci device", "the root disk needs it"));
+        }
+
+        if config.gpu_passthrough.is_empty() {
+            if config.gl && !self.has_device("virtio-vga-gl") {
+                warn!(
+                    "QEMU lacks virtio-vga-gl, falling back to virtio-vga without 3D acceleration"
+                );
+                config.gl = false;
+            }
+            if !self.has_device("virtio-vga") {
+                return Err(missing(
+                    "virtio-vga device",
+                    "install the QEMU package providing virtio-gpu",
+                ));
+            }
+            if config.display != DisplayMode::None
+                && !self.displays.contains(config.display.as_arg())
+            {
+                let Some(fallback) = WINDOWED_DISPLAYS
+                    .into_iter()
+                    .find(|display| self.displays.contains(display.as_arg()))
+                else {
+                    return Err(missing(
+                        "gtk, sdl or cocoa display",
+                        "install its UI module, or use `virtualghost shell` for a VM without a window",
+                    ));
+                };
+                warn!(
+                    wanted = config.display.as_arg(),
+                    using = fallback.as_arg(),
+                    "QEMU lacks the display backend, using another"
+                );
+                config.display = fallback;
+            }
+        } else if !self.has_device("vfio-pci") {
+            return Err(missing("vfio-pci device", "GPU passthrough needs it"));
+        }
+
+        if config.vsock_cid.is_some() && !self.has_device("vhost-vsock-pci") {
+            return Err(missing(
+                "vhost-vsock-pci device",
+                "the host reaches the guest agent over vsock",
+            ));
+        }
+        if config.ssh_port_forward.is_some() && !self.has_device("virtio-net-pci") {
+            return Err(missing(
+                "virtio-net-pci device",
+                "the host reaches the guest agent through it",
+            ));
+        }
+        if config.memory_hotplug() && !self.has_device("pc-dimm") {
+            return Err(missing(
+                "pc-dimm device",
+                "start the VM without --max-memory",
+            ));
+        }
+
+        if config.balloon && !self.has_device("virtio-balloon-pci") {
+            warn!("QEMU lacks virtio-balloon-pci; guest memory cannot be resized");
+            config.balloon = false;
+        }
+        if config.pvpanic && !self.has_device("pvpanic-pci") {
+            warn!("QEMU lacks pvpanic-pci; guest kernel panics will not be reported");
+            config.pvpanic = false;
+        }
+        Ok(())
+    }
+}
+
+/// Where the capabilities of `bin` are cached, named after a hash of its contents.
+fn cache_file(cache_dir: &Path, bin: &Path) -> Result<PathBuf, VirtualGhostError> {
+    let mut file = std::fs::File::open(bin)
+        .map_err(|e| VmError::Probe(format!("cannot read {}: {e}", bin.display())))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok(cache_dir
+        .join("qemu-capabilities")
+        .join(format!("{hash}.json")))
+}
+
+/// Model names in `-device help` output, e.g. `name "virtio-vga", bus PCI, ...`.
+fn parse_devices(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("name \""))
+        .filter_map(|rest| rest.split_once('"'))
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Names listed one per line under the heading of `-display help` or
+/// `-accel help`, up to the first blank line.
+fn parse_list(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .take_while(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_HELP: &str = "\
+Controller/Bridge/Hub devices:
+name \"pci-bridge\", bus PCI, desc \"Standard PCI Bridge\"
+name \"vhost-vsock-pci\", bus PCI
+
+Storage devices:
+name \"virtio-blk-pci\", bus PCI, alias \"virtio-blk\"
+
+Display devices:
+name \"virtio-vga\", bus PCI
+name \"virtio-vga-gl\", bus PCI
+
+Misc devices:
+name \"pc-dimm\", desc \"DIMM memory module\"
+name \"virtio-balloon-pci\", bus PCI, alias \"virtio-balloon\"
+";
+
+    const DISPLAY_HELP: &str = "\
+Available display backend types:
+none
+gtk
+sdl
+egl-headless
+
+Some display backends do not support all features.
+";
+
+    const ACCEL_HELP: &str = "\
+Accelerators supported in QEMU binary:
+tcg
+kvm
+";
+
+    fn capabilities() -> QemuCapabilities {
+        QemuCapabilities {
+            version: "8.2.2".to_string(),
+            devices: parse_devices(DEVICE_HELP),
+            displays: parse_list(DISPLAY_HELP),
+            accels: parse_list(ACCEL_HELP),
+        }
+    }
+
+    fn config() -> QemuConfig {
+        let mut config = QemuConfig::new(
+            "/usr/bin/qemu-system-x86_64".into(),
+            2,
+            2048,
+            "/cache/vmlinux",
+            "/cache/rootfs.ext4",
+        );
+        config.accel = Accelerator::Kvm;
+        config.gl = true;
+        config.display = DisplayMode::Gtk;
+        config.vsock_cid = Some(3);
+        config
+    }
+
+    #[test]
+    fn help_output_is_parsed() {
+        let capabilities = capabilities();
+        assert!(capabilities.has_device("virtio-vga-gl"));
+        assert!(capabilities.has_device("virtio-blk-pci"));
+        assert!(!capabilities.has_device("virtio-blk"));
+        assert_eq!(capabilities.devices.len(), 7);
+        assert_eq!(
+            capabilities.displays,
+            ["egl-headless", "gtk", "none", "sdl"]
+                .map(String::from)
+                .into()
+        );
+        assert_eq!(capabilities.accels, ["kvm", "tcg"].map(String::from).into());
+    }
+
+    #[test]
+    fn a_complete_qemu_changes_nothing() {
+        let mut config = config();
+        capabilities().apply(&mut config).unwrap();
+        assert_eq!(config.accel, Accelerator::Kvm);
+        assert!(config.gl);
+        assert_eq!(config.display, DisplayMode::Gtk);
+        assert!(config.balloon);
+    }
+
+    #[test]
+    fn missing_features_fall_back_or_fail() {
+        struct Case {
+            name: &'static str,
+            drop_devices: &'static [&'static str],
+            displays: Option<&'static [&'static str]>,
+            accels: Option<&'static [&'static str]>,
+            tune: fn(&mut QemuConfig),
+            // The config after `apply`, or the start of the missing item in its error
+            expect: Result<fn(&QemuConfig) -> bool, &'static str>,
+        }
+        let cases = [
+            Case {
+                name: "no virgl",
+                drop_devices: &["virtio-vga-gl"],
+                displays: None,
+                accels: None,
+                tune: |_| {},
+                expect: Ok(|config| !config.gl && config.display == DisplayMode::Gtk),
+            },
+            Case {
+                name: "no virtio-vga",
+                drop_devices: &["virtio-vga-gl", "virtio-vga"],
+                displays: None,
+                accels: None,
+                tune: |_| {},
+                expect: Err("virtio-vga device"),
+            },
+            Case {
+                name: "no gtk",
+                drop_devices: &[],
+                displays: Some(&["none", "sdl"]),
+                accels: None,
+                tune: |_| {},
+                expect: Ok(|config| config.display == DisplayMode::Sdl && config.gl),
+            },
+            Case {
+                name: "no window at all",
+                drop_devices: &[],
+                displays: Some(&["none", "egl-headless"]),
+                accels: None,
+                tune: |_| {},
+                expect: Err("gtk, sdl or cocoa display"),
+            },
+            Case {
+                name: "no window, none wanted",
+                drop_devices: &[],
+                displays: Some(&["none"]),
+                accels: None,
+                tune: |config| config.display = DisplayMode::None,
+                expect: Ok(|config| config.display == DisplayMode::None),
+            },
+            Case {
+                name: "no vsock",
+                drop_devices: &["vhost-vsock-pci"],
+                displays: None,
+                accels: None,
+                tune: |_| {},
+                expect: Err("vhost-vsock-pci device"),
+            },
+            Case {
+                name: "no vsock, none wanted",
+                drop_devices: &["vhost-vsock-pci"],
+                displays: None,
+                accels: None,
+                tune: |config| config.vsock_cid = None,
+                expect: Ok(|config| config.vsock_cid.is_none()),
+            },
+            Case {
+                name: "no kvm",
+                drop_devices: &[],
+                displays: None,
+                accels: Some(&["tcg"]),
+                tune: |_| {},
+                expect: Ok(|config| config.accel == Accelerator::Tcg && !config.gl),
+            },
+            Case {
+                name: "no accelerator",
+                drop_devices: &[],
+                displays: None,
+                accels: Some(&["hax"]),
+                tune: |_| {},
+                expect: Err("kvm or tcg accelerator"),
+            },
+            Case {
+                name: "no balloon",
+                drop_devices: &["virtio-balloon-pci"],
+                displays: None,
+                accels: None,
+                tune: |_| {},
+                expect: Ok(|config| !config.balloon),
+            },
+        ];
+
+        let set = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        for case in cases {
+            let mut capabilities = capabilities();
+            for device in case.drop_devices {
+                assert!(capabilities.devices.remove(*device), "{}", case.name);
+            }
+            if let Some(displays) = case.displays {
+                capabilities.displays = set(displays);
+            }
+            if let Some(accels) = case.accels {
+                capabilities.accels = set(accels);
+            }
+            let mut config = config();
+            (case.tune)(&mut config);
+
+            match (capabilities.apply(&mut config), case.expect) {
+                (Ok(()), Ok(check)) => assert!(check(&config), "{}", case.name),
+                (Err(e), Err(missing)) => {
+                    let message = e.to_string();
+                    assert!(
+                        message.contains(&format!("has no {missing}")),
+                        "{}: {message}",
+                        case.name
+                    );
+                    assert!(message.contains("QEMU 8.2.2 at /usr/bin/qemu-system-x86_64"));
+                }
+                (result, _) => panic!("{}: unexpected {result:?}", case.name),
+            }
+        }
+    }
+
+    /// A stand-in QEMU answering the probe's four questions.
+    #[cfg(unix)]
+    fn fake_qemu(path: &Path, version: &str) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = format!(
+            "#!/bin/sh\n\
+             case \"$1 $2\" in\n\
+             -version*) echo 'QEMU emulator version {version}' ;;\n\
+             '-device help') echo 'name \"virtio-vga\", bus PCI' ;;\n\
+             *) printf 'Available:\\ntcg\\n' ;;\n\
+             esac\n"
+        );
+        std::fs::write(path, script).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn probes_are_cached_per_binary_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let mut config = config();
+        config.qemu_bin = dir.path().join("qemu-system-x86_64");
+        let cached = || {
+            std::fs::read_dir(cache_dir.join("qemu-capabilities"))
+                .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+                .unwrap_or_else(|_| Vec::new())
+        };
+
+        fake_qemu(&config.qemu_bin, "8.2.2");
+        // A dry run probes without leaving a cache behind
+        let probed = QemuCapabilities::probe_cached(&config, &cache_dir, false).unwrap();
+        assert_eq!(probed.version, "8.2.2");
+        assert!(cached().is_empty());
+
+        let probed = QemuCapabilities::probe_cached(&config, &cache_dir, true).unwrap();
+        assert_eq!(probed.version, "8.2.2");
+        assert_eq!(probed.devices, ["virtio-vga".to_string()].into());
+        assert_eq!(probed.accels, ["tcg".to_string()].into());
+        let files: Vec<PathBuf> = cached();
+        assert_eq!(files, [cache_file(&cache_dir, &config.qemu_bin).unwrap()]);
+
+        // The same binary is answered from the cache, not run again
+        let mut stale = probed.clone();
+        stale.version = "from the cache".to_string();
+        std::fs::write(&files[0], serde_json::to_string(&stale).unwrap()).unwrap();
+        let probed = QemuCapabilities::probe_cached(&config, &cache_dir, true).unwrap();
+        assert_eq!(probed.version, "from the cache");
+
+        // An upgraded binary hashes differently and is probed afresh
+        fake_qemu(&config.qemu_bin, "9.0.0");
+        let probed = QemuCapabilities::probe_cached(&config, &cache_dir, true).unwrap();
+        assert_eq!(probed.version, "9.0.0");
+        assert_eq!(cached().len(), 2);
+    }
+}
@@ -0,0 +1,455 @@
+use crate::error::{VirtualGhostError, VmError};
+use crate::network::GuestEndpoint;
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use super::balloon::MIB;
+use super::config::{DisplayMode, QemuConfig};
+use super::fw_cfg_drive;
+use super::http::UnixHttpClient;
+use super::hypervisor::{Hypervisor, HypervisorKind, VmControl};
+use super::instance::InstanceDir;
+use super::models::{
+    ConsoleConfig, ConsoleMode, DeviceConfig, DiskConfig, MemoryConfig, PayloadConfig, RngConfig,
+    VmCreateConfig, VmInfo, VmState, VmmPingResponse, VsockConfig,
+};
+use super::process::ChildGuard;
+
+/// Delay between checks while the API socket comes up or the guest shuts down.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a running VM is checked for a guest that powered off.
+const SHUTDOWN_POLL: Duration = Duration::from_secs(1);
+
+/// Client for Cloud Hypervisor's REST API (`/api/v1/...` on `--api-socket`).
+#[derive(Debug, Clone)]
+pub struct ChApiClient {
+    http: UnixHttpClient,
+}
+
+impl ChApiClient {
+    pub fn new(socket: PathBuf) -> Self {
+        Self {
+            http: UnixHttpClient::new(socket),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<VmmPingResponse, VirtualGhostError> {
+        self.get("vmm.ping").await
+    }
+
+    pub async fn create(&self, config: &VmCreateConfig) -> Result<(), VirtualGhostError> {
+        let body = serde_json::to_value(config).map_err(|e| VmError::Api(e.to_string()))?;
+        self.put("vm.create", Some(&body)).await
+    }
+
+    pub async fn boot(&self) -> Result<(), VirtualGhostError> {
+        self.put("vm.boot", None).await
+    }
+
+    /// Shut the VMM process down, along with the VM if it still runs.
+    pub async fn shutdown_vmm(&self) -> Result<(), VirtualGhostError> {
+        self.put("vmm.shutdown", None).await
+    }
+
+    /// Ask a VMM whose guest powered off to exit, which it does not do by itself.
+    pub async fn reap_shutdown_guest(&self) {
+        if let Ok(VmInfo {
+            state: VmState::Shutdown,
+        }) = self.info().await
+        {
+            debug!("Guest is shut down, stopping Cloud Hypervisor");
+            let _ = self.shutdown_vmm().await;
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T, VirtualGhostError> {
+        self.http.get(&format!("/api/v1/{endpoint}")).await
+    }
+
+    async fn put(&self, endpoint: &str, body: Option<&Value>) -> Result<(), VirtualGhostError> {
+        self.http
+            .send("PUT", &format!("/api/v1/{endpoint}"), body)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VmControl for ChApiClient {
+    async fn power_button(&self) -> Result<(), VirtualGhostError> {
+        self.put("vm.power-button", None).await
+    }
+
+    async fn pause(&self) -> Result<(), VirtualGhostError> {
+        self.put("vm.pause", None).await
+    }
+
+    async fn resume(&self) -> Result<(), VirtualGhostError> {
+        self.put("vm.resume", None).await
+    }
+
+    async fn info(&self) -> Result<VmInfo, VirtualGhostError> {
+        self.get("vm.info").await
+    }
+}
+
+/// A Cloud Hypervisor process. The VM is created and booted over its API
+/// once the socket answers.
+pub struct CloudHypervisor {
+    child: ChildGuard,
+    api: ChApiClient,
+    vm: VmCreateConfig,
+    vsock_socket: PathBuf,
+    /// Whether the VM was created, so the API can be used to control it.
+    booted: bool,
+}
+
+impl CloudHypervisor {
+    /// The `vm.create` request for the VM described by `config`.
+    ///
+    /// Cloud Hypervisor has no virtual display, fw_cfg or user networking:
+    /// the guest is reached over hybrid vsock, a Unix socket in `instance`,
+    /// and finds its fw_cfg files on a second, read-only drive.
+    pub fn vm_config(config: &QemuConfig, instance: &InstanceDir) -> VmCreateConfig {
+        let payload = match &config.firmware {
+            Some(firmware) => PayloadConfig {
+                firmware: Some(firmware.code.display().to_string()),
+                kernel: None,
+                cmdline: None,
+                initramfs: None,
+            },
+            None => PayloadConfig {
+                firmware: None,
+                kernel: Some(config.kernel_path.clone()),
+                cmdline: Some(config.cmdline.clone()),
+                initramfs: config.initramfs_path.clone(),
+            },
+        };
+        let devices: Vec<DeviceConfig> = config
+            .gpu_passthrough
+            .iter()
+            .enumerate()
+            .map(|(i, path)| DeviceConfig {
+                path: path.clone(),
+                iommu: false,
+                id: Some(format!("vfio{i}")),
+            })
+            .collect();
+        let mut disks = vec![DiskConfig {
+            path: config.rootfs_path.clone(),
+            readonly: false,
+            direct: false,
+            backing_files: config.rootfs_format == "qcow2",
+            id: None,
+            serial: None,
+        }];
+        if !config.fw_cfg.is_empty() {
+            disks.push(DiskConfig {
+                path: instance.fw_cfg_drive().display().to_string(),
+                readonly: true,
+                direct: false,
+                backing_files: false,
+                id: Some("fw_cfg".into()),
+                serial: Some(fw_cfg_drive::SERIAL.into()),
+            });
+        }
+        VmCreateConfig {
+            cpus: Some(config.cpus.clone()),
+            memory: Some(MemoryConfig {
+                size: u64::from(config.memory_mib) * MIB,
+                shared: false,
+                hugepages: false,
+            }),
+            payload,
+            disks: Some(disks),
+            net: None,
+            rng: Some(RngConfig {
+                src: "/dev/urandom".into(),
+            }),
+            vsock: config.vsock_cid.map(|cid| VsockConfig {
+                cid,
+                socket: instance.vsock_socket().display().to_string(),
+                iommu: false,
+            }),
+            devices: (!devices.is_empty()).then_some(devices),
+            serial: Some(ConsoleConfig {
+                mode: ConsoleMode::File,
+                file: Some(instance.serial_log().display().to_string()),
+                socket: None,
+            }),
+            console: Some(ConsoleConfig {
+                mode: ConsoleMode::Off,
+                file: None,
+                socket: None,
+            }),
+            iommu: false,
+        }
+    }
+
+    /// Arguments starting the VMM with only its API socket; the VM comes over the API.
+    pub fn args(instance: &InstanceDir) -> Vec<String> {
+        vec![
+            "--api-socket".into(),
+            format!("path={}", instance.api_socket().display()),
+        ]
+    }
+
+    /// Stop the VMM of a VM handed off to run in the background once its
+    /// guest powers off, as [`Hypervisor::wait`] does for a foreground one.
+    /// Returns when the VMM is gone.
+    pub async fn reap_detached(instance: &InstanceDir) -> Result<(), VirtualGhostError> {
+        let api = ChApiClient::new(instance.api_socket());
+        while instance.running_state()?.is_some() {
+            api.reap_shutdown_guest().await;
+            tokio::time::sleep(SHUTDOWN_POLL).await;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Hypervisor for CloudHypervisor {
+    async fn spawn(config: &QemuConfig, instance: &InstanceDir) -> Result<Self, VirtualGhostError> {
+        if config.display != DisplayMode::None && config.gpu_passthrough.is_empty() {
+            warn!("Cloud Hypervisor has no virtual display; use GPU passthrough or `virtualghost shell`");
+        }
+        if !config.fw_cfg.is_empty() {
+            fw_cfg_drive::write(&config.fw_cfg, &instance.fw_cfg_drive())?;
+        }
+        if !config.extra_args.is_empty() {
+            warn!("Extra QEMU arguments are ignored with Cloud Hypervisor");
+        }
+
+        let args = Self::args(instance);
+        info!(bin = %config.qemu_bin.display(), args = ?args, "Spawning Cloud Hypervisor");
+        let _ = std::fs::remove_file(instance.api_socket());
+        let _ = std::fs::remove_file(instance.vsock_socket());
+
+        let mut cmd = Command::new(&config.qemu_bin);
+        cmd.args(&args)
+            .stdin(std::process::Stdio::null())
+            // Like QEMU, out of the terminal's process group: Ctrl-C is the launcher's to handle
+            .process_group(0);
+        let child = cmd
+            .spawn()
+            .map_err(|e| VmError::VmmSpawnFailed(config.qemu_bin.display().to_string(), e))?;
+        info!(pid = child.id(), "Cloud Hypervisor process started");
+
+        Ok(Self {
+            child: ChildGuard::new(child),
+            api: ChApiClient::new(instance.api_socket()),
+            vm: Self::vm_config(config, instance),
+            vsock_socket: instance.vsock_socket(),
+            booted: false,
+        })
+    }
+
+    fn kind(&self) -> HypervisorKind {
+        HypervisorKind::CloudHypervisor
+    }
+
+    fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    async fn wait_ready(&mut self, timeout: Duration) -> Result<(), VirtualGhostError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let version = loop {
+            match self.api.ping().await {
+                Ok(ping) => break ping.version,
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(e) => debug!(error = %e, "Cloud Hypervisor API not ready yet"),
+            }
+            if let Some(status) = self.child.try_wait()? {
+                return Err(VmError::ProcessExited(status.code()).into());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+        info!(version, "Cloud Hypervisor API ready");
+
+        self.api.create(&self.vm).await?;
+        self.api.boot().await?;
+        self.booted = true;
+        info!("Cloud Hypervisor VM booting");
+        Ok(())
+    }
+
+    fn agent_endpoint(&self, vsock_port: u32) -> Option<GuestEndpoint> {
+        self.vm.vsock.as_ref().map(|_| GuestEndpoint::HybridVsock {
+            socket: self.vsock_socket.clone(),
+            port: vsock_port,
+        })
+    }
+
+    fn control(&self) -> Option<&dyn VmControl> {
+        self.booted.then_some(&self.api as &dyn VmControl)
+    }
+
+    async fn wait(&mut self) -> Result<ExitStatus, VirtualGhostError> {
+        // The VMM outlives a guest that powers off; stop it once that happens
+        loop {
+            tokio::select! {
+                status = self.child.wait() => return status.map_err(|e| {
+                    VmError::VmmSpawnFailed("Cloud Hypervisor".into(), e).into()
+                }),
+                _ = tokio::time::sleep(SHUTDOWN_POLL), if self.booted => {
+                    self.api.reap_shutdown_guest().await;
+                }
+            }
+        }
+    }
+
+    async fn kill(&mut self) -> Result<(), VirtualGhostError> {
+        self.child
+            .kill()
+            .await
+            .map_err(|e| VmError::VmmSpawnFailed("Cloud Hypervisor".into(), e).into())
+    }
+
+    fn detach(mut self: Box<Self>) -> Option<u32> {
+        self.child.detach()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::http::fake::{response, FakeApi};
+    use super::*;
+
+    /// Answers like Cloud Hypervisor: JSON for `vmm.ping` and `vm.info`, 204 otherwise.
+    fn fake_ch() -> FakeApi {
+        FakeApi::serve(|request| match request.path.as_str() {
+            "/api/v1/vmm.ping" => response(
+                "200 OK",
+                r#"{"build_version":"v42.0","version":"42.0","pid":1234,"features":[]}"#,
+            ),
+            "/api/v1/vm.info" => response(
+                "200 OK",
+                r#"{"config":{},"state":"Paused","memory_actual_size":0}"#,
+            ),
+            "/api/v1/vm.boot" if request.method == "PUT" => {
+                "HTTP/1.1 204 No Content\r\n\r\n".into()
+            }
+            "/api/v1/vm.create"
+            | "/api/v1/vm.power-button"
+            | "/api/v1/vm.pause"
+            | "/api/v1/vm.resume"
+            | "/api/v1/vmm.shutdown" => {
+                "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n".into()
+            }
+            _ => response("404 Not Found", ""),
+        })
+    }
+
+    #[tokio::test]
+    async fn pings_the_vmm() {
+        let api = fake_ch();
+        let ping = ChApiClient::new(api.socket.clone()).ping().await.unwrap();
+        assert_eq!(ping.version, "42.0");
+        assert_eq!(ping.pid, 1234);
+        assert_eq!(api.requests()[0].method, "GET");
+    }
+
+    #[tokio::test]
+    async fn creates_and_boots_the_vm() {
+        let api = fake_ch();
+        let client = ChApiClient::new(api.socket.clone());
+        let mut config = QemuConfig::new(
+            "cloud-hypervisor".into(),
+            2,
+            1024,
+            "/boot/vmlinux",
+            "/disk/overlay.qcow2",
+        );
+        config.rootfs_format = "qcow2";
+        let vm = CloudHypervisor::vm_config(&config, &InstanceDir::new("ch-test"));
+        client.create(&vm).await.unwrap();
+        client.boot().await.unwrap();
+
+        let requests = api.requests();
+        let paths: Vec<_> = requests
+            .iter()
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect();
+        assert_eq!(paths, ["PUT /api/v1/vm.create", "PUT /api/v1/vm.boot"]);
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["payload"]["kernel"], "/boot/vmlinux");
+        assert_eq!(body["memory"]["size"], 1024 * MIB);
+        assert_eq!(body["disks"][0]["path"], "/disk/overlay.qcow2");
+        assert_eq!(body["disks"][0]["backing_files"], true);
+        assert_eq!(requests[1].body, "");
+    }
+
+    #[test]
+    fn fw_cfg_drive_carries_its_serial() {
+        let mut config = QemuConfig::new(
+            "cloud-hypervisor".into(),
+            2,
+            1024,
+            "/boot/vmlinux",
+            "/disk/rootfs.ext4",
+        );
+        let instance = InstanceDir::new("ch-test");
+        assert_eq!(
+            CloudHypervisor::vm_config(&config, &instance)
+                .disks
+                .unwrap()
+                .len(),
+            1
+        );
+
+        config
+            .fw_cfg
+            .push(("opt/virtualghost/authorized_keys".into(), "/keys".into()));
+        let disks = CloudHypervisor::vm_config(&config, &instance)
+            .disks
+            .unwrap();
+        assert_eq!(disks[0].serial, None);
+        assert_eq!(disks[1].path, instance.fw_cfg_drive().display().to_string());
+        assert!(disks[1].readonly);
+        assert_eq!(disks[1].serial.as_deref(), Some("fw_cfg"));
+    }
+
+    #[tokio::test]
+    async fn controls_the_vm() {
+        let api = fake_ch();
+        let client = ChApiClient::new(api.socket.clone());
+        client.power_button().await.unwrap();
+        client.pause().await.unwrap();
+        client.resume().await.unwrap();
+        assert!(matches!(
+            client.info().await.unwrap().state,
+            VmState::Paused
+        ));
+        client.shutdown_vmm().await.unwrap();
+
+        let paths: Vec<_> = api.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            [
+                "/api/v1/vm.power-button",
+                "/api/v1/vm.pause",
+                "/api/v1/vm.resume",
+                "/api/v1/vm.info",
+                "/api/v1/vmm.shutdown",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn surfaces_api_errors() {
+        let api = FakeApi::serve(|_| response("500 Internal Server Error", "VM is not booted"));
+        let error = ChApiClient::new(api.socket.clone())
+            .power_button()
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("500 VM is not booted"));
+    }
+}
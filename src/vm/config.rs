@@ -1,4 +1,12 @@
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::qmp::QmpAddress;
+use super::models::CpusConfig;
+use super::suspend::MachineSignature;
+
+/// Kernel command line for the bundled rootfs, unless overridden.
+const DEFAULT_CMDLINE: &str = "console=ttyS0 root=/dev/vda rw quiet";
 
 /// Hardware accelerator for QEMU.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -13,10 +21,8 @@ pub enum Accelerator {
 impl Accelerator {
     /// Detect the best available accelerator for the current platform.
     pub fn detect() -> Self {
-        if cfg!(target_os = "linux") {
-            if std::path::Path::new("/dev/kvm").exists() {
-                return Self::Kvm;
-            }
+        if cfg!(target_os = "linux") && std::path::Path::new("/dev/kvm").exists() {
+            return Self::Kvm;
         }
         if cfg!(target_os = "macos") {
             return Self::Hvf;
@@ -25,7 +31,7 @@ impl Accelerator {
         Self::Tcg
     }
 
-    fn as_arg(&self) -> &str {
+    pub fn as_arg(&self) -> &str {
         match self {
             Self::Kvm => "kvm",
             Self::Hvf => "hvf",
@@ -57,7 +63,7 @@ impl DisplayMode {
         }
     }
 
-    fn as_arg(&self) -> &str {
+    pub fn as_arg(&self) -> &str {
         match self {
             Self::Sdl => "sdl",
             Self::Cocoa => "cocoa",
@@ -67,16 +73,47 @@ impl DisplayMode {
     }
 }
 
+/// UEFI firmware that boots the rootfs as a disk image, in place of direct kernel boot.
+#[derive(Debug, Clone)]
+pub struct Firmware {
+    /// Firmware code (OVMF), mapped read-only.
+    pub code: PathBuf,
+    /// This VM's writable UEFI variable store; without one the firmware keeps no settings.
+    pub vars: Option<PathBuf>,
+}
+
+impl Firmware {
+    /// OVMF code and variable store template as QEMU ships them in its data directory.
+    pub fn bundled(data_dir: &Path) -> (PathBuf, PathBuf) {
+        (
+            data_dir.join("edk2-x86_64-code.fd"),
+            data_dir.join("edk2-i386-vars.fd"),
+        )
+    }
+}
+
 /// QEMU VM configuration — builds command-line arguments.
 pub struct QemuConfig {
     pub qemu_bin: PathBuf,
-    pub vcpus: u32,
+    /// vCPUs at boot, and how many the VM can grow to with hot-added ones.
+    pub cpus: CpusConfig,
     pub memory_mib: u32,
+    /// Memory the VM can grow to with hot-added DIMMs; no hotplug unless above `memory_mib`.
+    pub max_memory_mib: u32,
+    /// DIMM slots for hot-added memory.
+    pub memory_slots: u32,
     pub kernel_path: String,
     pub rootfs_path: String,
+    /// `raw` or `qcow2`, see [`image_format`].
+    pub rootfs_format: &'static str,
     pub cmdline: String,
+    pub initramfs_path: Option<String>,
+    /// Boot through UEFI firmware; the kernel, cmdline and initramfs are then unused.
+    pub firmware: Option<Firmware>,
     pub display: DisplayMode,
     pub accel: Accelerator,
+    /// Render with virgl 3D: `virtio-vga-gl` and a GL-enabled display.
+    pub gl: bool,
     pub gpu_passthrough: Vec<String>,
     pub vsock_cid: Option<u64>,
     pub ssh_port_forward: Option<u16>,
@@ -84,6 +121,24 @@ pub struct QemuConfig {
     pub qemu_data_dir: Option<PathBuf>,
     /// TCP port for QMP on Windows (dynamically allocated).
     pub qmp_tcp_port: Option<u16>,
+    /// Connect the serial console to the logger listening on this Unix socket.
+    /// Takes precedence over `serial_tcp_port`.
+    pub serial_socket: Option<PathBuf>,
+    /// Loopback TCP port the logger listens on where there are no Unix sockets.
+    pub serial_tcp_port: Option<u16>,
+    /// Host files exposed to the guest through fw_cfg, as (`opt/...` name, path).
+    pub fw_cfg: Vec<(String, PathBuf)>,
+    /// Add a pvpanic device so a guest kernel panic is reported as a QMP event.
+    pub pvpanic: bool,
+    /// Add a virtio-balloon device so guest memory can be resized at runtime.
+    pub balloon: bool,
+    /// Second QMP monitor for other `virtualghost` invocations while the launcher
+    /// holds the first one.
+    pub control_qmp: Option<QmpAddress>,
+    /// Load a saved VM state from this migration URI instead of booting.
+    pub incoming: Option<String>,
+    /// Passed verbatim after the generated arguments.
+    pub extra_args: Vec<String>,
 }
 
 impl QemuConfig {
@@ -99,24 +154,126 @@ impl QemuConfig {
 
         Self {
             qemu_bin,
-            vcpus,
+            cpus: CpusConfig {
+                boot_vcpus: vcpus,
+                max_vcpus: vcpus,
+            },
             memory_mib,
+            max_memory_mib: memory_mib,
+            memory_slots: 0,
             kernel_path: kernel_path.to_string(),
             rootfs_path: rootfs_path.to_string(),
-            cmdline: "console=ttyS0 root=/dev/vda rw quiet".to_string(),
+            rootfs_format: "raw",
+            cmdline: DEFAULT_CMDLINE.to_string(),
+            initramfs_path: None,
+            firmware: None,
             display,
             accel,
+            // Only KVM and HVF are fast enough for virgl to pay off
+            gl: matches!(accel, Accelerator::Kvm | Accelerator::Hvf),
             gpu_passthrough: Vec::new(),
             vsock_cid: None,
             ssh_port_forward: None,
             qmp_socket: PathBuf::new(),
             qemu_data_dir: None,
             qmp_tcp_port: None,
+            serial_socket: None,
+            serial_tcp_port: None,
+            fw_cfg: Vec::new(),
+            pvpanic: true,
+            balloon: true,
+            control_qmp: None,
+            incoming: None,
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// Address of the QMP server that `to_args` asks QEMU to open, if any.
+    pub fn qmp_address(&self) -> Option<QmpAddress> {
+        if cfg!(unix) {
+            Some(QmpAddress::Unix(self.qmp_socket.clone()))
+        } else {
+            self.qmp_tcp_port.map(QmpAddress::Tcp)
+        }
+    }
+
+    /// Whether `-m` leaves room for hot-added DIMMs.
+    pub fn memory_hotplug(&self) -> bool {
+        self.memory_slots > 0 && self.max_memory_mib > self.memory_mib
+    }
+
+    /// The settings a saved VM state can only be loaded back into unchanged.
+    pub fn machine_signature(&self) -> MachineSignature {
+        let args = self.to_args();
+        let devices = args
+            .iter()
+            .zip(args.iter().skip(1))
+            .filter(|(flag, _)| *flag == "-device")
+            // The vsock CID is host-side and may change between runs
+            .map(|(_, device)| match device.split_once(",guest-cid=") {
+                Some((model, _)) => model.to_string(),
+                None => device.clone(),
+            })
+            .collect();
+        MachineSignature {
+            qemu_bin: self.qemu_bin.clone(),
+            accel: self.accel.as_arg().to_string(),
+            cpus: self.cpus.clone(),
+            memory_mib: self.memory_mib,
+            max_memory_mib: self.max_memory_mib,
+            memory_slots: self.memory_slots,
+            kernel: self.kernel_path.clone(),
+            rootfs: self.rootfs_path.clone(),
+            firmware: self.firmware.as_ref().map(|firmware| firmware.code.clone()),
+            devices,
         }
     }
 
     /// Build QEMU command-line arguments.
     pub fn to_args(&self) -> Vec<String> {
+        let mut args = self.generated_args();
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// Entries of `extra_args` that clash with what [`Self::to_args`] already
+    /// sets: an option it manages, or a `-device` model it already adds.
+    pub fn conflicting_extra_args(&self) -> Vec<String> {
+        let generated = self.generated_args();
+        let managed: Vec<&str> = generated
+            .iter()
+            .filter(|arg| arg.starts_with('-') && !ADDITIVE_OPTIONS.contains(&arg.as_str()))
+            .map(String::as_str)
+            .collect();
+        let devices: Vec<&str> = generated
+            .iter()
+            .zip(generated.iter().skip(1))
+            .filter(|(flag, _)| *flag == "-device")
+            .map(|(_, device)| device_model(device))
+            .collect();
+
+        let mut conflicts = Vec::new();
+        let mut extra = self.extra_args.iter().peekable();
+        while let Some(arg) = extra.next() {
+            // QEMU accepts options with one dash or two
+            let flag = arg
+                .strip_prefix('-')
+                .filter(|rest| rest.starts_with('-'))
+                .unwrap_or(arg);
+            if flag == "-device" {
+                if let Some(device) =
+                    extra.next_if(|device| devices.contains(&device_model(device)))
+                {
+                    conflicts.push(format!("{arg} {device}"));
+                }
+            } else if managed.contains(&flag) {
+                conflicts.push(arg.clone());
+            }
+        }
+        conflicts
+    }
+
+    fn generated_args(&self) -> Vec<String> {
         let mut args = Vec::new();
 
         // Data directory (BIOS, VGA BIOS, keymaps, etc.)
@@ -133,19 +290,67 @@ impl QemuConfig {
             _ => "max",
         };
         args.extend(["-cpu".into(), cpu.into()]);
-        args.extend(["-smp".into(), self.vcpus.to_string()]);
-        args.extend(["-m".into(), self.memory_mib.to_string()]);
+        // vCPUs and memory, with room for hot-added ones if configured
+        let smp = if self.cpus.max_vcpus > self.cpus.boot_vcpus {
+            format!(
+                "cpus={},maxcpus={}",
+                self.cpus.boot_vcpus, self.cpus.max_vcpus
+            )
+        } else {
+            self.cpus.boot_vcpus.to_string()
+        };
+        args.extend(["-smp".into(), smp]);
+        let memory = if self.memory_hotplug() {
+            format!(
+                "size={}M,slots={},maxmem={}M",
+                self.memory_mib, self.memory_slots, self.max_memory_mib
+            )
+        } else {
+            self.memory_mib.to_string()
+        };
+        args.extend(["-m".into(), memory]);
 
-        // Kernel + cmdline
-        args.extend(["-kernel".into(), self.kernel_path.clone()]);
-        args.extend(["-append".into(), self.cmdline.clone()]);
+        match &self.firmware {
+            // UEFI: the firmware finds a bootloader on the disk
+            Some(firmware) => {
+                args.extend([
+                    "-drive".into(),
+                    format!(
+                        "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                        escape_opt(&firmware.code.display().to_string())
+                    ),
+                ]);
+                if let Some(vars) = &firmware.vars {
+                    args.extend([
+                        "-drive".into(),
+                        format!(
+                            "if=pflash,format=raw,unit=1,file={}",
+                            escape_opt(&vars.display().to_string())
+                        ),
+                    ]);
+                }
+            }
+            // Kernel + cmdline; hot-added memory is put to use without help from guest userspace
+            None => {
+                let mut cmdline = self.cmdline.clone();
+                if self.memory_hotplug() {
+                    cmdline.push_str(" memhp_default_state=online");
+                }
+                args.extend(["-kernel".into(), self.kernel_path.clone()]);
+                args.extend(["-append".into(), cmdline]);
+                if let Some(initramfs) = &self.initramfs_path {
+                    args.extend(["-initrd".into(), initramfs.clone()]);
+                }
+            }
+        }
 
         // Rootfs disk
         args.extend([
             "-drive".into(),
             format!(
-                "file={},format=raw,if=virtio",
-                self.rootfs_path
+                "file={},format={},if=virtio",
+                escape_opt(&self.rootfs_path),
+                self.rootfs_format
             ),
         ]);
 
@@ -155,7 +360,7 @@ impl QemuConfig {
             args.extend(["-display".into(), "none".into()]);
             args.extend(["-vga".into(), "none".into()]);
         } else {
-            if self.accel == Accelerator::Kvm || self.accel == Accelerator::Hvf {
+            if self.gl {
                 // virgl 3D: host GL context passed to guest via virtio-gpu
                 args.extend(["-device".into(), "virtio-vga-gl".into()]);
                 args.extend([
@@ -163,7 +368,7 @@ impl QemuConfig {
                     format!("{},gl=on", self.display.as_arg()),
                 ]);
             } else {
-                // TCG/software: no virgl support, basic VGA framebuffer.
+                // TCG/software or no virgl in this QEMU: basic VGA framebuffer.
                 // Guest uses wlroots pixman renderer (software rendering).
                 args.extend(["-device".into(), "virtio-vga".into()]);
                 args.extend(["-display".into(), self.display.as_arg().into()]);
@@ -173,23 +378,48 @@ impl QemuConfig {
         }
 
         // Serial console
-        args.extend(["-serial".into(), "stdio".into()]);
+        match (&self.serial_socket, self.serial_tcp_port) {
+            (Some(socket), _) => {
+                args.extend([
+                    "-chardev".into(),
+                    format!(
+                        "socket,id=serial0,path={},server=off",
+                        escape_opt(&socket.display().to_string())
+                    ),
+                ]);
+                args.extend(["-serial".into(), "chardev:serial0".into()]);
+            }
+            (None, Some(port)) => {
+                args.extend([
+                    "-chardev".into(),
+                    format!("socket,id=serial0,host=127.0.0.1,port={port},server=off"),
+                ]);
+                args.extend(["-serial".into(), "chardev:serial0".into()]);
+            }
+            (None, None) => args.extend(["-serial".into(), "none".into()]),
+        }
 
-        // QMP socket for graceful shutdown
-        if cfg!(unix) {
-            let qmp_path = self.qmp_socket.display().to_string();
-            args.extend([
-                "-qmp".into(),
-                format!("unix:{qmp_path},server,nowait"),
-            ]);
-        } else if let Some(port) = self.qmp_tcp_port {
-            // Windows: use TCP with a dynamically allocated port
+        // Guest panic notification (GUEST_PANICKED event)
+        if self.pvpanic {
+            args.extend(["-device".into(), "pvpanic-pci".into()]);
+        }
+
+        // Memory balloon; the guest takes memory back by itself rather than hit OOM
+        if self.balloon {
             args.extend([
-                "-qmp".into(),
-                format!("tcp:127.0.0.1:{port},server,nowait"),
+                "-device".into(),
+                "virtio-balloon-pci,id=balloon0,deflate-on-oom=on".into(),
             ]);
         }
 
+        // QMP socket for graceful shutdown (Windows: TCP with a dynamically allocated port)
+        if let Some(addr) = self.qmp_address() {
+            args.extend(["-qmp".into(), qmp_arg(&addr)]);
+        }
+        if let Some(addr) = &self.control_qmp {
+            args.extend(["-qmp".into(), qmp_arg(addr)]);
+        }
+
         // Vsock (Linux only — vhost-vsock-pci requires KVM)
         if let Some(cid) = self.vsock_cid {
             args.extend([
@@ -212,9 +442,195 @@ impl QemuConfig {
             args.extend(["-device".into(), format!("vfio-pci,host={pci_addr}")]);
         }
 
+        // Boot-time data for the guest agent (read from /sys/firmware/qemu_fw_cfg)
+        for (name, path) in &self.fw_cfg {
+            args.extend([
+                "-fw_cfg".into(),
+                format!(
+                    "name={},file={}",
+                    escape_opt(name),
+                    escape_opt(&path.display().to_string())
+                ),
+            ]);
+        }
+
+        // Resume from a saved state
+        if let Some(uri) = &self.incoming {
+            args.extend(["-incoming".into(), uri.clone()]);
+        }
+
         // Misc
         args.push("-nodefaults".into());
 
         args
     }
 }
+
+/// Format of the disk image at `path` by its header: `qcow2`, or else `raw`.
+pub fn image_format(path: &Path) -> std::io::Result<&'static str> {
+    let mut magic = [0; 4];
+    let read = std::fs::File::open(path)?.read(&mut magic)?;
+    Ok(if read == magic.len() && magic == *b"QFI\xfb" {
+        "qcow2"
+    } else {
+        "raw"
+    })
+}
+
+/// Options that may be given more than once, each adding another device or backend.
+const ADDITIVE_OPTIONS: &[&str] = &[
+    "-device", "-drive", "-chardev", "-netdev", "-fw_cfg", "-qmp",
+];
+
+/// Model of a `-device` argument, without its properties.
+fn device_model(device: &str) -> &str {
+    device.split(',').next().unwrap_or(device)
+}
+
+/// `-qmp` argument for a QMP server at `addr`.
+fn qmp_arg(addr: &QmpAddress) -> String {
+    match addr {
+        QmpAddress::Unix(path) => format!(
+            "unix:{},server,nowait",
+            escape_opt(&path.display().to_string())
+        ),
+        QmpAddress::Tcp(port) => format!("tcp:127.0.0.1:{port},server,nowait"),
+    }
+}
+
+/// Escape a value embedded in a QEMU `key=value,...` option string (commas are doubled).
+fn escape_opt(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra_args: &[&str]) -> QemuConfig {
+        let mut config = QemuConfig::new(
+            "qemu-system-x86_64".into(),
+            2,
+            2048,
+            "/cache/vmlinux",
+            "/cache/rootfs.ext4",
+        );
+        config.accel = Accelerator::Kvm;
+        config.gl = true;
+        config.display = DisplayMode::Gtk;
+        config.extra_args = extra_args.iter().map(|arg| arg.to_string()).collect();
+        config
+    }
+
+    #[test]
+    fn extra_args_that_add_to_the_machine_do_not_conflict() {
+        let config = config(&[
+            "-device",
+            "usb-tablet",
+            "-drive",
+            "file=data.img,if=virtio",
+            "-object",
+            "rng-random,id=rng0",
+            "-no-reboot",
+        ]);
+        assert!(config.conflicting_extra_args().is_empty());
+        assert!(config.to_args().ends_with(&config.extra_args));
+    }
+
+    #[test]
+    fn managed_options_and_duplicate_devices_conflict() {
+        let config = config(&[
+            "-smp",
+            "8",
+            "--m",
+            "4096",
+            "-device",
+            "virtio-vga-gl,xres=1920",
+            "-device",
+            "usb-tablet",
+            "-display",
+            "none",
+            "-device",
+        ]);
+        assert_eq!(
+            config.conflicting_extra_args(),
+            ["-smp", "--m", "-device virtio-vga-gl,xres=1920", "-display"]
+        );
+    }
+
+    #[test]
+    fn devices_conflict_by_model_only() {
+        let mut config = config(&["-device", "vhost-vsock-pci,guest-cid=9"]);
+        assert!(config.conflicting_extra_args().is_empty());
+        config.vsock_cid = Some(3);
+        assert_eq!(
+            config.conflicting_extra_args(),
+            ["-device vhost-vsock-pci,guest-cid=9"]
+        );
+    }
+
+    /// The values following each occurrence of `option` in `args`.
+    fn values<'a>(args: &'a [String], option: &str) -> Vec<&'a str> {
+        args.windows(2)
+            .filter(|pair| pair[0] == option)
+            .map(|pair| pair[1].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn direct_boot_passes_kernel_cmdline_and_initrd() {
+        let mut config = config(&[]);
+        let args = config.to_args();
+        assert_eq!(values(&args, "-kernel"), ["/cache/vmlinux"]);
+        assert_eq!(values(&args, "-append"), [DEFAULT_CMDLINE]);
+        assert!(values(&args, "-initrd").is_empty());
+        assert!(!args.iter().any(|arg| arg.contains("if=pflash")));
+
+        config.cmdline = "console=hvc0 root=/dev/vda1".into();
+        config.initramfs_path = Some("/boot/initrd.img".into());
+        config.memory_slots = 4;
+        config.max_memory_mib = 8192;
+        let args = config.to_args();
+        assert_eq!(
+            values(&args, "-append"),
+            ["console=hvc0 root=/dev/vda1 memhp_default_state=online"]
+        );
+        assert_eq!(values(&args, "-initrd"), ["/boot/initrd.img"]);
+    }
+
+    #[test]
+    fn uefi_boot_maps_firmware_as_pflash() {
+        let mut config = config(&[]);
+        config.cmdline = "ignored".into();
+        config.initramfs_path = Some("/boot/initrd.img".into());
+        config.firmware = Some(Firmware {
+            code: "/usr/share/OVMF/OVMF_CODE,4M.fd".into(),
+            vars: Some("/state/efi-vars.fd".into()),
+        });
+        let args = config.to_args();
+        let pflash: Vec<_> = values(&args, "-drive")
+            .into_iter()
+            .filter(|drive| drive.starts_with("if=pflash"))
+            .collect();
+        assert_eq!(
+            pflash,
+            [
+                "if=pflash,format=raw,unit=0,readonly=on,file=/usr/share/OVMF/OVMF_CODE,,4M.fd",
+                "if=pflash,format=raw,unit=1,file=/state/efi-vars.fd",
+            ]
+        );
+        for option in ["-kernel", "-append", "-initrd"] {
+            assert!(values(&args, option).is_empty(), "{option}");
+        }
+
+        config.firmware.as_mut().unwrap().vars = None;
+        let args = config.to_args();
+        assert_eq!(
+            values(&args, "-drive")
+                .iter()
+                .filter(|drive| drive.starts_with("if=pflash"))
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,116 @@
+#![cfg(unix)]
+
+use crate::error::{VirtualGhostError, VmError};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tracing::debug;
+
+use super::serial::{SerialLog, PARTIAL_LINE_FLUSH};
+
+/// Console output chunks buffered per attached client before it starts missing some.
+const CLIENT_BACKLOG: usize = 256;
+
+/// Serve a VM's serial console until QEMU disconnects.
+///
+/// QEMU connects to `serial` once at startup. Everything it prints is recorded
+/// in `log` and copied to every client attached through `console`; whatever a
+/// client types goes back to the guest. Clients may come and go at any time.
+pub async fn serve_console(
+    log: &SerialLog,
+    serial: UnixListener,
+    console: UnixListener,
+    connect_timeout: Duration,
+) -> Result<(), VirtualGhostError> {
+    let (qemu, _) = tokio::time::timeout(connect_timeout, serial.accept())
+        .await
+        .map_err(|_| VmError::SerialLog("QEMU did not connect to the console socket".into()))??;
+    drop(serial);
+    let (mut from_guest, mut to_guest) = qemu.into_split();
+
+    let (output, _) = broadcast::channel(CLIENT_BACKLOG);
+    let (input, mut keys) = mpsc::channel(64);
+    let clients = tokio::spawn(accept_clients(console, output.clone(), input));
+
+    let mut recorder = log.recorder()?;
+    let mut buf = [0u8; 4096];
+    let result = loop {
+        let partial = recorder.has_partial();
+        let quiet = async {
+            if partial {
+                tokio::time::sleep(PARTIAL_LINE_FLUSH).await
+            } else {
+                std::future::pending().await
+            }
+        };
+        tokio::select! {
+            read = from_guest.read(&mut buf) => {
+                let n = match read {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => n,
+                    Err(e) => break Err(e),
+                };
+                // No receivers just means nobody is attached
+                let _ = output.send(buf[..n].to_vec());
+                recorder.feed(&buf[..n])?;
+            }
+            Some(typed) = keys.recv() => to_guest.write_all(&typed).await?,
+            _ = quiet => recorder.flush_partial()?,
+        }
+    };
+
+    clients.abort();
+    recorder.flush_partial()?;
+    Ok(result?)
+}
+
+async fn accept_clients(
+    listener: UnixListener,
+    output: broadcast::Sender<Vec<u8>>,
+    input: mpsc::Sender<Vec<u8>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                debug!("Console client attached");
+                tokio::spawn(relay_client(stream, output.subscribe(), input.clone()));
+            }
+            Err(e) => debug!(error = %e, "Failed to accept a console client"),
+        }
+    }
+}
+
+/// Copy console output to one client and its keystrokes to the guest until it detaches.
+async fn relay_client(
+    stream: UnixStream,
+    mut output: broadcast::Receiver<Vec<u8>>,
+    input: mpsc::Sender<Vec<u8>>,
+) {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            chunk = output.recv() => match chunk {
+                Ok(bytes) => {
+                    if writer.write_all(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                // A slow client misses some output rather than stalling the guest
+                Err(RecvError::Lagged(skipped)) => debug!(skipped, "Console client lagging"),
+                Err(RecvError::Closed) => break,
+            },
+            read = reader.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if input.send(buf[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+            },
+        }
+    }
+    debug!("Console client detached");
+}
@@ -0,0 +1,349 @@
+use crate::error::{VirtualGhostError, VmError};
+use crate::network::GuestEndpoint;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use super::config::{DisplayMode, QemuConfig};
+use super::fw_cfg_drive;
+use super::http::UnixHttpClient;
+use super::hypervisor::{Hypervisor, HypervisorKind, VmControl};
+use super::instance::InstanceDir;
+use super::models::{
+    FcAction, FcActionType, FcBootSource, FcDrive, FcInstanceInfo, FcMachineConfig, FcVmState,
+    FcVmStateChange, FcVsock, VmInfo, VmState,
+};
+use super::process::ChildGuard;
+
+/// Delay between checks while the API socket comes up; Firecracker is quick.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Appended to the kernel command line: a guest reboot (or a panic, after a
+/// second) ends the VMM, and there is no PCI bus to probe.
+const BOOT_ARGS: &str = "reboot=k panic=1 pci=off";
+
+/// Client for Firecracker's API on `--api-sock`.
+#[derive(Debug, Clone)]
+pub struct FcApiClient {
+    http: UnixHttpClient,
+}
+
+impl FcApiClient {
+    pub fn new(socket: PathBuf) -> Self {
+        Self {
+            http: UnixHttpClient::new(socket),
+        }
+    }
+
+    pub async fn instance_info(&self) -> Result<FcInstanceInfo, VirtualGhostError> {
+        self.http.get("/").await
+    }
+
+    async fn send(&self, method: &str, path: &str, body: &Value) -> Result<(), VirtualGhostError> {
+        self.http.send(method, path, Some(body)).await?;
+        Ok(())
+    }
+
+    async fn action(&self, action_type: FcActionType) -> Result<(), VirtualGhostError> {
+        self.send("PUT", "/actions", &to_value(&FcAction { action_type })?)
+            .await
+    }
+
+    async fn set_state(&self, state: FcVmState) -> Result<(), VirtualGhostError> {
+        self.send("PATCH", "/vm", &to_value(&FcVmStateChange { state })?)
+            .await
+    }
+}
+
+#[async_trait]
+impl VmControl for FcApiClient {
+    /// Send Ctrl+Alt+Del, which the guest answers by rebooting; with `reboot=k`
+    /// that ends Firecracker.
+    async fn power_button(&self) -> Result<(), VirtualGhostError> {
+        self.action(FcActionType::SendCtrlAltDel).await
+    }
+
+    async fn pause(&self) -> Result<(), VirtualGhostError> {
+        self.set_state(FcVmState::Paused).await
+    }
+
+    async fn resume(&self) -> Result<(), VirtualGhostError> {
+        self.set_state(FcVmState::Resumed).await
+    }
+
+    async fn info(&self) -> Result<VmInfo, VirtualGhostError> {
+        let state = match self.instance_info().await?.state.as_str() {
+            "Not started" => VmState::Created,
+            "Running" => VmState::Running,
+            "Paused" => VmState::Paused,
+            other => {
+                return Err(VmError::Api(format!("unknown Firecracker state {other:?}")).into())
+            }
+        };
+        Ok(VmInfo { state })
+    }
+}
+
+/// What Firecracker is configured with before the guest starts.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirecrackerConfig {
+    pub machine: FcMachineConfig,
+    pub boot_source: FcBootSource,
+    pub drives: Vec<FcDrive>,
+    pub vsock: Option<FcVsock>,
+}
+
+impl FirecrackerConfig {
+    /// The `PUT` requests applying this configuration, in order.
+    pub fn requests(&self) -> Result<Vec<(String, Value)>, VirtualGhostError> {
+        let mut requests = vec![
+            ("/machine-config".to_string(), to_value(&self.machine)?),
+            ("/boot-source".to_string(), to_value(&self.boot_source)?),
+        ];
+        for drive in &self.drives {
+            requests.push((format!("/drives/{}", drive.drive_id), to_value(drive)?));
+        }
+        if let Some(vsock) = &self.vsock {
+            requests.push(("/vsock".to_string(), to_value(vsock)?));
+        }
+        Ok(requests)
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value, VirtualGhostError> {
+    serde_json::to_value(value).map_err(|e| VmError::Api(e.to_string()).into())
+}
+
+/// A Firecracker process. The VM is configured and started over its API once
+/// the socket answers.
+pub struct Firecracker {
+    child: ChildGuard,
+    api: FcApiClient,
+    vm: FirecrackerConfig,
+    vsock_socket: PathBuf,
+    /// Whether the guest was started, so the API can be used to control it.
+    started: bool,
+}
+
+impl Firecracker {
+    /// The configuration for the VM described by `config`, or why Firecracker
+    /// cannot run it.
+    ///
+    /// Firecracker only boots raw disk images, and has no firmware, display,
+    /// fw_cfg or PCI devices: the guest is reached over hybrid
+    /// vsock, a Unix socket in `instance`, and finds its fw_cfg files on a
+    /// second, read-only drive.
+    pub fn vm_config(
+        config: &QemuConfig,
+        instance: &InstanceDir,
+    ) -> Result<FirecrackerConfig, VirtualGhostError> {
+        let unsupported = |what: &str| -> VirtualGhostError {
+            VmError::Unsupported(format!("Firecracker {what}")).into()
+        };
+        if !config.gpu_passthrough.is_empty() {
+            return Err(unsupported("has no PCI devices to pass a GPU through"));
+        }
+        if config.rootfs_format != "raw" {
+            return Err(unsupported(&format!(
+                "only boots raw disk images, and {} is {}",
+                config.rootfs_path, config.rootfs_format
+            )));
+        }
+
+        let mut boot_args = format!("{} {BOOT_ARGS}", config.cmdline);
+        let mut drives = vec![FcDrive {
+            drive_id: "rootfs".into(),
+            path_on_host: config.rootfs_path.clone(),
+            is_root_device: true,
+            is_read_only: false,
+        }];
+        if !config.fw_cfg.is_empty() {
+            drives.push(FcDrive {
+                drive_id: "fw_cfg".into(),
+                path_on_host: instance.fw_cfg_drive().display().to_string(),
+                is_root_device: false,
+                is_read_only: true,
+            });
+            // The root device is attached first, so this drive comes second
+            boot_args.push_str(&format!(" {}=vdb", fw_cfg_drive::DEVICE_PARAM));
+        }
+        Ok(FirecrackerConfig {
+            machine: FcMachineConfig {
+                vcpu_count: config.cpus.boot_vcpus,
+                mem_size_mib: config.memory_mib,
+                smt: false,
+            },
+            boot_source: FcBootSource {
+                kernel_image_path: config.kernel_path.clone(),
+                boot_args: Some(boot_args),
+                initrd_path: config.initramfs_path.clone(),
+            },
+            drives,
+            vsock: config.vsock_cid.map(|cid| FcVsock {
+                guest_cid: cid as u32,
+                uds_path: instance.vsock_socket().display().to_string(),
+            }),
+        })
+    }
+
+    /// Arguments starting the VMM with only its API socket; the VM comes over the API.
+    pub fn args(instance: &InstanceDir) -> Vec<String> {
+        vec![
+            "--api-sock".into(),
+            instance.api_socket().display().to_string(),
+        ]
+    }
+}
+
+#[async_trait]
+impl Hypervisor for Firecracker {
+    async fn spawn(config: &QemuConfig, instance: &InstanceDir) -> Result<Self, VirtualGhostError> {
+        let vm = Self::vm_config(config, instance)?;
+        if config.display != DisplayMode::None {
+            warn!("Firecracker has no display; use `virtualghost shell` or `exec`");
+        }
+        if config.cpus.max_vcpus > config.cpus.boot_vcpus || config.memory_hotplug() {
+            warn!("Firecracker cannot hot-add vCPUs or memory; the VM keeps its boot size");
+        }
+        if !config.extra_args.is_empty() {
+            warn!("Extra QEMU arguments are ignored with Firecracker");
+        }
+        if !config.fw_cfg.is_empty() {
+            fw_cfg_drive::write(&config.fw_cfg, &instance.fw_cfg_drive())?;
+        }
+
+        let args = Self::args(instance);
+        info!(bin = %config.qemu_bin.display(), args = ?args, "Spawning Firecracker");
+        let _ = std::fs::remove_file(instance.api_socket());
+        let _ = std::fs::remove_file(instance.vsock_socket());
+
+        // The guest's serial console is Firecracker's stdout
+        let serial_log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(instance.serial_log())?;
+        let mut cmd = Command::new(&config.qemu_bin);
+        cmd.args(&args)
+            .stdin(std::process::Stdio::null())
+            .stdout(serial_log)
+            // Like QEMU, out of the terminal's process group: Ctrl-C is the launcher's to handle
+            .process_group(0);
+        let child = cmd
+            .spawn()
+            .map_err(|e| VmError::VmmSpawnFailed(config.qemu_bin.display().to_string(), e))?;
+        info!(pid = child.id(), "Firecracker process started");
+
+        Ok(Self {
+            child: ChildGuard::new(child),
+            api: FcApiClient::new(instance.api_socket()),
+            vm,
+            vsock_socket: instance.vsock_socket(),
+            started: false,
+        })
+    }
+
+    fn kind(&self) -> HypervisorKind {
+        HypervisorKind::Firecracker
+    }
+
+    fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    async fn wait_ready(&mut self, timeout: Duration) -> Result<(), VirtualGhostError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let version = loop {
+            match self.api.instance_info().await {
+                Ok(info) => break info.vmm_version,
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(e) => debug!(error = %e, "Firecracker API not ready yet"),
+            }
+            if let Some(status) = self.child.try_wait()? {
+                return Err(VmError::ProcessExited(status.code()).into());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+        info!(version, "Firecracker API ready");
+
+        for (path, body) in self.vm.requests()? {
+            self.api.send("PUT", &path, &body).await?;
+        }
+        self.api.action(FcActionType::InstanceStart).await?;
+        self.started = true;
+        info!("Firecracker VM started");
+        Ok(())
+    }
+
+    fn agent_endpoint(&self, vsock_port: u32) -> Option<GuestEndpoint> {
+        self.vm.vsock.as_ref().map(|_| GuestEndpoint::HybridVsock {
+            socket: self.vsock_socket.clone(),
+            port: vsock_port,
+        })
+    }
+
+    fn control(&self) -> Option<&dyn VmControl> {
+        self.started.then_some(&self.api as &dyn VmControl)
+    }
+
+    async fn wait(&mut self) -> Result<ExitStatus, VirtualGhostError> {
+        self.child
+            .wait()
+            .await
+            .map_err(|e| VmError::VmmSpawnFailed("Firecracker".into(), e).into())
+    }
+
+    async fn kill(&mut self) -> Result<(), VirtualGhostError> {
+        self.child
+            .kill()
+            .await
+            .map_err(|e| VmError::VmmSpawnFailed("Firecracker".into(), e).into())
+    }
+
+    fn detach(mut self: Box<Self>) -> Option<u32> {
+        self.child.detach()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fw_cfg_drive_is_named_on_the_kernel_command_line() {
+        let mut config = QemuConfig::new(
+            "firecracker".into(),
+            2,
+            1024,
+            "/boot/vmlinux",
+            "/disk/rootfs.ext4",
+        );
+        let instance = InstanceDir::new("fc-test");
+        let vm = Firecracker::vm_config(&config, &instance).unwrap();
+        assert_eq!(vm.drives.len(), 1);
+        assert!(!vm.boot_source.boot_args.unwrap().contains("fw_cfg"));
+
+        config
+            .fw_cfg
+            .push(("opt/virtualghost/authorized_keys".into(), "/keys".into()));
+        let vm = Firecracker::vm_config(&config, &instance).unwrap();
+        let ids: Vec<_> = vm
+            .drives
+            .iter()
+            .map(|drive| drive.drive_id.as_str())
+            .collect();
+        assert_eq!(ids, ["rootfs", "fw_cfg"]);
+        assert!(vm.drives[0].is_root_device);
+        assert_eq!(
+            vm.boot_source.boot_args.unwrap(),
+            format!(
+                "{} {BOOT_ARGS} virtualghost.fw_cfg_drive=vdb",
+                config.cmdline
+            )
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// First bytes of the drive, which the guest agent checks before reading it.
+const MAGIC: &[u8] = b"VGFWCFG1\n";
+
+/// Serial of the drive where the VMM can set one (Cloud Hypervisor), which the
+/// guest agent looks for in `/sys/block/vd*/serial`.
+pub const SERIAL: &str = "fw_cfg";
+
+/// Kernel parameter naming the drive's block device, for VMMs that give it no
+/// serial of our choosing (Firecracker).
+pub const DEVICE_PARAM: &str = "virtualghost.fw_cfg_drive";
+
+/// Block devices come in whole sectors.
+const SECTOR: usize = 512;
+
+/// Write the fw_cfg files a VMM without fw_cfg cannot pass (Cloud Hypervisor,
+/// Firecracker) to a raw image attached as a read-only drive instead.
+///
+/// After the magic, each entry is `<name> <length>\n`, the content and a
+/// newline; a blank line ends the list and zeros pad it to a whole sector.
+pub fn write(entries: &[(String, PathBuf)], path: &Path) -> std::io::Result<()> {
+    let mut image = MAGIC.to_vec();
+    for (name, file) in entries {
+        let content = std::fs::read(file)?;
+        image.extend_from_slice(format!("{name} {}\n", content.len()).as_bytes());
+        image.extend_from_slice(&content);
+        image.push(b'\n');
+    }
+    image.push(b'\n');
+    image.resize(image.len().div_ceil(SECTOR) * SECTOR, 0);
+
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(&image)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the entries back the way the guest agent does.
+    fn entries(image: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut rest = image.strip_prefix(MAGIC).expect("magic");
+        let mut entries = Vec::new();
+        while rest[0] != b'\n' {
+            let newline = rest.iter().position(|&byte| byte == b'\n').unwrap();
+            let header = std::str::from_utf8(&rest[..newline]).unwrap();
+            let (name, length) = header.split_once(' ').unwrap();
+            let length: usize = length.parse().unwrap();
+            let content = &rest[newline + 1..newline + 1 + length];
+            assert_eq!(rest[newline + 1 + length], b'\n');
+            entries.push((name.to_string(), content.to_vec()));
+            rest = &rest[newline + 2 + length..];
+        }
+        assert!(rest[1..].iter().all(|&byte| byte == 0), "padding is zeros");
+        entries
+    }
+
+    #[test]
+    fn entries_round_trip_through_the_drive() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("key");
+        let empty = dir.path().join("empty");
+        let awkward = dir.path().join("awkward");
+        std::fs::write(&key, "ssh-ed25519 AAAA host\n").unwrap();
+        std::fs::write(&empty, "").unwrap();
+        std::fs::write(&awkward, b"two\n\nlines and a \0 byte").unwrap();
+        let input = vec![
+            ("opt/virtualghost/ssh_key".to_string(), key),
+            ("opt/virtualghost/empty".to_string(), empty),
+            ("opt/virtualghost/awkward".to_string(), awkward),
+        ];
+
+        let drive = dir.path().join("fw_cfg.img");
+        write(&input, &drive).unwrap();
+        let image = std::fs::read(&drive).unwrap();
+
+        assert_eq!(image.len() % SECTOR, 0);
+        let expected: Vec<_> = input
+            .iter()
+            .map(|(name, file)| (name.clone(), std::fs::read(file).unwrap()))
+            .collect();
+        assert_eq!(entries(&image), expected);
+    }
+
+    #[test]
+    fn an_empty_list_is_one_sector() {
+        let dir = tempfile::tempdir().unwrap();
+        let drive = dir.path().join("fw_cfg.img");
+        write(&[], &drive).unwrap();
+        let image = std::fs::read(&drive).unwrap();
+        assert_eq!(image.len(), SECTOR);
+        assert!(entries(&image).is_empty());
+    }
+
+    #[test]
+    fn missing_files_fail_without_a_drive() {
+        let dir = tempfile::tempdir().unwrap();
+        let drive = dir.path().join("fw_cfg.img");
+        let input = [("opt/missing".to_string(), dir.path().join("missing"))];
+        assert!(write(&input, &drive).is_err());
+        assert!(!drive.exists());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::error::{VirtualGhostError, VmError};
+use serde_json::{Map, Value};
+use tracing::info;
+
+use super::balloon::MIB;
+use super::qmp::{HotpluggableCpu, MemoryDevice, QmpClient};
+use super::suspend::MachineSignature;
+
+/// Linux onlines memory in blocks of this size, so DIMMs come in multiples of it.
+pub const DIMM_ALIGN_MIB: u32 = 128;
+
+/// QOM prefix of devices added with `device_add`.
+const PERIPHERAL: &str = "/machine/peripheral/";
+
+/// vCPUs of a VM: how many are plugged, and how many it can have.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuCount {
+    pub present: u32,
+    pub max: u32,
+}
+
+pub async fn cpu_count(qmp: &QmpClient) -> Result<CpuCount, VirtualGhostError> {
+    let slots = qmp.query_hotpluggable_cpus().await?;
+    Ok(CpuCount {
+        present: slots.iter().filter(|cpu| cpu.qom_path.is_some()).count() as u32,
+        max: slots.len() as u32,
+    })
+}
+
+/// Plug `count` more vCPUs into free slots. The guest agent brings them online.
+pub async fn add_vcpus(qmp: &QmpClient, count: u32) -> Result<(), VirtualGhostError> {
+    let slots = qmp.query_hotpluggable_cpus().await?;
+    for cpu in free_cpu_slots(&slots, count)? {
+        // Named after the slot's topology, which no other vCPU shares
+        let id = std::iter::once("vcpu".to_string())
+            .chain(cpu.props.values().map(|value| value.to_string()))
+            .collect::<Vec<_>>()
+            .join("-");
+        qmp.device_add(&cpu.driver, &id, cpu.props.clone()).await?;
+        info!(id, "vCPU hot-added");
+    }
+    Ok(())
+}
+
+/// Memory in hot-added DIMMs, in bytes.
+pub async fn dimm_memory(qmp: &QmpClient) -> Result<u64, VirtualGhostError> {
+    Ok(qmp
+        .query_memory_devices()
+        .await?
+        .iter()
+        .map(|dimm| dimm.size)
+        .sum())
+}
+
+/// The free slots `count` more vCPUs go into.
+fn free_cpu_slots(
+    slots: &[HotpluggableCpu],
+    count: u32,
+) -> Result<Vec<&HotpluggableCpu>, VirtualGhostError> {
+    let free: Vec<_> = slots.iter().filter(|cpu| cpu.qom_path.is_none()).collect();
+    if free.is_empty() {
+        return Err(VmError::Hotplug(
+            "the VM has no free vCPU slots (start it with --max-vcpus above --vcpus)".into(),
+        )
+        .into());
+    }
+    if free.len() < count as usize {
+        return Err(VmError::Hotplug(format!(
+            "only {} more vCPUs fit (start the VM with a higher --max-vcpus)",
+            free.len()
+        ))
+        .into());
+    }
+    Ok(free.into_iter().take(count as usize).collect())
+}
+
+/// Plug a DIMM of `size_mib`, backed by fresh host RAM. `machine` bounds it
+/// to the slots and maximum memory the VM was started with, when known.
+pub async fn add_dimm(
+    qmp: &QmpClient,
+    size_mib: u32,
+    machine: Option<&MachineSignature>,
+) -> Result<(), VirtualGhostError> {
+    let dimms = qmp.query_memory_devices().await?;
+    let id = next_dimm(size_mib, machine, &dimms)?;
+    let backend = format!("{id}-mem");
+
+    let mut memory = Map::new();
+    memory.insert("size".into(), Value::from(u64::from(size_mib) * MIB));
+    qmp.object_add("memory-backend-ram", &backend, memory)
+        .await?;
+
+    let mut dimm = Map::new();
+    dimm.insert("memdev".into(), Value::from(backend.clone()));
+    if let Err(e) = qmp.device_add("pc-dimm", &id, dimm).await {
+        // Out of slots or beyond maxmem: don't leave the backing memory allocated
+        let _ = qmp.object_del(&backend).await;
+        return Err(e);
+    }
+    info!(id, size_mib, "DIMM hot-added");
+    Ok(())
+}
+
+/// The ID for a DIMM of `size_mib` next to the `dimms` already plugged, if it fits.
+fn next_dimm(
+    size_mib: u32,
+    machine: Option<&MachineSignature>,
+    dimms: &[MemoryDevice],
+) -> Result<String, VirtualGhostError> {
+    let refuse = |reason: String| -> VirtualGhostError { VmError::Hotplug(reason).into() };
+    if size_mib == 0 || !size_mib.is_multiple_of(DIMM_ALIGN_MIB) {
+        return Err(refuse(format!(
+            "DIMM size must be a multiple of {DIMM_ALIGN_MIB} MiB"
+        )));
+    }
+    if let Some(machine) = machine {
+        if machine.memory_slots == 0 || machine.max_memory_mib <= machine.memory_mib {
+            return Err(refuse(
+                "the VM has no room for DIMMs (start it with --max-memory above --memory)".into(),
+            ));
+        }
+        if dimms.len() >= machine.memory_slots as usize {
+            return Err(refuse(format!(
+                "all {} memory slots are in use",
+                machine.memory_slots
+            )));
+        }
+        let plugged: u64 = dimms.iter().map(|dimm| dimm.size).sum();
+        let room =
+            u64::from(machine.max_memory_mib - machine.memory_mib).saturating_sub(plugged / MIB);
+        if u64::from(size_mib) > room {
+            return Err(refuse(format!(
+                "only {room} MiB more fit (start the VM with a higher --max-memory)"
+            )));
+        }
+    }
+
+    let taken = |id: &str| dimms.iter().any(|dimm| dimm.id.as_deref() == Some(id));
+    let mut index = 0;
+    while taken(&format!("dimm{index}")) {
+        index += 1;
+    }
+    Ok(format!("dimm{index}"))
+}
+
+/// Whether vCPUs or memory were hot-added since boot. A saved state could
+/// not be loaded back without them.
+pub async fn has_hotplugged(qmp: &QmpClient) -> Result<bool, VirtualGhostError> {
+    let cpus = qmp.query_hotpluggable_cpus().await?;
+    let added_cpu = cpus.iter().any(|cpu| {
+        cpu.qom_path
+            .as_deref()
+            .is_some_and(|path| path.starts_with(PERIPHERAL))
+    });
+    Ok(added_cpu || !qmp.query_memory_devices().await?.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::models::CpusConfig;
+
+    /// Slots as `query-hotpluggable-cpus` lists them, the first `present` plugged.
+    fn cpu_slots(present: usize, max: usize) -> Vec<HotpluggableCpu> {
+        (0..max)
+            .map(|core| HotpluggableCpu {
+                driver: "host-x86_64-cpu".to_string(),
+                props: serde_json::json!({ "socket-id": 0, "core-id": core, "thread-id": 0 })
+                    .as_object()
+                    .cloned()
+                    .unwrap(),
+                qom_path: (core < present).then(|| format!("/machine/unattached/device[{core}]")),
+            })
+            .collect()
+    }
+
+    fn machine(memory_mib: u32, max_memory_mib: u32, memory_slots: u32) -> MachineSignature {
+        MachineSignature {
+            qemu_bin: "qemu-system-x86_64".into(),
+            accel: "kvm".to_string(),
+            cpus: CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+            },
+            memory_mib,
+            max_memory_mib,
+            memory_slots,
+            kernel: "/cache/vmlinux".to_string(),
+            rootfs: "/cache/rootfs.ext4".to_string(),
+            firmware: None,
+            devices: Vec::new(),
+        }
+    }
+
+    fn dimm(index: u32, size_mib: u64) -> MemoryDevice {
+        MemoryDevice {
+            id: Some(format!("dimm{index}")),
+            size: size_mib * MIB,
+        }
+    }
+
+    #[test]
+    fn vcpus_go_into_free_slots_up_to_max_vcpus() {
+        let slots = cpu_slots(2, 4);
+        let picked = free_cpu_slots(&slots, 2).unwrap();
+        assert_eq!(picked.len(), 2);
+        assert!(picked.iter().all(|cpu| cpu.qom_path.is_none()));
+        assert_eq!(picked[0].props["core-id"], 2);
+
+        let error = free_cpu_slots(&slots, 3).unwrap_err().to_string();
+        assert!(
+            error.ends_with("only 2 more vCPUs fit (start the VM with a higher --max-vcpus)"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn vcpus_need_max_vcpus() {
+        // Without --max-vcpus, QEMU lists only the boot vCPUs, all plugged
+        let error = free_cpu_slots(&cpu_slots(2, 2), 1).unwrap_err().to_string();
+        assert!(
+            error.ends_with(
+                "the VM has no free vCPU slots (start it with --max-vcpus above --vcpus)"
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn dimms_are_sized_in_memory_blocks() {
+        for size_mib in [0, 64, 200, 1000] {
+            let error = next_dimm(size_mib, None, &[]).unwrap_err().to_string();
+            assert!(
+                error.ends_with("DIMM size must be a multiple of 128 MiB"),
+                "{error}"
+            );
+        }
+        for size_mib in [128, 256, 1024] {
+            assert_eq!(next_dimm(size_mib, None, &[]).unwrap(), "dimm0");
+        }
+    }
+
+    #[test]
+    fn dimms_take_the_first_free_id() {
+        let machine = machine(2048, 8192, 4);
+        let dimms = [dimm(0, 512), dimm(2, 512)];
+        assert_eq!(next_dimm(512, Some(&machine), &dimms).unwrap(), "dimm1");
+    }
+
+    #[test]
+    fn dimms_stay_within_slots_and_max_memory() {
+        // Started without --max-memory
+        for machine in [machine(2048, 2048, 0), machine(2048, 2048, 4)] {
+            let error = next_dimm(512, Some(&machine), &[]).unwrap_err().to_string();
+            assert!(
+                error.ends_with(
+                    "the VM has no room for DIMMs (start it with --max-memory above --memory)"
+                ),
+                "{error}"
+            );
+        }
+
+        let machine = machine(2048, 4096, 2);
+        let error = next_dimm(128, Some(&machine), &[dimm(0, 128), dimm(1, 128)])
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("all 2 memory slots are in use"), "{error}");
+
+        let error = next_dimm(2048, Some(&machine), &[dimm(0, 512)])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.ends_with("only 1536 MiB more fit (start the VM with a higher --max-memory)"),
+            "{error}"
+        );
+        assert_eq!(
+            next_dimm(1536, Some(&machine), &[dimm(0, 512)]).unwrap(),
+            "dimm1"
+        );
+        assert_eq!(next_dimm(2048, Some(&machine), &[]).unwrap(), "dimm0");
+    }
+}
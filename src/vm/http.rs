@@ -0,0 +1,391 @@
+use crate::error::{VirtualGhostError, VmError};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tracing::debug;
+
+/// A response from a VMM's REST API.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The body as text, for error messages.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).trim().to_string()
+    }
+}
+
+/// Minimal HTTP/1.1 client for the REST APIs VMMs serve on a Unix socket,
+/// one connection per request.
+#[derive(Debug, Clone)]
+pub struct UnixHttpClient {
+    socket: PathBuf,
+}
+
+impl UnixHttpClient {
+    pub fn new(socket: PathBuf) -> Self {
+        Self { socket }
+    }
+
+    /// Send `method path` with an optional JSON body and read the response.
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<HttpResponse, VirtualGhostError> {
+        let fail = |what: &str, e: std::io::Error| {
+            VmError::Api(format!(
+                "{method} {path} on {}: {what}: {e}",
+                self.socket.display()
+            ))
+        };
+        let mut stream = UnixStream::connect(&self.socket)
+            .await
+            .map_err(|e| fail("connect", e))?;
+
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let mut request =
+            format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\n");
+        if !body.is_empty() {
+            request.push_str("Content-Type: application/json\r\n");
+        }
+        request.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        ));
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| fail("write", e))?;
+
+        // Status line and headers; the body is delimited by Content-Length,
+        // chunked, or runs until the VMM closes the connection
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| fail("read", e))?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| {
+                VmError::Api(format!("{method} {path}: malformed status line {line:?}"))
+            })?;
+        let mut length = None;
+        let mut chunked = false;
+        loop {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .await
+                .map_err(|e| fail("read", e))?;
+            let header = line.trim_end();
+            if read == 0 || header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse::<usize>().ok();
+                } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                    let value = value.trim();
+                    if !value.eq_ignore_ascii_case("chunked") {
+                        return Err(VmError::Api(format!(
+                            "{method} {path}: unsupported transfer encoding {value:?}"
+                        ))
+                        .into());
+                    }
+                    chunked = true;
+                }
+            }
+        }
+        let mut body = Vec::new();
+        match length {
+            _ if chunked => read_chunked(&mut reader, &mut body)
+                .await
+                .map_err(|e| fail("read chunked body", e))?,
+            Some(length) => {
+                body.resize(length, 0);
+                reader
+                    .read_exact(&mut body)
+                    .await
+                    .map_err(|e| fail("read body", e))?;
+            }
+            None => {
+                reader
+                    .read_to_end(&mut body)
+                    .await
+                    .map_err(|e| fail("read body", e))?;
+            }
+        }
+        debug!(method, path, status, "VMM API request");
+        Ok(HttpResponse { status, body })
+    }
+
+    /// Send a request the VMM must accept (2xx), returning the response body.
+    pub async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<Vec<u8>, VirtualGhostError> {
+        let response = self.request(method, path, body).await?;
+        if !response.is_success() {
+            return Err(VmError::Api(format!(
+                "{method} {path}: {} {}",
+                response.status,
+                response.text()
+            ))
+            .into());
+        }
+        Ok(response.body)
+    }
+
+    /// `GET path`, parsing the JSON it returns.
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, VirtualGhostError> {
+        let body = self.send("GET", path, None).await?;
+        serde_json::from_slice(&body).map_err(|e| VmError::Api(format!("GET {path}: {e}")).into())
+    }
+}
+
+/// Read a `Transfer-Encoding: chunked` body into `body`, up to the last chunk
+/// and its trailers.
+async fn read_chunked<R>(reader: &mut R, body: &mut Vec<u8>) -> std::io::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let invalid = |what: String| std::io::Error::new(std::io::ErrorKind::InvalidData, what);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let size = line.trim_end().split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid(format!("malformed chunk size {line:?}")))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+        if crlf != *b"\r\n" {
+            return Err(invalid("chunk not followed by CRLF".into()));
+        }
+    }
+    // Trailers, up to the blank line ending the message
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// A stand-in for a VMM's API socket, for tests.
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+    use tokio::task::JoinHandle;
+
+    /// A request as the server received it.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Request {
+        pub method: String,
+        pub path: String,
+        pub body: String,
+    }
+
+    /// Answers every connection with the raw HTTP response `respond` gives
+    /// for its request, recording the requests.
+    pub struct FakeApi {
+        pub socket: PathBuf,
+        requests: Arc<Mutex<Vec<Request>>>,
+        task: JoinHandle<()>,
+        _dir: tempfile::TempDir,
+    }
+
+    impl FakeApi {
+        pub fn serve(respond: impl Fn(&Request) -> String + Send + Sync + 'static) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let socket = dir.path().join("api.sock");
+            let listener = UnixListener::bind(&socket).unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let seen = requests.clone();
+            let task = tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let mut reader = BufReader::new(stream);
+                    let request = read_request(&mut reader).await;
+                    let response = respond(&request);
+                    seen.lock().unwrap().push(request);
+                    let mut stream = reader.into_inner();
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+            Self {
+                socket,
+                requests,
+                task,
+                _dir: dir,
+            }
+        }
+
+        pub fn requests(&self) -> Vec<Request> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for FakeApi {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+
+    async fn read_request(reader: &mut BufReader<tokio::net::UnixStream>) -> Request {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        let mut words = line.split_whitespace();
+        let method = words.next().unwrap_or_default().to_string();
+        let path = words.next().unwrap_or_default().to_string();
+        let mut length = 0;
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        Request {
+            method,
+            path,
+            body: String::from_utf8(body).unwrap(),
+        }
+    }
+
+    /// A complete response with a `Content-Length` body.
+    pub fn response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::{response, FakeApi, Request};
+    use super::*;
+    use crate::vm::{VmInfo, VmState};
+    use serde_json::json;
+
+    fn client(api: &FakeApi) -> UnixHttpClient {
+        UnixHttpClient::new(api.socket.clone())
+    }
+
+    #[tokio::test]
+    async fn reads_a_content_length_body() {
+        let api = FakeApi::serve(|_| response("200 OK", r#"{"ok":true}"#));
+        let reply = client(&api)
+            .request("PUT", "/api/v1/vm.create", Some(&json!({"a": 1})))
+            .await
+            .unwrap();
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body, br#"{"ok":true}"#);
+        assert_eq!(
+            api.requests(),
+            [Request {
+                method: "PUT".into(),
+                path: "/api/v1/vm.create".into(),
+                body: r#"{"a":1}"#.into(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_a_response_without_body() {
+        let api = FakeApi::serve(|_| "HTTP/1.1 204 No Content\r\n\r\n".into());
+        let body = client(&api).send("PUT", "/vm.boot", None).await.unwrap();
+        assert!(body.is_empty());
+        assert_eq!(api.requests()[0].body, "");
+    }
+
+    #[tokio::test]
+    async fn reads_until_close_without_length() {
+        let api = FakeApi::serve(|_| "HTTP/1.1 200 OK\r\n\r\nstreamed".into());
+        let body = client(&api).send("GET", "/x", None).await.unwrap();
+        assert_eq!(body, b"streamed");
+    }
+
+    #[tokio::test]
+    async fn reads_a_chunked_body() {
+        let api = FakeApi::serve(|_| {
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n"
+                .into()
+        });
+        let body = client(&api).send("GET", "/x", None).await.unwrap();
+        assert_eq!(body, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_transfer_encodings() {
+        let api = FakeApi::serve(|_| {
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n".into()
+        });
+        let error = client(&api).send("GET", "/x", None).await.unwrap_err();
+        assert!(error.to_string().contains("unsupported transfer encoding"));
+    }
+
+    #[tokio::test]
+    async fn reports_non_2xx_responses() {
+        let api = FakeApi::serve(|_| response("500 Internal Server Error", "VM not created"));
+        let http = client(&api);
+        let error = http.send("PUT", "/vm.boot", None).await.unwrap_err();
+        let message = error.to_string();
+        assert!(
+            message.contains("PUT /vm.boot: 500 VM not created"),
+            "{message}"
+        );
+        // `request` itself hands back any status
+        let reply = http.request("PUT", "/vm.boot", None).await.unwrap();
+        assert!(!reply.is_success());
+    }
+
+    #[tokio::test]
+    async fn parses_json_from_get() {
+        let api = FakeApi::serve(|_| response("200 OK", r#"{"state":"Running"}"#));
+        let info: VmInfo = client(&api).get("/vm.info").await.unwrap();
+        assert!(matches!(info.state, VmState::Running));
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_malformed_status_line() {
+        let api = FakeApi::serve(|_| "garbage\r\n\r\n".into());
+        let error = client(&api).send("GET", "/x", None).await.unwrap_err();
+        assert!(error.to_string().contains("malformed status line"));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::error::{VirtualGhostError, VmError};
+use crate::network::GuestEndpoint;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::process::ExitStatus;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use super::config::QemuConfig;
+use super::instance::{InstanceDir, InstanceState};
+use super::models::{VmInfo, VmState};
+use super::outcome::GuestEvents;
+use super::qmp::QmpClient;
+
+/// Which VMM runs the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HypervisorKind {
+    #[default]
+    Qemu,
+    CloudHypervisor,
+    Firecracker,
+}
+
+impl std::fmt::Display for HypervisorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Qemu => "QEMU",
+            Self::CloudHypervisor => "Cloud Hypervisor",
+            Self::Firecracker => "Firecracker",
+        })
+    }
+}
+
+/// Requests any invocation can send to a running VM's VMM.
+#[async_trait]
+pub trait VmControl: Send + Sync {
+    /// Press the ACPI power button, asking the guest to shut down.
+    async fn power_button(&self) -> Result<(), VirtualGhostError>;
+    /// Stop the vCPUs, keeping the guest in memory.
+    async fn pause(&self) -> Result<(), VirtualGhostError>;
+    /// Continue paused vCPUs.
+    async fn resume(&self) -> Result<(), VirtualGhostError>;
+    async fn info(&self) -> Result<VmInfo, VirtualGhostError>;
+}
+
+/// A VMM process started by this invocation, driven the same way whichever
+/// hypervisor it is.
+#[async_trait]
+pub trait Hypervisor: Send + Sync {
+    /// Start the VMM for the VM described by `config`, keeping its sockets in `instance`.
+    async fn spawn(config: &QemuConfig, instance: &InstanceDir) -> Result<Self, VirtualGhostError>
+    where
+        Self: Sized;
+
+    fn kind(&self) -> HypervisorKind;
+
+    /// OS process ID of the VMM, or `None` once it has been reaped.
+    fn id(&self) -> Option<u32>;
+
+    /// Wait until the VMM answers on its control channel and the guest is booting.
+    async fn wait_ready(&mut self, timeout: Duration) -> Result<(), VirtualGhostError>;
+
+    /// Where the guest agent's SSH server is reachable from the host.
+    fn agent_endpoint(&self, vsock_port: u32) -> Option<GuestEndpoint>;
+
+    /// Control channel to the VMM (power button, pause, info), once
+    /// [`Self::wait_ready`] has connected it.
+    fn control(&self) -> Option<&dyn VmControl>;
+
+    /// Wait for the VMM process to exit.
+    async fn wait(&mut self) -> Result<ExitStatus, VirtualGhostError>;
+
+    /// Press the power button and wait up to `grace` for the VMM to exit,
+    /// killing it if the guest does not comply in time (or there is no
+    /// control channel to ask it).
+    async fn shutdown(&mut self, grace: Duration) -> Result<ExitStatus, VirtualGhostError> {
+        let kind = self.kind();
+        let requested = match self.control() {
+            Some(control) => match control.power_button().await {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!(error = %e, "Power button request failed");
+                    false
+                }
+            },
+            None => false,
+        };
+
+        if requested {
+            info!(grace_secs = grace.as_secs(), "Waiting for guest to power off");
+            if let Ok(status) = tokio::time::timeout(grace, self.wait()).await {
+                return status;
+            }
+            warn!("Guest did not power off in time, killing {kind}");
+        } else {
+            warn!("Cannot request a clean shutdown, killing {kind}");
+        }
+
+        self.kill().await?;
+        self.wait().await
+    }
+
+    /// Kill the VMM process.
+    async fn kill(&mut self) -> Result<(), VirtualGhostError>;
+
+    /// Leave the VMM running after the launcher exits. Returns its PID.
+    fn detach(self: Box<Self>) -> Option<u32>;
+
+    /// What the VMM reported about the guest, collected once it has exited.
+    async fn events(&mut self) -> GuestEvents {
+        GuestEvents::default()
+    }
+}
+
+#[async_trait]
+impl VmControl for QmpClient {
+    async fn power_button(&self) -> Result<(), VirtualGhostError> {
+        self.system_powerdown().await
+    }
+
+    async fn pause(&self) -> Result<(), VirtualGhostError> {
+        self.stop().await
+    }
+
+    async fn resume(&self) -> Result<(), VirtualGhostError> {
+        self.cont().await
+    }
+
+    async fn info(&self) -> Result<VmInfo, VirtualGhostError> {
+        let state = match self.query_status().await?.as_str() {
+            "running" => VmState::Running,
+            "prelaunch" | "inmigrate" | "restore-vm" => VmState::Created,
+            "paused" => VmState::Paused,
+            // The guest state has moved to the migration target or file
+            "shutdown" | "postmigrate" => VmState::Shutdown,
+            "guest-panicked" | "internal-error" | "io-error" | "watchdog" => VmState::Crashed,
+            other => {
+                return Err(
+                    VmError::QmpError(format!("unexpected QEMU run state {other:?}")).into(),
+                )
+            }
+        };
+        Ok(VmInfo { state })
+    }
+}
+
+/// Start the VMM `kind` for the VM described by `config`.
+pub async fn spawn(
+    kind: HypervisorKind,
+    config: &QemuConfig,
+    instance: &InstanceDir,
+) -> Result<Box<dyn Hypervisor>, VirtualGhostError> {
+    match kind {
+        HypervisorKind::Qemu => Ok(Box::new(
+            <super::QemuProcess as Hypervisor>::spawn(config, instance).await?,
+        )),
+        #[cfg(unix)]
+        HypervisorKind::CloudHypervisor => Ok(Box::new(
+            <super::CloudHypervisor as Hypervisor>::spawn(config, instance).await?,
+        )),
+        #[cfg(unix)]
+        HypervisorKind::Firecracker => Ok(Box::new(
+            <super::Firecracker as Hypervisor>::spawn(config, instance).await?,
+        )),
+        #[cfg(not(unix))]
+        _ => Err(VmError::Unsupported(format!("{kind} only runs on Linux")).into()),
+    }
+}
+
+/// Reach the VMM of a VM running elsewhere, through the socket recorded in its state.
+pub async fn connect_control(
+    state: &InstanceState,
+) -> Result<Box<dyn VmControl>, VirtualGhostError> {
+    match state.hypervisor {
+        HypervisorKind::Qemu => {
+            let addr = state
+                .qmp
+                .as_ref()
+                .ok_or_else(|| VmError::Api("the VM has no control socket".into()))?;
+            Ok(Box::new(QmpClient::connect(addr).await?))
+        }
+        #[cfg(unix)]
+        HypervisorKind::CloudHypervisor => Ok(Box::new(super::cloud_hypervisor::ChApiClient::new(
+            api_socket(state)?,
+        ))),
+        #[cfg(unix)]
+        HypervisorKind::Firecracker => Ok(Box::new(super::firecracker::FcApiClient::new(
+            api_socket(state)?,
+        ))),
+        #[cfg(not(unix))]
+        kind => Err(VmError::Unsupported(format!("{kind} only runs on Linux")).into()),
+    }
+}
+
+#[cfg(unix)]
+fn api_socket(state: &InstanceState) -> Result<std::path::PathBuf, VirtualGhostError> {
+    state
+        .api_socket
+        .clone()
+        .ok_or_else(|| VmError::Api("the VM has no API socket".into()).into())
+}
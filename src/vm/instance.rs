@@ -0,0 +1,352 @@
+use crate::config::VirtualGhostConfig;
+use crate::error::{VirtualGhostError, VmError};
+use crate::network::GuestEndpoint;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use super::hypervisor::HypervisorKind;
+use super::process::same_process;
+use super::qmp::QmpAddress;
+use super::suspend::{MachineSignature, SuspendInfo};
+
+/// Name of the VM started by `virtualghost run`.
+pub const DEFAULT_INSTANCE: &str = "default";
+
+/// What other invocations need to reach a running VM, written once QEMU is up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceState {
+    /// QEMU's process ID.
+    pub pid: Option<u32>,
+    /// [`process_identity`](super::process::process_identity) of `pid`, so a
+    /// reused PID is not taken for the VM.
+    #[serde(default)]
+    pub pid_identity: Option<String>,
+    /// The `virtualghost run` process supervising a foreground VM; `None` once
+    /// the VM has been handed off to run in the background.
+    pub launcher_pid: Option<u32>,
+    /// Identity of `launcher_pid`, like `pid_identity`.
+    #[serde(default)]
+    pub launcher_identity: Option<String>,
+    pub qmp: Option<QmpAddress>,
+    /// Guest CID of the vhost-vsock device (Linux).
+    pub vsock_cid: Option<u32>,
+    /// Host port forwarded to the guest agent (macOS/Windows).
+    pub ssh_port: Option<u16>,
+    /// Where the guest agent's SSH server is reachable from the host.
+    pub agent: Option<GuestEndpoint>,
+    /// Private key the guest agent accepts for this boot.
+    pub identity: PathBuf,
+    /// Boot time, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// What QEMU was started with, checked again when a suspended VM resumes.
+    #[serde(default)]
+    pub machine: Option<MachineSignature>,
+    /// Which VMM runs the guest.
+    #[serde(default)]
+    pub hypervisor: HypervisorKind,
+    /// REST API socket of a VMM other than QEMU, which has `qmp` instead.
+    #[serde(default)]
+    pub api_socket: Option<PathBuf>,
+}
+
+impl InstanceState {
+    /// Whether QEMU is still running; a missing PID means it never started.
+    pub fn is_alive(&self) -> bool {
+        self.pid
+            .is_some_and(|pid| same_process(pid, self.pid_identity.as_deref()))
+    }
+
+    /// The launcher supervising the VM, if it is still running.
+    pub fn live_launcher(&self) -> Option<u32> {
+        self.launcher_pid
+            .filter(|pid| same_process(*pid, self.launcher_identity.as_deref()))
+    }
+}
+
+/// Per-VM directory holding the credentials handed to the guest, the serial
+/// log, the QMP socket and the runtime state.
+pub struct InstanceDir {
+    name: String,
+    path: PathBuf,
+}
+
+impl InstanceDir {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            path: VirtualGhostConfig::instances_dir().join(name),
+        }
+    }
+
+    /// Instance directories that exist on disk, running or not, sorted by name.
+    pub fn list() -> Result<Vec<Self>, VirtualGhostError> {
+        let root = VirtualGhostConfig::instances_dir();
+        let entries = match std::fs::read_dir(&root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names.iter().map(|name| Self::new(name)).collect())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Host name used for this VM in OpenSSH configuration.
+    pub fn ssh_alias(&self) -> String {
+        if self.name == DEFAULT_INSTANCE {
+            "virtualghost".to_string()
+        } else {
+            format!("virtualghost-{}", self.name)
+        }
+    }
+
+    /// Create the directory, readable only by the current user.
+    pub fn create(&self) -> Result<(), VirtualGhostError> {
+        std::fs::create_dir_all(&self.path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(())
+    }
+
+    pub fn qmp_socket(&self) -> PathBuf {
+        self.path.join("qmp.sock")
+    }
+
+    /// Where the serial console logger listens for QEMU to connect.
+    pub fn serial_socket(&self) -> PathBuf {
+        self.path.join("serial.sock")
+    }
+
+    /// Where `virtualghost console` attaches to the serial console.
+    pub fn console_socket(&self) -> PathBuf {
+        self.path.join("console.sock")
+    }
+
+    /// Second QMP monitor, free for other invocations while the launcher holds the first.
+    pub fn control_socket(&self) -> PathBuf {
+        self.path.join("control.sock")
+    }
+
+    /// REST API of a Cloud Hypervisor VMM.
+    pub fn api_socket(&self) -> PathBuf {
+        self.path.join("api.sock")
+    }
+
+    /// Host end of the guest's vsock device, for VMMs with hybrid vsock.
+    pub fn vsock_socket(&self) -> PathBuf {
+        self.path.join("vsock.sock")
+    }
+
+    /// Copy-on-write disk the VM boots from, discarded when it exits.
+    pub fn overlay(&self, format: &str) -> PathBuf {
+        self.path.join(format!("overlay.{}", image_extension(format)))
+    }
+
+    /// Disk kept across boots with `--persist`, only ever removed by hand.
+    pub fn persistent_disk(&self, format: &str) -> PathBuf {
+        self.path.join(format!("disk.{}", image_extension(format)))
+    }
+
+    /// RAM and device state of a suspended VM.
+    pub fn suspend_image(&self) -> PathBuf {
+        self.path.join("suspend.img")
+    }
+
+    fn suspend_info_file(&self) -> PathBuf {
+        self.path.join("suspend.json")
+    }
+
+    /// UEFI variable store (boot entries, firmware settings), kept across boots.
+    pub fn efi_vars(&self) -> PathBuf {
+        self.path.join("efi_vars.fd")
+    }
+
+    pub fn serial_log(&self) -> PathBuf {
+        self.path.join("serial.log")
+    }
+
+    /// Ephemeral client key, used when `ssh.key_path` is not configured.
+    pub fn identity(&self) -> PathBuf {
+        self.path.join("id_ed25519")
+    }
+
+    pub fn authorized_keys(&self) -> PathBuf {
+        self.path.join("authorized_keys")
+    }
+
+    /// Private host key passed to the guest agent via fw_cfg.
+    pub fn host_key(&self) -> PathBuf {
+        self.path.join("ssh_host_ed25519_key")
+    }
+
+    /// Where the guest agent sends its events, passed to it via fw_cfg.
+    pub fn agent_events(&self) -> PathBuf {
+        self.path.join("agent_events")
+    }
+
+    /// The files otherwise passed via fw_cfg, as a drive for VMMs without fw_cfg.
+    pub fn fw_cfg_drive(&self) -> PathBuf {
+        self.path.join("fw_cfg.img")
+    }
+
+    /// Public half of [`Self::host_key`], pinned when connecting to the guest.
+    pub fn host_key_pub(&self) -> PathBuf {
+        self.path.join("ssh_host_ed25519_key.pub")
+    }
+
+    /// `known_hosts` pinning the current host key under [`Self::ssh_alias`].
+    pub fn known_hosts(&self) -> PathBuf {
+        self.path.join("known_hosts")
+    }
+
+    fn state_file(&self) -> PathBuf {
+        self.path.join("state.json")
+    }
+
+    pub fn save_state(&self, state: &InstanceState) -> Result<(), VirtualGhostError> {
+        let json = serde_json::to_string_pretty(state)
+            .map_err(|e| VmError::InstanceState(e.to_string()))?;
+        std::fs::write(self.state_file(), json)?;
+        Ok(())
+    }
+
+    /// Read the state of a running VM; fails with [`VmError::NotRunning`] if there is none.
+    pub fn load_state(&self) -> Result<InstanceState, VirtualGhostError> {
+        let json = match std::fs::read_to_string(self.state_file()) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(VmError::NotRunning(self.name.clone()).into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&json).map_err(|e| {
+            VmError::InstanceState(format!("{}: {e}", self.state_file().display())).into()
+        })
+    }
+
+    /// State of the VM if its QEMU process is still alive.
+    ///
+    /// Entries left behind by a QEMU that died without cleaning up (crash,
+    /// host reboot, `kill -9` of the launcher) are removed on the way.
+    pub fn running_state(&self) -> Result<Option<InstanceState>, VirtualGhostError> {
+        let state = match self.load_state() {
+            Ok(state) => state,
+            Err(VirtualGhostError::Vm(VmError::NotRunning(_))) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if state.is_alive() {
+            return Ok(Some(state));
+        }
+        debug!(instance = %self.name, pid = ?state.pid, "Removing stale instance state");
+        self.clear_runtime();
+        Ok(None)
+    }
+
+    /// Whether the VM was suspended and can be resumed.
+    pub fn is_suspended(&self) -> bool {
+        self.suspend_info_file().exists()
+    }
+
+    pub fn save_suspend(&self, info: &SuspendInfo) -> Result<(), VirtualGhostError> {
+        let json = serde_json::to_string_pretty(info)
+            .map_err(|e| VmError::InstanceState(e.to_string()))?;
+        std::fs::write(self.suspend_info_file(), json)?;
+        Ok(())
+    }
+
+    /// Read what was saved with a suspended VM; fails with [`VmError::NotSuspended`] if there is none.
+    pub fn load_suspend(&self) -> Result<SuspendInfo, VirtualGhostError> {
+        let json = match std::fs::read_to_string(self.suspend_info_file()) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(VmError::NotSuspended(self.name.clone()).into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&json).map_err(|e| {
+            VmError::InstanceState(format!("{}: {e}", self.suspend_info_file().display())).into()
+        })
+    }
+
+    /// Drop a suspended VM's saved state, once resumed or when discarding it.
+    pub fn clear_suspend(&self) {
+        remove_files([self.suspend_info_file(), self.suspend_image()]);
+    }
+
+    /// Remove the runtime state, per-boot credentials and disk overlay once QEMU is gone.
+    ///
+    /// The serial log is kept for post-mortem inspection. A suspended guest
+    /// still trusts its credentials, needs its disk and sends its events where
+    /// it was told to at boot, so they stay until it is resumed or discarded.
+    pub fn clear_runtime(&self) {
+        remove_files([
+            self.state_file(),
+            self.qmp_socket(),
+            self.control_socket(),
+            self.serial_socket(),
+            self.console_socket(),
+            self.api_socket(),
+            self.vsock_socket(),
+        ]);
+        if !self.is_suspended() {
+            self.clear_boot_files();
+        }
+    }
+
+    fn clear_boot_files(&self) {
+        remove_files([
+            self.identity(),
+            self.authorized_keys(),
+            self.host_key(),
+            self.host_key_pub(),
+            self.known_hosts(),
+            self.fw_cfg_drive(),
+            self.agent_events(),
+            self.overlay("qcow2"),
+            self.overlay("raw"),
+        ]);
+    }
+
+    /// Discard a suspended VM: its saved state, the credentials it trusts and
+    /// its disk overlay.
+    pub fn discard_suspended(&self) {
+        self.clear_suspend();
+        self.clear_boot_files();
+    }
+}
+
+/// File extension for a disk image in `format`.
+fn image_extension(format: &str) -> &str {
+    match format {
+        "raw" => "img",
+        other => other,
+    }
+}
+
+fn remove_files(paths: impl IntoIterator<Item = PathBuf>) {
+    for path in paths {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => debug!(path = %path.display(), error = %e, "Failed to remove file"),
+        }
+    }
+}
@@ -1,9 +1,45 @@
 mod assets;
+pub mod balloon;
+mod capabilities;
+#[cfg(unix)]
+mod cloud_hypervisor;
 mod config;
+mod console;
+#[cfg(unix)]
+mod firecracker;
+mod fw_cfg_drive;
+pub mod hotplug;
+#[cfg(unix)]
+mod http;
+pub mod hypervisor;
+mod instance;
 mod models;
+mod outcome;
+mod overlay;
 mod process;
+mod qmp;
+mod readiness;
+pub mod registry;
+mod serial;
+pub mod suspend;
 
 pub use assets::AssetManager;
-pub use config::{Accelerator, DisplayMode, QemuConfig};
+pub use capabilities::QemuCapabilities;
+#[cfg(unix)]
+pub use cloud_hypervisor::CloudHypervisor;
+#[cfg(unix)]
+pub use firecracker::Firecracker;
+pub use config::{DisplayMode, Firmware, QemuConfig};
+#[cfg(unix)]
+pub use console::serve_console;
+pub use hypervisor::{Hypervisor, HypervisorKind};
+pub use instance::{InstanceDir, InstanceState, DEFAULT_INSTANCE};
 pub use models::*;
-pub use process::QemuProcess;
+pub use outcome::VmOutcome;
+pub use overlay::{kept_backing_files, Overlay};
+pub use process::{process_identity, signal_pid, QemuProcess};
+pub use qmp::{QmpAddress, QmpClient};
+pub use readiness::wait_until_ready;
+pub use serial::SerialLog;
+#[cfg(not(unix))]
+pub use serial::record_serial;
@@ -28,7 +28,7 @@ pub struct VmCreateConfig {
     pub iommu: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u32,
     pub max_vcpus: u32,
@@ -63,8 +63,14 @@ pub struct DiskConfig {
     pub readonly: bool,
     #[serde(default)]
     pub direct: bool,
+    /// Open the backing file of a qcow2 image; off by default.
+    #[serde(default)]
+    pub backing_files: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<String>,
+    /// Serial the guest reads back from the virtio-blk device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -134,6 +140,22 @@ pub enum VmState {
     Running,
     Shutdown,
     Paused,
+    /// The guest panicked or the VMM hit an error it stopped the vCPUs for;
+    /// only QEMU reports this.
+    Crashed,
+}
+
+impl VmState {
+    /// Lowercase name, as shown by `ps`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Running => "running",
+            Self::Shutdown => "shutdown",
+            Self::Paused => "paused",
+            Self::Crashed => "crashed",
+        }
+    }
 }
 
 /// Response from GET /api/v1/vmm.ping
@@ -143,3 +165,75 @@ pub struct VmmPingResponse {
     pub version: String,
     pub pid: i64,
 }
+
+/// Firecracker: PUT /machine-config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcMachineConfig {
+    pub vcpu_count: u32,
+    pub mem_size_mib: u32,
+    #[serde(default)]
+    pub smt: bool,
+}
+
+/// Firecracker: PUT /boot-source
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcBootSource {
+    pub kernel_image_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_args: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initrd_path: Option<String>,
+}
+
+/// Firecracker: PUT /drives/{drive_id}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcDrive {
+    pub drive_id: String,
+    pub path_on_host: String,
+    pub is_root_device: bool,
+    pub is_read_only: bool,
+}
+
+/// Firecracker: PUT /vsock
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcVsock {
+    pub guest_cid: u32,
+    /// Host Unix socket for hybrid vsock connections
+    pub uds_path: String,
+}
+
+/// Firecracker: PUT /actions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcAction {
+    pub action_type: FcActionType,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FcActionType {
+    InstanceStart,
+    /// Keyboard Ctrl+Alt+Del (x86 only); the guest reboots, which ends the VMM
+    SendCtrlAltDel,
+    FlushMetrics,
+}
+
+/// Firecracker: PATCH /vm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcVmStateChange {
+    pub state: FcVmState,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FcVmState {
+    Paused,
+    Resumed,
+}
+
+/// Firecracker: response from GET /
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FcInstanceInfo {
+    pub id: String,
+    /// "Not started", "Running" or "Paused"
+    pub state: String,
+    pub vmm_version: String,
+    pub app_name: String,
+}
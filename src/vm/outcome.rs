@@ -0,0 +1,277 @@
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use super::qmp::QmpClient;
+use super::serial;
+
+/// How long to wait for QMP to deliver the events QEMU sent before exiting.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serial console lines included in the report of an abnormal exit.
+const SERIAL_TAIL_LINES: usize = 20;
+
+/// Exit status of the launcher when the guest kernel panicked.
+pub const EXIT_GUEST_PANIC: i32 = 3;
+
+/// Exit status of the launcher when QEMU itself failed.
+pub const EXIT_QEMU_CRASH: i32 = 4;
+
+/// Exit status of the launcher when Ghostty failed and the guest powered off.
+pub const EXIT_GHOSTTY_FAILED: i32 = 5;
+
+/// Lifecycle events QEMU reported over QMP, and Ghostty's exit status from the guest agent.
+#[derive(Debug, Clone, Default)]
+pub struct GuestEvents {
+    /// `SHUTDOWN`: whether the guest initiated it, and QEMU's reason.
+    pub shutdown: Option<(bool, String)>,
+    /// A `GUEST_PANICKED` event arrived (via the pvpanic device).
+    pub panicked: bool,
+    /// Number of `RESET` events.
+    pub resets: u32,
+    /// Ghostty's exit status, as the guest agent reported it.
+    pub ghostty_exit: Option<i32>,
+}
+
+/// Records `SHUTDOWN`, `GUEST_PANICKED` and `RESET` events for the lifetime of a VM,
+/// and logs pauses.
+pub struct EventRecorder {
+    events: Arc<Mutex<GuestEvents>>,
+    task: JoinHandle<()>,
+}
+
+impl EventRecorder {
+    pub fn start(qmp: &QmpClient) -> Self {
+        let events = Arc::new(Mutex::new(GuestEvents::default()));
+        let mut rx = qmp.subscribe();
+        let record = events.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let guest = event.data["guest"].as_bool().unwrap_or(false);
+                let reason = event.data["reason"].as_str().unwrap_or("unknown");
+                let mut record = record.lock().unwrap();
+                match event.event.as_str() {
+                    "SHUTDOWN" => {
+                        info!(guest, reason, "Guest shut down");
+                        record.shutdown = Some((guest, reason.to_string()));
+                    }
+                    "GUEST_PANICKED" => {
+                        let action = event.data["action"].as_str().unwrap_or("unknown");
+                        error!(action, "Guest kernel panicked");
+                        record.panicked = true;
+                    }
+                    "RESET" => {
+                        warn!(guest, reason, "Guest reset");
+                        record.resets += 1;
+                    }
+                    "STOP" => info!("VM paused"),
+                    "RESUME" => info!("VM resumed"),
+                    _ => {}
+                }
+            }
+        });
+        Self { events, task }
+    }
+
+    /// Collect the recorded events once QEMU has exited.
+    ///
+    /// Waits for `qmp` to reach end-of-stream so events sent right before the
+    /// exit are not missed, then closes it.
+    pub async fn finish(self, qmp: Option<QmpClient>) -> GuestEvents {
+        if let Some(qmp) = qmp {
+            let deadline = tokio::time::Instant::now() + DRAIN_TIMEOUT;
+            while !qmp.is_closed() && tokio::time::Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        // With the client gone the event channel closes and the task drains it
+        if tokio::time::timeout(DRAIN_TIMEOUT, self.task)
+            .await
+            .is_err()
+        {
+            warn!("Timed out collecting QMP events");
+        }
+        let events = self.events.lock().unwrap().clone();
+        events
+    }
+}
+
+/// How a VM run ended, from QEMU's exit status and the events it reported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmOutcome {
+    /// The guest powered itself off, normally because Ghostty exited.
+    Poweroff,
+    /// Ghostty exited with this non-zero status and the guest powered off.
+    GhosttyFailed(i32),
+    /// The VM was shut down from the host (Ctrl-C, `virtualghost stop`, a signal to QEMU).
+    Stopped(Option<String>),
+    /// The guest kernel panicked.
+    GuestPanic,
+    /// QEMU exited with an error of its own.
+    QemuCrash(Option<i32>),
+    /// QEMU was killed by a signal.
+    Killed(i32),
+    /// The VM state was saved to disk by `virtualghost suspend`.
+    Suspended,
+}
+
+impl VmOutcome {
+    /// Classify a finished run. `stop_requested` is set when the launcher itself
+    /// shut the VM down, whichever way QEMU ended up exiting.
+    pub fn classify(status: ExitStatus, events: &GuestEvents, stop_requested: bool) -> Self {
+        if events.panicked {
+            return Self::GuestPanic;
+        }
+        if stop_requested {
+            return Self::Stopped(None);
+        }
+        if let Some(signal) = exit_signal(status) {
+            return Self::Killed(signal);
+        }
+        if !status.success() {
+            return Self::QemuCrash(status.code());
+        }
+        match (&events.shutdown, events.ghostty_exit) {
+            (Some((false, reason)), _) => Self::Stopped(Some(reason.clone())),
+            (_, Some(code)) if code != 0 => Self::GhosttyFailed(code),
+            // A guest shutdown, or a clean exit without QMP to tell us more
+            _ => Self::Poweroff,
+        }
+    }
+
+    /// Exit status for the launcher: 0 for a normal end, distinct codes otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Poweroff | Self::Stopped(_) | Self::Suspended => 0,
+            Self::GuestPanic => EXIT_GUEST_PANIC,
+            Self::QemuCrash(_) => EXIT_QEMU_CRASH,
+            Self::GhosttyFailed(_) => EXIT_GHOSTTY_FAILED,
+            Self::Killed(signal) => 128 + signal,
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.exit_code() == 0
+    }
+
+    /// One-line description of the outcome.
+    pub fn summary(&self) -> String {
+        match self {
+            Self::Poweroff => "The guest powered off".to_string(),
+            Self::GhosttyFailed(code) => {
+                format!("Ghostty exited with status {code} and the guest powered off")
+            }
+            Self::Stopped(None) => "The VM was stopped".to_string(),
+            Self::Stopped(Some(reason)) => format!("The VM was stopped by the host ({reason})"),
+            Self::GuestPanic => "The guest kernel panicked".to_string(),
+            Self::QemuCrash(Some(code)) => format!("QEMU failed with exit code {code}"),
+            Self::QemuCrash(None) => "QEMU failed".to_string(),
+            Self::Killed(signal) => format!("QEMU was killed by signal {signal}"),
+            Self::Suspended => "The VM was suspended".to_string(),
+        }
+    }
+
+    /// The summary, followed by the end of the serial console for abnormal exits.
+    pub fn report(&self, serial_log: &Path) -> String {
+        let mut report = self.summary();
+        if self.is_clean() {
+            return report;
+        }
+        let tail = serial::tail_lines(serial_log, SERIAL_TAIL_LINES);
+        if tail.is_empty() {
+            report.push_str(" (the serial console log is empty)");
+        } else {
+            report.push_str(". Last serial console lines:");
+            for line in tail {
+                report.push_str("\n  ");
+                report.push_str(&line);
+            }
+        }
+        report
+    }
+}
+
+#[cfg(unix)]
+fn exit_signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[test]
+    fn ghostty_failure_decides_a_guest_poweroff() {
+        let mut events = GuestEvents {
+            shutdown: Some((true, "guest-shutdown".into())),
+            ghostty_exit: Some(0),
+            ..GuestEvents::default()
+        };
+        assert_eq!(
+            VmOutcome::classify(exited(0), &events, false),
+            VmOutcome::Poweroff
+        );
+
+        events.ghostty_exit = Some(1);
+        let outcome = VmOutcome::classify(exited(0), &events, false);
+        assert_eq!(outcome, VmOutcome::GhosttyFailed(1));
+        assert_eq!(outcome.exit_code(), EXIT_GHOSTTY_FAILED);
+
+        // Stopping the VM from the host is not Ghostty's failure
+        assert_eq!(
+            VmOutcome::classify(exited(0), &events, true),
+            VmOutcome::Stopped(None)
+        );
+        events.shutdown = Some((false, "host-qmp-system-reset".into()));
+        assert!(matches!(
+            VmOutcome::classify(exited(0), &events, false),
+            VmOutcome::Stopped(Some(_))
+        ));
+    }
+
+    #[test]
+    fn panics_and_vmm_failures_take_precedence() {
+        let events = GuestEvents {
+            panicked: true,
+            ghostty_exit: Some(1),
+            ..GuestEvents::default()
+        };
+        assert_eq!(
+            VmOutcome::classify(exited(0), &events, false),
+            VmOutcome::GuestPanic
+        );
+
+        let events = GuestEvents {
+            ghostty_exit: Some(1),
+            ..GuestEvents::default()
+        };
+        assert_eq!(
+            VmOutcome::classify(exited(1), &events, false),
+            VmOutcome::QemuCrash(Some(1))
+        );
+        assert_eq!(
+            VmOutcome::classify(ExitStatus::from_raw(9), &events, false),
+            VmOutcome::Killed(9)
+        );
+    }
+}
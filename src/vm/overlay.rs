@@ -0,0 +1,515 @@
+use crate::error::{VirtualGhostError, VmError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use super::config::image_format;
+use super::instance::InstanceDir;
+
+/// qcow2 images start with `QFI\xfb`.
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+
+/// 64 KiB clusters, QEMU's default.
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER: u64 = 1 << CLUSTER_BITS;
+
+/// Length of a version 3 header without optional fields.
+const HEADER_LENGTH: u32 = 104;
+
+/// Header extension naming the backing file's format, so QEMU does not probe it.
+const BACKING_FORMAT_EXTENSION: u32 = 0xe279_2aca;
+
+/// Longest backing file name QEMU accepts.
+const MAX_BACKING_FILE: usize = 1023;
+
+/// The disk a VM boots from: a copy-on-write overlay of the rootfs in the
+/// instance directory, so the rootfs itself is never written.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    path: PathBuf,
+    base: PathBuf,
+    base_format: &'static str,
+    /// A full copy of the base instead, for VMMs that only read raw images:
+    /// a reflink where the filesystem has them.
+    copy: bool,
+    persist: bool,
+}
+
+impl Overlay {
+    /// The overlay `instance` boots from on top of `base`: discarded when the
+    /// VM exits unless `persist` is set. `raw_only` VMMs get a full copy.
+    pub fn new(
+        instance: &InstanceDir,
+        base: &Path,
+        raw_only: bool,
+        persist: bool,
+    ) -> Result<Self, VirtualGhostError> {
+        let base = std::fs::canonicalize(base)?;
+        let base_format = image_format(&base)?;
+        let format = if raw_only { base_format } else { "qcow2" };
+        let path = if persist {
+            instance.persistent_disk(format)
+        } else {
+            instance.overlay(format)
+        };
+        Ok(Self {
+            path,
+            base,
+            base_format,
+            copy: raw_only,
+            persist,
+        })
+    }
+
+    /// Image the VMM opens.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `qcow2`, or the base's format for a copy.
+    pub fn format(&self) -> &'static str {
+        if self.copy {
+            self.base_format
+        } else {
+            "qcow2"
+        }
+    }
+
+    /// Create the overlay, or check the one a persistent or resumed VM
+    /// already has still belongs to the base.
+    pub fn prepare(&self, resuming: bool) -> Result<(), VirtualGhostError> {
+        if !self.path.exists() {
+            if resuming {
+                return Err(VmError::Suspend(format!(
+                    "the disk {} the VM was saved with is missing",
+                    self.path.display()
+                ))
+                .into());
+            }
+            return self.create();
+        }
+        if !self.persist && !resuming {
+            // Left behind by a launcher that did not get to clean up
+            return self.create();
+        }
+        if self.copy {
+            return Ok(());
+        }
+
+        let backing = backing_file(&self.path)?;
+        if backing.as_deref() != Some(self.base.as_path()) {
+            return Err(VmError::Overlay(format!(
+                "{} is an overlay of {}, not {}; delete it to start over",
+                self.path.display(),
+                backing.map_or("no image".into(), |path| path.display().to_string()),
+                self.base.display()
+            ))
+            .into());
+        }
+        // Blocks the overlay does not have are read from the base, so they
+        // no longer match the guest's filesystem once it changes
+        if self.base_changed() {
+            let message = format!(
+                "{} changed after the overlay {} was created",
+                self.base.display(),
+                self.path.display()
+            );
+            if resuming {
+                return Err(VmError::Suspend(message).into());
+            }
+            warn!("{message}; the guest may find its filesystem corrupted");
+        }
+        Ok(())
+    }
+
+    fn create(&self) -> Result<(), VirtualGhostError> {
+        if self.copy {
+            info!(
+                base = %self.base.display(),
+                disk = %self.path.display(),
+                "Copying the rootfs"
+            );
+            let partial = self.path.with_extension("part");
+            if let Err(e) = reflink(&self.base, &partial) {
+                warn!(
+                    error = %e,
+                    "Cannot clone the rootfs, copying it in full; \
+                     --persist keeps the copy for the next boot"
+                );
+                std::fs::copy(&self.base, &partial)?;
+            }
+            std::fs::rename(&partial, &self.path)?;
+        } else {
+            create_qcow2(&self.path, &self.base, self.base_format)
+                .map_err(|e| VmError::Overlay(format!("{}: {e}", self.path.display())))?;
+        }
+        Ok(())
+    }
+
+    fn base_changed(&self) -> bool {
+        let created = std::fs::metadata(&self.path).and_then(|m| m.created());
+        let modified = std::fs::metadata(&self.base).and_then(|m| m.modified());
+        match (created, modified) {
+            (Ok(created), Ok(modified)) => modified > created,
+            // Filesystems without creation times get the benefit of the doubt
+            _ => false,
+        }
+    }
+}
+
+/// Make `to` a clone of `from` sharing its extents until either is written
+/// (`FICLONE`), which takes no time or space on filesystems with reflinks
+/// such as Btrfs and XFS.
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    /// `_IOW(0x94, 9, int)`
+    const FICLONE: u64 = 0x4004_9409;
+
+    let source = std::fs::File::open(from)?;
+    let target = std::fs::File::create(to)?;
+    // SAFETY: the ioctl takes the source file descriptor by value on an open fd.
+    let ret = unsafe { nix::libc::ioctl(target.as_raw_fd(), FICLONE as _, source.as_raw_fd()) };
+    if ret != 0 {
+        let error = std::io::Error::last_os_error();
+        drop(target);
+        let _ = std::fs::remove_file(to);
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "reflinks are only made on Linux",
+    ))
+}
+
+/// Write an empty qcow2 v3 image at `path` that reads everything from `base`.
+///
+/// Cluster 0 holds the header, the backing format extension and the backing
+/// file name; cluster 1 the refcount table, cluster 2 its only refcount block
+/// and the clusters after it the L1 table, all unallocated.
+fn create_qcow2(path: &Path, base: &Path, base_format: &str) -> std::io::Result<()> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+    let backing = base.to_string_lossy();
+    if backing.len() > MAX_BACKING_FILE {
+        return Err(invalid(format!(
+            "the backing file name is longer than {MAX_BACKING_FILE} bytes"
+        )));
+    }
+    let size = virtual_size(base, base_format)?.next_multiple_of(512);
+
+    // Each L1 entry maps an L2 table of 8-byte entries, each mapping a cluster
+    let l1_coverage = CLUSTER * (CLUSTER / 8);
+    let l1_size = size.div_ceil(l1_coverage);
+    let l1_clusters = (l1_size * 8).div_ceil(CLUSTER).max(1);
+    let clusters = 3 + l1_clusters;
+    if clusters > CLUSTER / 2 {
+        return Err(invalid(format!("{size} bytes is too large for an overlay")));
+    }
+
+    let mut header = Vec::with_capacity(CLUSTER as usize);
+    header.extend_from_slice(&QCOW2_MAGIC.to_be_bytes());
+    header.extend_from_slice(&3u32.to_be_bytes()); // version
+    header.extend_from_slice(&0u64.to_be_bytes()); // backing file offset, set below
+    header.extend_from_slice(&(backing.len() as u32).to_be_bytes());
+    header.extend_from_slice(&CLUSTER_BITS.to_be_bytes());
+    header.extend_from_slice(&size.to_be_bytes());
+    header.extend_from_slice(&0u32.to_be_bytes()); // no encryption
+    header.extend_from_slice(&(l1_size as u32).to_be_bytes());
+    header.extend_from_slice(&(3 * CLUSTER).to_be_bytes()); // L1 table
+    header.extend_from_slice(&CLUSTER.to_be_bytes()); // refcount table
+    header.extend_from_slice(&1u32.to_be_bytes()); // refcount table clusters
+    header.extend_from_slice(&0u32.to_be_bytes()); // snapshots
+    header.extend_from_slice(&0u64.to_be_bytes()); // snapshot table
+    header.extend_from_slice(&0u64.to_be_bytes()); // incompatible features
+    header.extend_from_slice(&0u64.to_be_bytes()); // compatible features
+    header.extend_from_slice(&0u64.to_be_bytes()); // autoclear features
+    header.extend_from_slice(&4u32.to_be_bytes()); // 16-bit refcounts
+    header.extend_from_slice(&HEADER_LENGTH.to_be_bytes());
+
+    header.extend_from_slice(&BACKING_FORMAT_EXTENSION.to_be_bytes());
+    header.extend_from_slice(&(base_format.len() as u32).to_be_bytes());
+    header.extend_from_slice(base_format.as_bytes());
+    header.resize(header.len().next_multiple_of(8), 0);
+    header.extend_from_slice(&[0; 8]); // end of extensions
+
+    let backing_offset = header.len() as u64;
+    header[8..16].copy_from_slice(&backing_offset.to_be_bytes());
+    header.extend_from_slice(backing.as_bytes());
+    header.resize(CLUSTER as usize, 0);
+
+    let mut refcount_table = vec![0; CLUSTER as usize];
+    refcount_table[..8].copy_from_slice(&(2 * CLUSTER).to_be_bytes());
+    let mut refcount_block = vec![0; CLUSTER as usize];
+    for cluster in 0..clusters as usize {
+        refcount_block[2 * cluster..2 * cluster + 2].copy_from_slice(&1u16.to_be_bytes());
+    }
+
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(&header)?;
+    file.write_all(&refcount_table)?;
+    file.write_all(&refcount_block)?;
+    file.set_len(clusters * CLUSTER)?;
+    file.sync_all()
+}
+
+/// Size of the disk a `raw` or `qcow2` image presents to the guest.
+fn virtual_size(path: &Path, format: &str) -> std::io::Result<u64> {
+    if format != "qcow2" {
+        return Ok(std::fs::metadata(path)?.len());
+    }
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(24))?;
+    let mut size = [0; 8];
+    file.read_exact(&mut size)?;
+    Ok(u64::from_be_bytes(size))
+}
+
+/// Backing file named in the header of the qcow2 image at `path`, if any.
+pub fn backing_file(path: &Path) -> std::io::Result<Option<PathBuf>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut header = [0; 20];
+    file.read_exact(&mut header)?;
+    if header[..4] != QCOW2_MAGIC.to_be_bytes() {
+        return Ok(None);
+    }
+    let offset = u64::from_be_bytes(header[8..16].try_into().unwrap_or_default());
+    let len = u32::from_be_bytes(header[16..20].try_into().unwrap_or_default());
+    if offset == 0 || len as usize > MAX_BACKING_FILE {
+        return Ok(None);
+    }
+    let mut name = vec![0; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut name)?;
+    Ok(Some(String::from_utf8_lossy(&name).into_owned().into()))
+}
+
+/// Images read by the overlays kept between boots, the disks of `--persist`
+/// and of suspended VMs, each with the instance keeping it.
+pub fn kept_backing_files() -> Result<Vec<(PathBuf, String)>, VirtualGhostError> {
+    let mut backing_files = Vec::new();
+    for instance in InstanceDir::list()? {
+        let mut disks = vec![instance.persistent_disk("qcow2")];
+        if instance.is_suspended() {
+            disks.push(instance.overlay("qcow2"));
+        }
+        for disk in disks.into_iter().filter(|disk| disk.exists()) {
+            match backing_file(&disk) {
+                Ok(Some(base)) => backing_files.push((base, instance.name().to_string())),
+                Ok(None) => {}
+                Err(e) => warn!(disk = %disk.display(), error = %e, "Cannot read the overlay"),
+            }
+        }
+    }
+    Ok(backing_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn be64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// A sparse raw image of `size` bytes.
+    fn raw_base(dir: &Path, size: u64) -> PathBuf {
+        let path = dir.join("rootfs.ext4");
+        std::fs::File::create(&path).unwrap().set_len(size).unwrap();
+        path
+    }
+
+    #[test]
+    fn overlay_reads_back_its_backing_file_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        // Not a multiple of 512: the guest sees whole sectors
+        let base = raw_base(dir.path(), 100 * 1024 * 1024 + 100);
+        let overlay = dir.path().join("overlay.qcow2");
+        create_qcow2(&overlay, &base, "raw").unwrap();
+
+        assert_eq!(backing_file(&overlay).unwrap(), Some(base.clone()));
+        assert_eq!(
+            virtual_size(&overlay, "qcow2").unwrap(),
+            100 * 1024 * 1024 + 512
+        );
+        assert_eq!(image_format(&overlay).unwrap(), "qcow2");
+        // A raw image has no header to name a backing file
+        assert_eq!(backing_file(&base).unwrap(), None);
+    }
+
+    #[test]
+    fn overlay_header_and_tables_are_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let size = 3 * 1024 * 1024 * 1024;
+        let base = raw_base(dir.path(), size);
+        let overlay = dir.path().join("overlay.qcow2");
+        create_qcow2(&overlay, &base, "raw").unwrap();
+        let image = std::fs::read(&overlay).unwrap();
+
+        assert_eq!(be32(&image, 0), QCOW2_MAGIC);
+        assert_eq!(be32(&image, 4), 3);
+        assert_eq!(be32(&image, 20), CLUSTER_BITS);
+        assert_eq!(be64(&image, 24), size);
+        assert_eq!(be32(&image, 32), 0, "encryption");
+        // Each L1 entry covers 512 MiB with 64 KiB clusters
+        let l1_size = be32(&image, 36) as u64;
+        assert_eq!(l1_size, 6);
+        let l1_offset = be64(&image, 40);
+        let refcount_table = be64(&image, 48);
+        assert_eq!(be32(&image, 56), 1, "refcount table clusters");
+        assert_eq!(be32(&image, 60), 0, "snapshots");
+        assert_eq!(be64(&image, 72), 0, "incompatible features");
+        assert_eq!(be32(&image, 96), 4, "refcount order");
+        assert_eq!(be32(&image, 100), HEADER_LENGTH);
+        for offset in [l1_offset, refcount_table] {
+            assert_eq!(offset % CLUSTER, 0);
+        }
+
+        // The backing format extension, then the end of extensions
+        let extension = HEADER_LENGTH as usize;
+        assert_eq!(be32(&image, extension), BACKING_FORMAT_EXTENSION);
+        assert_eq!(be32(&image, extension + 4), 3);
+        assert_eq!(&image[extension + 8..extension + 11], b"raw");
+        assert_eq!(be64(&image, extension + 16), 0);
+        let backing_offset = be64(&image, 8) as usize;
+        assert!(backing_offset >= extension + 24);
+
+        // Every cluster in the file is referenced once, and nothing beyond it
+        let clusters = image.len() as u64 / CLUSTER;
+        assert_eq!(image.len() as u64 % CLUSTER, 0);
+        let block = be64(&image, refcount_table as usize) as usize;
+        for cluster in 0..CLUSTER as usize / 2 {
+            let refcount =
+                u16::from_be_bytes(image[block + 2 * cluster..][..2].try_into().unwrap());
+            let expected = u16::from(cluster < clusters as usize);
+            assert_eq!(refcount, expected, "refcount of cluster {cluster}");
+        }
+        assert!(l1_offset / CLUSTER < clusters);
+
+        // No data is allocated: every L1 entry is empty
+        let l1 = &image[l1_offset as usize..][..l1_size as usize * 8];
+        assert!(l1.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn header_fields_follow_the_base() {
+        const MIB: u64 = 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        // Base size, the size the guest sees and L1 entries (512 MiB each)
+        let cases = [
+            (1, 512, 1),
+            (512 * MIB, 512 * MIB, 1),
+            (512 * MIB + 1, 512 * MIB + 512, 2),
+            (1024 * MIB + 4096, 1024 * MIB + 4096, 3),
+            (100 * 1024 * MIB, 100 * 1024 * MIB, 200),
+        ];
+        for (base_size, size, l1_size) in cases {
+            let base = raw_base(dir.path(), base_size);
+            let overlay = dir.path().join("overlay.qcow2");
+            create_qcow2(&overlay, &base, "raw").unwrap();
+            let image = std::fs::read(&overlay).unwrap();
+
+            assert_eq!(&image[..4], b"QFI\xfb");
+            assert_eq!(be32(&image, 4), 3, "version");
+            let backing = base.to_str().unwrap().as_bytes();
+            let backing_offset = be64(&image, 8) as usize;
+            assert_eq!(be32(&image, 16) as usize, backing.len());
+            assert_eq!(&image[backing_offset..][..backing.len()], backing);
+            assert!(backing_offset + backing.len() <= CLUSTER as usize);
+            assert_eq!(be32(&image, 20), 16, "cluster bits");
+            assert_eq!(be64(&image, 24), size, "size of {base_size} bytes");
+            assert_eq!(be32(&image, 36), l1_size, "L1 size for {base_size} bytes");
+        }
+    }
+
+    #[test]
+    fn overlay_of_a_qcow2_image_takes_its_virtual_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = raw_base(dir.path(), 64 * 1024 * 1024);
+        let middle = dir.path().join("middle.qcow2");
+        create_qcow2(&middle, &base, "raw").unwrap();
+        let top = dir.path().join("top.qcow2");
+        create_qcow2(&top, &middle, "qcow2").unwrap();
+
+        assert_eq!(virtual_size(&top, "qcow2").unwrap(), 64 * 1024 * 1024);
+        assert_eq!(backing_file(&top).unwrap(), Some(middle));
+        let image = std::fs::read(&top).unwrap();
+        let extension = HEADER_LENGTH as usize;
+        assert_eq!(be32(&image, extension + 4), 5);
+        assert_eq!(&image[extension + 8..extension + 13], b"qcow2");
+    }
+
+    #[test]
+    fn reflink_clones_or_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("rootfs.ext4");
+        std::fs::write(&base, vec![0x5a; 3 * CLUSTER as usize]).unwrap();
+        let clone = dir.path().join("overlay.part");
+        match reflink(&base, &clone) {
+            Ok(()) => assert_eq!(
+                std::fs::read(&clone).unwrap(),
+                std::fs::read(&base).unwrap()
+            ),
+            // ext4 and tmpfs have no reflinks; the caller copies instead
+            Err(_) => assert!(!clone.exists()),
+        }
+    }
+
+    #[test]
+    fn long_backing_file_names_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("a".repeat(200)).join("b".repeat(200));
+        let base = base.join("c".repeat(200)).join("d".repeat(200));
+        let base = base.join("e".repeat(200)).join("rootfs.ext4");
+        let error = create_qcow2(&dir.path().join("overlay.qcow2"), &base, "raw").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    /// QEMU's own checker: `cargo test -- --ignored qemu_img` with qemu-img installed.
+    #[test]
+    #[ignore = "needs qemu-img"]
+    fn qemu_img_accepts_the_overlay() {
+        let version = std::process::Command::new("qemu-img")
+            .arg("--version")
+            .output()
+            .expect("qemu-img is not installed");
+        assert!(version.status.success());
+
+        let dir = tempfile::tempdir().unwrap();
+        let base = raw_base(dir.path(), 1024 * 1024 * 1024 + 4096);
+        let overlay = dir.path().join("overlay.qcow2");
+        create_qcow2(&overlay, &base, "raw").unwrap();
+
+        let check = std::process::Command::new("qemu-img")
+            .args(["check", "-f", "qcow2"])
+            .arg(&overlay)
+            .output()
+            .unwrap();
+        assert!(
+            check.status.success(),
+            "{}",
+            String::from_utf8_lossy(&check.stdout)
+        );
+
+        let info = std::process::Command::new("qemu-img")
+            .args(["info", "--output=json", "-f", "qcow2"])
+            .arg(&overlay)
+            .output()
+            .unwrap();
+        assert!(info.status.success());
+        let info: serde_json::Value = serde_json::from_slice(&info.stdout).unwrap();
+        assert_eq!(info["virtual-size"], 1024 * 1024 * 1024 + 4096);
+        assert_eq!(info["backing-filename"], base.to_str().unwrap());
+        assert_eq!(info["backing-filename-format"], "raw");
+        assert_eq!(info["cluster-size"], CLUSTER);
+    }
+}
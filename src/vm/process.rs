@@ -1,19 +1,63 @@
 #![allow(dead_code)]
 
 use crate::error::{VmError, VirtualGhostError};
+use crate::network::GuestEndpoint;
+use async_trait::async_trait;
 use std::process::ExitStatus;
+use std::time::Duration;
 use tokio::process::{Child, Command};
-use tracing::info;
+use tracing::{info, warn};
 
 use super::config::QemuConfig;
+use super::hypervisor::{Hypervisor, HypervisorKind, VmControl};
+use super::instance::InstanceDir;
+use super::outcome::{EventRecorder, GuestEvents};
+use super::qmp::{QmpAddress, QmpClient};
 
 pub struct QemuProcess {
-    child: Child,
+    child: ChildGuard,
+    /// The launcher's QMP monitor, once [`Hypervisor::wait_ready`] connected it.
+    qmp: Option<QmpClient>,
+    qmp_addr: Option<QmpAddress>,
+    events: Option<EventRecorder>,
+    vsock_cid: Option<u64>,
+    ssh_port_forward: Option<u16>,
 }
 
 impl QemuProcess {
+    /// Environment variables set for QEMU on top of the inherited ones, so an
+    /// extracted QEMU finds the DLLs/shared libraries next to it.
+    pub fn environment(config: &QemuConfig) -> Vec<(&'static str, String)> {
+        let mut env = Vec::new();
+        if let Some(qemu_dir) = config.qemu_bin.parent() {
+            #[cfg(target_os = "windows")]
+            {
+                let path_var = std::env::var("PATH").unwrap_or_default();
+                env.push(("PATH", format!("{};{}", qemu_dir.display(), path_var)));
+            }
+
+            #[cfg(target_os = "linux")]
+            env.push(("LD_LIBRARY_PATH", prepend_path(qemu_dir, "LD_LIBRARY_PATH")));
+
+            #[cfg(target_os = "macos")]
+            env.push((
+                "DYLD_LIBRARY_PATH",
+                prepend_path(qemu_dir, "DYLD_LIBRARY_PATH"),
+            ));
+        }
+        env
+    }
+
+    /// The launcher's QMP monitor, if QEMU answered on it.
+    pub fn qmp(&self) -> Option<&QmpClient> {
+        self.qmp.as_ref()
+    }
+}
+
+#[async_trait]
+impl Hypervisor for QemuProcess {
     /// Spawn QEMU with the given configuration.
-    pub async fn spawn(config: &QemuConfig) -> Result<Self, VirtualGhostError> {
+    async fn spawn(config: &QemuConfig, _instance: &InstanceDir) -> Result<Self, VirtualGhostError> {
         let args = config.to_args();
 
         info!(
@@ -25,47 +69,72 @@ impl QemuProcess {
         let mut cmd = Command::new(&config.qemu_bin);
         cmd.args(&args).stdin(std::process::Stdio::null());
 
-        // Ensure extracted QEMU can find its DLLs/shared libraries
-        if let Some(qemu_dir) = config.qemu_bin.parent() {
-            #[cfg(target_os = "windows")]
-            {
-                let path_var = std::env::var("PATH").unwrap_or_default();
-                let new_path = format!("{};{}", qemu_dir.display(), path_var);
-                cmd.env("PATH", new_path);
-            }
-
-            #[cfg(target_os = "linux")]
-            {
-                let ld_path = std::env::var("LD_LIBRARY_PATH").unwrap_or_default();
-                let new_path = if ld_path.is_empty() {
-                    qemu_dir.display().to_string()
-                } else {
-                    format!("{}:{}", qemu_dir.display(), ld_path)
-                };
-                cmd.env("LD_LIBRARY_PATH", new_path);
-            }
+        // Run QEMU in its own process group so a terminal Ctrl-C reaches only the
+        // launcher, which then powers the guest off cleanly instead of QEMU dying mid-write.
+        #[cfg(unix)]
+        cmd.process_group(0);
+        #[cfg(windows)]
+        cmd.creation_flags(0x0000_0200); // CREATE_NEW_PROCESS_GROUP
 
-            #[cfg(target_os = "macos")]
-            {
-                let dyld_path = std::env::var("DYLD_LIBRARY_PATH").unwrap_or_default();
-                let new_path = if dyld_path.is_empty() {
-                    qemu_dir.display().to_string()
-                } else {
-                    format!("{}:{}", qemu_dir.display(), dyld_path)
-                };
-                cmd.env("DYLD_LIBRARY_PATH", new_path);
-            }
-        }
+        cmd.envs(Self::environment(config));
 
         let child = cmd.spawn().map_err(VmError::SpawnFailed)?;
 
         info!(pid = child.id(), "QEMU process started");
 
-        Ok(Self { child })
+        Ok(Self {
+            child: ChildGuard::new(child),
+            qmp: None,
+            qmp_addr: config.qmp_address(),
+            events: None,
+            vsock_cid: config.vsock_cid,
+            ssh_port_forward: config.ssh_port_forward,
+        })
+    }
+
+    fn kind(&self) -> HypervisorKind {
+        HypervisorKind::Qemu
+    }
+
+    /// OS process ID, or `None` once QEMU has been reaped.
+    fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Connect to QMP and start recording guest events. QMP is needed for a
+    /// clean shutdown, but a QEMU without it still runs: we can only kill it.
+    async fn wait_ready(&mut self, timeout: Duration) -> Result<(), VirtualGhostError> {
+        let Some(addr) = &self.qmp_addr else {
+            return Ok(());
+        };
+        match QmpClient::connect_retry(addr, timeout).await {
+            Ok(qmp) => {
+                self.events = Some(EventRecorder::start(&qmp));
+                self.qmp = Some(qmp);
+            }
+            Err(e) => warn!(error = %e, "QMP unavailable — graceful shutdown disabled"),
+        }
+        Ok(())
+    }
+
+    /// vsock when a CID is assigned, otherwise the forwarded SSH port.
+    fn agent_endpoint(&self, vsock_port: u32) -> Option<GuestEndpoint> {
+        if let Some(cid) = self.vsock_cid {
+            Some(GuestEndpoint::Vsock {
+                cid: cid as u32,
+                port: vsock_port,
+            })
+        } else {
+            self.ssh_port_forward.map(|port| GuestEndpoint::Tcp { port })
+        }
+    }
+
+    fn control(&self) -> Option<&dyn VmControl> {
+        self.qmp.as_ref().map(|qmp| qmp as &dyn VmControl)
     }
 
     /// Wait for the QEMU process to exit.
-    pub async fn wait(&mut self) -> Result<ExitStatus, VirtualGhostError> {
+    async fn wait(&mut self) -> Result<ExitStatus, VirtualGhostError> {
         let status = self
             .child
             .wait()
@@ -75,18 +144,190 @@ impl QemuProcess {
     }
 
     /// Kill the QEMU process.
-    pub async fn kill(&mut self) -> Result<(), VirtualGhostError> {
+    async fn kill(&mut self) -> Result<(), VirtualGhostError> {
         self.child
             .kill()
             .await
             .map_err(VmError::SpawnFailed)?;
         Ok(())
     }
+
+    /// Leave QEMU running after the launcher exits. Returns its PID.
+    fn detach(mut self: Box<Self>) -> Option<u32> {
+        self.child.detach()
+    }
+
+    /// The events QMP reported, including any still in flight when QEMU exited.
+    async fn events(&mut self) -> GuestEvents {
+        match self.events.take() {
+            Some(recorder) => recorder.finish(self.qmp.take()).await,
+            None => GuestEvents::default(),
+        }
+    }
+}
+
+/// A VMM process, killed (best effort) when dropped unless it was handed off
+/// to run in the background.
+pub struct ChildGuard {
+    child: Child,
+    detached: bool,
+}
+
+impl ChildGuard {
+    pub fn new(child: Child) -> Self {
+        Self {
+            child,
+            detached: false,
+        }
+    }
+
+    /// Leave the process running once the guard is dropped. Returns its PID.
+    pub fn detach(&mut self) -> Option<u32> {
+        self.detached = true;
+        self.child.id()
+    }
 }
 
-impl Drop for QemuProcess {
+impl std::ops::Deref for ChildGuard {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl std::ops::DerefMut for ChildGuard {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for ChildGuard {
     fn drop(&mut self) {
-        // Best-effort kill on drop
-        let _ = self.child.start_kill();
+        if !self.detached {
+            let _ = self.child.start_kill();
+        }
+    }
+}
+
+/// Whether a process with this PID still exists.
+///
+/// Only Unix can check; elsewhere the process is assumed to be alive.
+pub fn pid_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        use nix::errno::Errno;
+        use nix::unistd::Pid;
+        // Signal 0 only checks for existence; EPERM still means the PID is taken
+        !matches!(
+            nix::sys::signal::kill(Pid::from_raw(pid as i32), None),
+            Err(Errno::ESRCH)
+        )
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+/// What tells a process apart from a later one that reuses its PID, e.g.
+/// after a host reboot: the boot ID and start time on Linux, the start time
+/// on other Unixes. `None` where that cannot be read.
+pub fn process_identity(pid: u32) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let boot_id = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // The command name in parentheses may contain spaces; field 22,
+        // the start time in clock ticks since boot, counts from the state after it
+        let (_, fields) = stat.rsplit_once(')')?;
+        let start = fields.split_whitespace().nth(19)?;
+        Some(format!("{} {start}", boot_id.trim()))
+    }
+    #[cfg(all(unix, not(target_os = "linux")))]
+    {
+        let output = std::process::Command::new("ps")
+            .args(["-o", "lstart=", "-p", &pid.to_string()])
+            .output()
+            .ok()?;
+        let start = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !start.is_empty()).then_some(start)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// Whether `pid` is still the process recorded with `identity`, from
+/// [`process_identity`]; without one, only whether the PID exists.
+pub fn same_process(pid: u32, identity: Option<&str>) -> bool {
+    if !pid_alive(pid) {
+        return false;
+    }
+    match identity {
+        Some(identity) => process_identity(pid).as_deref() == Some(identity),
+        None => true,
+    }
+}
+
+/// Ask a process to exit (SIGTERM), or force it to (SIGKILL) when `force` is set.
+pub fn signal_pid(pid: u32, force: bool) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        let signal = if force { Signal::SIGKILL } else { Signal::SIGTERM };
+        kill(Pid::from_raw(pid as i32), signal).map_err(std::io::Error::from)
+    }
+    #[cfg(not(unix))]
+    {
+        let mut cmd = std::process::Command::new("taskkill");
+        cmd.args(["/PID", &pid.to_string()]);
+        if force {
+            cmd.arg("/F");
+        }
+        let status = cmd.status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!("taskkill exited with {status}")))
+        }
+    }
+}
+
+/// `dir` followed by the current value of the search path `var`, if any.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn prepend_path(dir: &std::path::Path, var: &str) -> String {
+    let current = std::env::var(var).unwrap_or_default();
+    if current.is_empty() {
+        dir.display().to_string()
+    } else {
+        format!("{}:{}", dir.display(), current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_tells_processes_apart() {
+        let pid = std::process::id();
+        let identity = process_identity(pid).unwrap();
+        assert!(same_process(pid, Some(&identity)));
+        assert!(same_process(pid, None));
+        // The same PID started at another time, e.g. before a reboot
+        assert!(!same_process(pid, Some("another boot 1")));
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let child_pid = child.id();
+        let child_identity = process_identity(child_pid);
+        assert!(child_identity.is_some());
+        child.wait().unwrap();
+        assert!(!same_process(child_pid, child_identity.as_deref()));
     }
 }
@@ -0,0 +1,613 @@
+#![allow(dead_code)]
+
+use crate::error::{VirtualGhostError, VmError};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Responders of in-flight commands by id; `None` once the connection closed.
+type PendingMap = Arc<StdMutex<Option<HashMap<u64, oneshot::Sender<Result<Value, String>>>>>>;
+
+/// Where QEMU's QMP server is listening.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QmpAddress {
+    /// Unix domain socket (Linux/macOS).
+    Unix(PathBuf),
+    /// Loopback TCP port (Windows).
+    Tcp(u16),
+}
+
+impl std::fmt::Display for QmpAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(port) => write!(f, "tcp:127.0.0.1:{port}"),
+        }
+    }
+}
+
+/// Result of `query-migrate`.
+#[derive(Debug, Clone)]
+pub struct MigrationInfo {
+    /// "setup", "active", "completed", "failed", ... ("none" before any migration).
+    pub status: String,
+    /// RAM bytes written so far.
+    pub transferred: u64,
+    /// Why the migration failed, if it did.
+    pub error: Option<String>,
+}
+
+/// A vCPU slot reported by `query-hotpluggable-cpus`.
+#[derive(Debug, Clone)]
+pub struct HotpluggableCpu {
+    /// CPU model to pass to `device_add` (e.g. "host-x86_64-cpu").
+    pub driver: String,
+    /// Topology properties (`socket-id`, `core-id`, ...) identifying the slot.
+    pub props: Map<String, Value>,
+    /// QOM path of the vCPU in this slot, if one is plugged.
+    pub qom_path: Option<String>,
+}
+
+/// A hot-pluggable memory device reported by `query-memory-devices`.
+#[derive(Debug, Clone)]
+pub struct MemoryDevice {
+    pub id: Option<String>,
+    /// Size in bytes.
+    pub size: u64,
+}
+
+/// Asynchronous event emitted by QEMU (e.g. `SHUTDOWN`, `RESET`, `STOP`).
+#[derive(Debug, Clone)]
+pub struct QmpEvent {
+    pub event: String,
+    pub data: Value,
+    /// Event time as (seconds, microseconds) since the Unix epoch.
+    pub timestamp: (u64, u64),
+}
+
+/// Client for the QEMU Machine Protocol — line-delimited JSON over the `-qmp` socket.
+///
+/// A background task reads the socket, routes each command response to its
+/// caller by `id`, and broadcasts asynchronous events to subscribers.
+pub struct QmpClient {
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    pending: PendingMap,
+    next_id: AtomicU64,
+    events: broadcast::Sender<QmpEvent>,
+    reader: JoinHandle<()>,
+    greeting: Value,
+}
+
+impl QmpClient {
+    /// Connect to a QMP server, read its greeting and negotiate capabilities.
+    pub async fn connect(addr: &QmpAddress) -> Result<Self, VirtualGhostError> {
+        match addr {
+            #[cfg(unix)]
+            QmpAddress::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path)
+                    .await
+                    .map_err(|e| VmError::QmpError(format!("failed to connect to {addr}: {e}")))?;
+                Self::handshake(stream).await
+            }
+            #[cfg(not(unix))]
+            QmpAddress::Unix(_) => Err(VmError::QmpError(format!(
+                "{addr}: Unix sockets are not supported on this platform"
+            ))
+            .into()),
+            QmpAddress::Tcp(port) => {
+                let stream = tokio::net::TcpStream::connect(("127.0.0.1", *port))
+                    .await
+                    .map_err(|e| VmError::QmpError(format!("failed to connect to {addr}: {e}")))?;
+                Self::handshake(stream).await
+            }
+        }
+    }
+
+    /// Connect, retrying until `timeout` elapses.
+    ///
+    /// QEMU creates the QMP socket shortly after it starts, so the first few
+    /// attempts right after spawning are expected to fail.
+    pub async fn connect_retry(
+        addr: &QmpAddress,
+        timeout: Duration,
+    ) -> Result<Self, VirtualGhostError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match Self::connect(addr).await {
+                Ok(client) => return Ok(client),
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(e) => debug!(%addr, error = %e, "QMP not ready yet"),
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    async fn handshake<S>(stream: S) -> Result<Self, VirtualGhostError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read_half);
+
+        // Server greeting: {"QMP": {"version": {...}, "capabilities": [...]}}
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| VmError::QmpError(format!("failed to read greeting: {e}")))?;
+        let greeting: Value = serde_json::from_str(&line)
+            .map_err(|e| VmError::QmpError(format!("invalid greeting {line:?}: {e}")))?;
+        let greeting = greeting
+            .get("QMP")
+            .cloned()
+            .ok_or_else(|| VmError::QmpError(format!("unexpected greeting: {line}")))?;
+
+        let pending: PendingMap = Arc::new(StdMutex::new(Some(HashMap::new())));
+        let (events, _) = broadcast::channel(64);
+        let reader = tokio::spawn(Self::read_loop(reader, pending.clone(), events.clone()));
+
+        let client = Self {
+            writer: Mutex::new(Box::new(write_half)),
+            pending,
+            next_id: AtomicU64::new(1),
+            events,
+            reader,
+            greeting,
+        };
+
+        // Leave capabilities negotiation mode so commands are accepted
+        client.execute("qmp_capabilities", None).await?;
+
+        info!(version = %client.version(), "QMP connected");
+        Ok(client)
+    }
+
+    async fn read_loop<R>(
+        mut reader: BufReader<R>,
+        pending: PendingMap,
+        events: broadcast::Sender<QmpEvent>,
+    ) where
+        R: AsyncRead + Unpin,
+    {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(error = %e, "QMP read failed");
+                    break;
+                }
+            }
+
+            let msg: Value = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!(line = line.trim_end(), error = %e, "Ignoring malformed QMP message");
+                    continue;
+                }
+            };
+
+            if let Some(event) = msg.get("event").and_then(Value::as_str) {
+                let timestamp = msg.get("timestamp");
+                let field = |name| {
+                    timestamp
+                        .and_then(|t| t.get(name))
+                        .and_then(Value::as_u64)
+                        .unwrap_or(0)
+                };
+                let event = QmpEvent {
+                    event: event.to_string(),
+                    data: msg.get("data").cloned().unwrap_or(Value::Null),
+                    timestamp: (field("seconds"), field("microseconds")),
+                };
+                debug!(event = event.event, data = %event.data, "QMP event");
+                // No subscribers is fine — events are simply dropped
+                let _ = events.send(event);
+                continue;
+            }
+
+            let Some(id) = msg.get("id").and_then(Value::as_u64) else {
+                debug!(%msg, "Ignoring QMP message without id");
+                continue;
+            };
+            let tx = pending.lock().unwrap().as_mut().and_then(|p| p.remove(&id));
+            let Some(tx) = tx else {
+                debug!(id, "Ignoring QMP response for unknown id");
+                continue;
+            };
+
+            let result = if let Some(ret) = msg.get("return") {
+                Ok(ret.clone())
+            } else if let Some(err) = msg.get("error") {
+                let class = err.get("class").and_then(Value::as_str).unwrap_or("Error");
+                let desc = err.get("desc").and_then(Value::as_str).unwrap_or("unknown");
+                Err(format!("{class}: {desc}"))
+            } else {
+                Err(format!("malformed response: {msg}"))
+            };
+            let _ = tx.send(result);
+        }
+
+        // Connection closed: fail every in-flight command, and any issued from now on
+        pending.lock().unwrap().take();
+        debug!("QMP connection closed");
+    }
+
+    /// Execute a QMP command and wait for its `return` value.
+    pub async fn execute(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<Value, VirtualGhostError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut request = json!({ "execute": command, "id": id });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut payload = request.to_string();
+        payload.push('\n');
+
+        let (tx, rx) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => {
+                return Err(VmError::QmpError(format!("{command}: QMP connection closed")).into())
+            }
+        };
+
+        debug!(command, id, "QMP execute");
+        let write_result = {
+            let mut writer = self.writer.lock().await;
+            match writer.write_all(payload.as_bytes()).await {
+                Ok(()) => writer.flush().await,
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = write_result {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(VmError::QmpError(format!("{command}: write failed: {e}")).into());
+        }
+
+        match rx.await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(VmError::QmpError(format!("{command}: {e}")).into()),
+            Err(_) => Err(VmError::QmpError(format!("{command}: QMP connection closed")).into()),
+        }
+    }
+
+    /// Whether QEMU closed the connection (and every message before that was handled).
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_finished()
+    }
+
+    /// Subscribe to asynchronous QMP events received from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<QmpEvent> {
+        self.events.subscribe()
+    }
+
+    /// QEMU version string from the server greeting (e.g. "9.2.0").
+    pub fn version(&self) -> String {
+        let qemu = &self.greeting["version"]["qemu"];
+        format!(
+            "{}.{}.{}",
+            qemu["major"].as_u64().unwrap_or(0),
+            qemu["minor"].as_u64().unwrap_or(0),
+            qemu["micro"].as_u64().unwrap_or(0)
+        )
+    }
+
+    /// Send an ACPI power button press to the guest, resuming it first if paused.
+    pub async fn system_powerdown(&self) -> Result<(), VirtualGhostError> {
+        // A paused guest would never see the button press
+        if self.query_status().await? == "paused" {
+            self.cont().await?;
+        }
+        self.execute("system_powerdown", None).await?;
+        Ok(())
+    }
+
+    /// Pause the guest's vCPUs.
+    pub async fn stop(&self) -> Result<(), VirtualGhostError> {
+        self.execute("stop", None).await?;
+        Ok(())
+    }
+
+    /// Resume the guest's vCPUs.
+    pub async fn cont(&self) -> Result<(), VirtualGhostError> {
+        self.execute("cont", None).await?;
+        Ok(())
+    }
+
+    /// Make QEMU exit right away, without shutting the guest down.
+    pub async fn quit(&self) -> Result<(), VirtualGhostError> {
+        match self.execute("quit", None).await {
+            Ok(_) => Ok(()),
+            // QEMU may exit before its reply makes it out
+            Err(_) if self.is_closed() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Start saving the VM state to `uri`; follow it with [`Self::query_migrate`].
+    pub async fn migrate(&self, uri: &str) -> Result<(), VirtualGhostError> {
+        self.execute("migrate", Some(json!({ "uri": uri }))).await?;
+        Ok(())
+    }
+
+    /// Progress of the current (or last) migration.
+    pub async fn query_migrate(&self) -> Result<MigrationInfo, VirtualGhostError> {
+        let info = self.execute("query-migrate", None).await?;
+        Ok(MigrationInfo {
+            status: info["status"].as_str().unwrap_or("none").to_string(),
+            transferred: info["ram"]["transferred"].as_u64().unwrap_or(0),
+            error: info["error-desc"].as_str().map(str::to_string),
+        })
+    }
+
+    /// Ask the guest to use `bytes` of memory, inflating or deflating the balloon.
+    pub async fn balloon(&self, bytes: u64) -> Result<(), VirtualGhostError> {
+        self.execute("balloon", Some(json!({ "value": bytes }))).await?;
+        Ok(())
+    }
+
+    /// Memory the guest currently has, in bytes, after the balloon.
+    pub async fn query_balloon(&self) -> Result<u64, VirtualGhostError> {
+        let info = self.execute("query-balloon", None).await?;
+        info["actual"]
+            .as_u64()
+            .ok_or_else(|| VmError::QmpError("query-balloon: missing actual size".into()).into())
+    }
+
+    /// Read a QOM property.
+    pub async fn qom_get(&self, path: &str, property: &str) -> Result<Value, VirtualGhostError> {
+        self.execute("qom-get", Some(json!({ "path": path, "property": property })))
+            .await
+    }
+
+    /// Set a QOM property.
+    pub async fn qom_set(
+        &self,
+        path: &str,
+        property: &str,
+        value: Value,
+    ) -> Result<(), VirtualGhostError> {
+        self.execute(
+            "qom-set",
+            Some(json!({ "path": path, "property": property, "value": value })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// vCPU slots of a VM started with `maxcpus`, plugged or not.
+    pub async fn query_hotpluggable_cpus(&self) -> Result<Vec<HotpluggableCpu>, VirtualGhostError> {
+        let cpus = self.execute("query-hotpluggable-cpus", None).await?;
+        Ok(cpus
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|cpu| HotpluggableCpu {
+                driver: cpu["type"].as_str().unwrap_or_default().to_string(),
+                props: cpu["props"].as_object().cloned().unwrap_or_default(),
+                qom_path: cpu["qom-path"].as_str().map(str::to_string),
+            })
+            .collect())
+    }
+
+    /// DIMMs and other memory devices plugged into the VM.
+    pub async fn query_memory_devices(&self) -> Result<Vec<MemoryDevice>, VirtualGhostError> {
+        let devices = self.execute("query-memory-devices", None).await?;
+        Ok(devices
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|device| MemoryDevice {
+                id: device["data"]["id"].as_str().map(str::to_string),
+                size: device["data"]["size"].as_u64().unwrap_or(0),
+            })
+            .collect())
+    }
+
+    /// Hot-plug a device.
+    pub async fn device_add(
+        &self,
+        driver: &str,
+        id: &str,
+        mut props: Map<String, Value>,
+    ) -> Result<(), VirtualGhostError> {
+        props.insert("driver".into(), driver.into());
+        props.insert("id".into(), id.into());
+        self.execute("device_add", Some(Value::Object(props))).await?;
+        Ok(())
+    }
+
+    /// Create a backend object, such as the memory behind a DIMM.
+    pub async fn object_add(
+        &self,
+        qom_type: &str,
+        id: &str,
+        mut props: Map<String, Value>,
+    ) -> Result<(), VirtualGhostError> {
+        props.insert("qom-type".into(), qom_type.into());
+        props.insert("id".into(), id.into());
+        self.execute("object-add", Some(Value::Object(props))).await?;
+        Ok(())
+    }
+
+    /// Remove a backend object created with [`Self::object_add`].
+    pub async fn object_del(&self, id: &str) -> Result<(), VirtualGhostError> {
+        self.execute("object-del", Some(json!({ "id": id }))).await?;
+        Ok(())
+    }
+
+    /// Query the VM run state (e.g. "running", "paused", "shutdown").
+    pub async fn query_status(&self) -> Result<String, VirtualGhostError> {
+        let status = self.execute("query-status", None).await?;
+        Ok(status["status"].as_str().unwrap_or("unknown").to_string())
+    }
+}
+
+impl Drop for QmpClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{DuplexStream, Lines, ReadHalf, WriteHalf};
+
+    /// The greeting of QEMU 9.2.0.
+    fn greeting() -> String {
+        let version = json!({ "major": 9, "minor": 2, "micro": 0 });
+        json!({ "QMP": { "version": { "qemu": version }, "capabilities": [] } }).to_string()
+    }
+
+    /// A command's successful response.
+    fn reply(command: &Value, ret: Value) -> String {
+        json!({ "return": ret, "id": command["id"] }).to_string()
+    }
+
+    /// QEMU's end of an in-memory QMP connection.
+    struct FakeQemu {
+        lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl FakeQemu {
+        async fn send(&mut self, line: &str) {
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+            self.writer.write_all(b"\n").await.unwrap();
+        }
+
+        /// The next command the client executes.
+        async fn command(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    /// A client connected to a fake QEMU 9.2.0 that has answered the handshake.
+    async fn connect() -> (QmpClient, FakeQemu) {
+        let (client, server) = tokio::io::duplex(4096);
+        let (read_half, writer) = tokio::io::split(server);
+        let mut qemu = FakeQemu {
+            lines: BufReader::new(read_half).lines(),
+            writer,
+        };
+        let handshake = tokio::spawn(QmpClient::handshake(client));
+        qemu.send(&greeting()).await;
+        let command = qemu.command().await;
+        assert_eq!(command["execute"], "qmp_capabilities");
+        qemu.send(&reply(&command, json!({}))).await;
+        (handshake.await.unwrap().unwrap(), qemu)
+    }
+
+    #[tokio::test]
+    async fn negotiates_capabilities_and_reads_the_version() {
+        let (client, _qemu) = connect().await;
+        assert_eq!(client.version(), "9.2.0");
+        assert!(!client.is_closed());
+    }
+
+    #[tokio::test]
+    async fn routes_responses_by_id_past_events_and_noise() {
+        let (client, mut qemu) = connect().await;
+        let mut events = client.subscribe();
+
+        let first = client.execute("query-status", None);
+        let second = client.execute("query-balloon", None);
+        let server = async {
+            let status = qemu.command().await;
+            let balloon = qemu.command().await;
+            let timestamp = json!({ "seconds": 12, "microseconds": 34 });
+            let stop = json!({ "event": "STOP", "data": {}, "timestamp": timestamp });
+            qemu.send(&stop.to_string()).await;
+            qemu.send("not json").await;
+            qemu.send(r#"{"return": {}}"#).await;
+            qemu.send(r#"{"return": {}, "id": 999}"#).await;
+            // Out of order
+            qemu.send(&reply(&balloon, json!({ "actual": 1024 }))).await;
+            qemu.send(&reply(&status, json!({ "status": "paused" })))
+                .await;
+        };
+        let (first, second, ()) = tokio::join!(first, second, server);
+        assert_eq!(first.unwrap(), json!({ "status": "paused" }));
+        assert_eq!(second.unwrap(), json!({ "actual": 1024 }));
+
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.event, "STOP");
+        assert_eq!(event.timestamp, (12, 34));
+    }
+
+    #[tokio::test]
+    async fn reports_qemu_errors() {
+        let (client, mut qemu) = connect().await;
+        let server = async {
+            let command = qemu.command().await;
+            assert_eq!(command["arguments"], json!({ "value": 1 }));
+            let error = json!({ "class": "GenericError", "desc": "no balloon device" });
+            qemu.send(&json!({ "error": error, "id": command["id"] }).to_string())
+                .await;
+        };
+        let (result, ()) = tokio::join!(
+            client.execute("balloon", Some(json!({ "value": 1 }))),
+            server
+        );
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.contains("balloon: GenericError: no balloon device"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_commands_in_flight_when_qemu_goes_away() {
+        let (client, mut qemu) = connect().await;
+        let server = async {
+            qemu.command().await;
+            drop(qemu);
+        };
+        let (result, ()) = tokio::join!(client.execute("query-status", None), server);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("QMP connection closed"), "{error}");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Nothing will answer commands issued after the connection closed
+        let late =
+            tokio::time::timeout(Duration::from_secs(5), client.execute("query-status", None));
+        let error = late.await.unwrap().unwrap_err().to_string();
+        assert!(
+            error.contains("query-status: QMP connection closed"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_greeting() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        server.write_all(b"{\"hello\": 1}\n").await.unwrap();
+        let error = QmpClient::handshake(client).await.err().unwrap();
+        assert!(error.to_string().contains("unexpected greeting"), "{error}");
+    }
+}
@@ -0,0 +1,84 @@
+use crate::error::{NetworkError, VmError, VirtualGhostError};
+use crate::network::{AgentStatus, GuestEndpoint};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+use super::serial;
+
+/// Delay between agent probes while the guest boots.
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Serial console lines included in a boot timeout error.
+const SERIAL_TAIL_LINES: usize = 20;
+
+/// Wait until the guest agent reports its boot complete on `status`, or
+/// answers a probe for agents that do not send events, returning how long the
+/// boot took.
+///
+/// Fails with [`VmError::BootTimeout`] (carrying the tail of `serial_log`)
+/// if the agent is still silent after `timeout`.
+pub async fn wait_until_ready(
+    endpoint: GuestEndpoint,
+    timeout: Duration,
+    serial_log: Option<PathBuf>,
+    status: Option<watch::Receiver<AgentStatus>>,
+) -> Result<Duration, VirtualGhostError> {
+    info!(%endpoint, timeout_secs = timeout.as_secs(), "Waiting for guest agent");
+    let start = Instant::now();
+    tokio::select! {
+        ready = probe_until_ready(&endpoint, start, timeout, serial_log) => ready,
+        () = boot_complete(status) => {
+            let elapsed = start.elapsed();
+            info!(%endpoint, elapsed_ms = elapsed.as_millis() as u64, "Guest agent reports boot complete");
+            Ok(elapsed)
+        }
+    }
+}
+
+/// Resolves once the agent reports its boot complete; never without `status`
+/// or once its listener is gone.
+async fn boot_complete(status: Option<watch::Receiver<AgentStatus>>) {
+    if let Some(mut status) = status {
+        if status.wait_for(|status| status.boot_complete).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Poll the guest agent until it answers.
+async fn probe_until_ready(
+    endpoint: &GuestEndpoint,
+    start: Instant,
+    timeout: Duration,
+    serial_log: Option<PathBuf>,
+) -> Result<Duration, VirtualGhostError> {
+    let deadline = start + timeout;
+    loop {
+        match endpoint.probe().await {
+            Ok(()) => {
+                let elapsed = start.elapsed();
+                info!(%endpoint, elapsed_ms = elapsed.as_millis() as u64, "Guest agent ready");
+                return Ok(elapsed);
+            }
+            Err(e @ VirtualGhostError::Network(NetworkError::VsockUnavailable(_))) => return Err(e),
+            Err(e) => debug!(error = %e, "Guest agent not ready yet"),
+        }
+
+        if Instant::now() + PROBE_INTERVAL >= deadline {
+            let serial_tail = serial_log
+                .as_deref()
+                .map(|path| serial::tail_lines(path, SERIAL_TAIL_LINES))
+                .unwrap_or_default();
+            return Err(VmError::BootTimeout {
+                timeout_secs: timeout.as_secs(),
+                serial_tail,
+            }
+            .into());
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
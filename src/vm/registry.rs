@@ -0,0 +1,113 @@
+use crate::config::VirtualGhostConfig;
+use crate::error::{VirtualGhostError, VmError};
+use std::fs::{File, TryLockError};
+use tracing::{info, warn};
+
+use super::instance::{InstanceDir, InstanceState};
+
+/// CIDs 0-2 are reserved (hypervisor, local, host).
+pub const FIRST_GUEST_CID: u32 = 3;
+
+/// How many CIDs to try before giving up.
+const CID_SEARCH_LIMIT: u32 = 4096;
+
+/// Taken by a launch from checking its instance name is free until the VM's
+/// state is written, so concurrent launches cannot claim the same name, CID
+/// or ports. Released on drop.
+pub struct RegistryLock {
+    _file: File,
+}
+
+/// Wait until no other launch is claiming an instance, then lock the registry.
+pub fn lock() -> Result<RegistryLock, VirtualGhostError> {
+    let root = VirtualGhostConfig::instances_dir();
+    std::fs::create_dir_all(&root)?;
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(root.join(".lock"))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            info!("Waiting for another VM to finish starting");
+            file.lock()?;
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    Ok(RegistryLock { _file: file })
+}
+
+/// All VMs whose QEMU is still alive, sorted by name. Stale entries are cleaned up.
+pub fn running() -> Result<Vec<(InstanceDir, InstanceState)>, VirtualGhostError> {
+    let mut running = Vec::new();
+    for instance in InstanceDir::list()? {
+        match instance.running_state() {
+            Ok(Some(state)) => running.push((instance, state)),
+            Ok(None) => {}
+            Err(e) => warn!(instance = instance.name(), error = %e, "Skipping instance"),
+        }
+    }
+    Ok(running)
+}
+
+/// Pick a guest CID that no running VM uses, `preferred` if it is free.
+///
+/// On Linux the kernel is asked too, so CIDs held by VMs started outside
+/// VirtualGhost are skipped.
+pub fn allocate_cid(
+    running: &[(InstanceDir, InstanceState)],
+    preferred: Option<u32>,
+) -> Result<u32, VirtualGhostError> {
+    preferred
+        .into_iter()
+        .chain(FIRST_GUEST_CID..FIRST_GUEST_CID + CID_SEARCH_LIMIT)
+        .find(|cid| {
+            !running
+                .iter()
+                .any(|(_, state)| state.vsock_cid == Some(*cid))
+                && cid_available(*cid)
+        })
+        .ok_or_else(|| VmError::Exhausted("vsock CID").into())
+}
+
+/// Pick a free loopback TCP port for QEMU to listen on.
+pub fn allocate_port() -> Result<u16, VirtualGhostError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Try to claim `cid` on a scratch vhost-vsock device; the claim is released
+/// when the device is closed, right before QEMU takes it.
+#[cfg(target_os = "linux")]
+fn cid_available(cid: u32) -> bool {
+    use std::os::fd::AsRawFd;
+
+    // _IOW(VHOST_VIRTIO, 0x60, __u64)
+    const VHOST_VSOCK_SET_GUEST_CID: u64 = 0x4008_af60;
+
+    let device = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/vhost-vsock")
+    {
+        Ok(device) => device,
+        // Can't ask the kernel; QEMU will report a conflict when it starts
+        Err(_) => return true,
+    };
+    let guest_cid = cid as u64;
+    // SAFETY: the ioctl reads a u64 through a valid pointer on an open vhost-vsock fd.
+    let ret = unsafe {
+        nix::libc::ioctl(
+            device.as_raw_fd(),
+            VHOST_VSOCK_SET_GUEST_CID as _,
+            &guest_cid,
+        )
+    };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() != Some(nix::libc::EADDRINUSE)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cid_available(_cid: u32) -> bool {
+    true
+}
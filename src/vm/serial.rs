@@ -0,0 +1,396 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Only the end of the log is scanned when collecting the tail.
+const TAIL_WINDOW: u64 = 64 * 1024;
+
+/// Console output without a newline (e.g. a login prompt) is written out after this much quiet.
+pub const PARTIAL_LINE_FLUSH: Duration = Duration::from_millis(200);
+
+/// Longer lines are split so a runaway console cannot grow one without bound.
+const MAX_LINE: usize = 4096;
+
+/// Delay between checks for new output while following the log.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Length of the `2026-01-31T12:00:00.000Z` prefix on each recorded line.
+const TIMESTAMP_LEN: usize = 24;
+
+/// A VM's serial console log.
+///
+/// Each line is prefixed with the UTC time it was received. The current output
+/// is in `serial.log`; once it reaches `max_bytes` (or the VM boots again) it
+/// moves to `serial.log.1`, with up to `keep` older files behind it.
+pub struct SerialLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl SerialLog {
+    pub fn new(path: PathBuf, max_bytes: u64, keep: usize) -> Self {
+        Self {
+            path,
+            max_bytes,
+            keep,
+        }
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+
+    /// Existing log files, oldest first.
+    fn files(&self) -> Vec<PathBuf> {
+        (1..=self.keep)
+            .rev()
+            .map(|index| self.rotated(index))
+            .chain(std::iter::once(self.path.clone()))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    /// Move the current log aside, dropping the oldest file beyond `keep`.
+    pub fn rotate(&self) -> io::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        for index in (1..self.keep).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))
+    }
+
+    /// Start recording console output into the current log file.
+    pub fn recorder(&self) -> io::Result<Recorder<'_>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok(Recorder {
+            log: self,
+            file,
+            size,
+            line: Vec::new(),
+        })
+    }
+
+    /// Write the recorded lines received at or after `since` to `out`, without timestamps.
+    pub fn print(&self, since: Option<SystemTime>, out: &mut impl Write) -> io::Result<()> {
+        let since = since.map(format_timestamp);
+        for path in self.files() {
+            let mut buf = Vec::new();
+            match File::open(&path) {
+                Ok(mut file) => file.read_to_end(&mut buf)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            print_lines(&buf, since.as_deref(), out)?;
+        }
+        out.flush()
+    }
+
+    /// Like [`Self::print`], then keep printing new lines for as long as `running` holds.
+    pub async fn follow(
+        &self,
+        since: Option<SystemTime>,
+        out: &mut impl Write,
+        mut running: impl FnMut() -> bool,
+    ) -> io::Result<()> {
+        let since = since.map(format_timestamp);
+        for path in self.files() {
+            if path != self.path {
+                let mut buf = Vec::new();
+                if let Ok(mut file) = File::open(&path) {
+                    file.read_to_end(&mut buf)?;
+                }
+                print_lines(&buf, since.as_deref(), out)?;
+            }
+        }
+
+        let mut current: Option<File> = None;
+        let mut pending = Vec::new();
+        loop {
+            // Start over on the new file once the logger rotates (or starts a new boot)
+            let reopen = match &current {
+                Some(file) => replaced(file, &self.path),
+                None => true,
+            };
+            if reopen {
+                if let Some(file) = current.as_mut() {
+                    file.read_to_end(&mut pending)?;
+                }
+                current = File::open(&self.path).ok();
+            }
+            if let Some(file) = current.as_mut() {
+                file.read_to_end(&mut pending)?;
+            }
+            if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+                print_lines(&pending[..=end], since.as_deref(), out)?;
+                out.flush()?;
+                pending.drain(..=end);
+            }
+
+            if !running() {
+                return Ok(());
+            }
+            tokio::time::sleep(FOLLOW_INTERVAL).await;
+        }
+    }
+}
+
+/// Record a VM's serial console from the first connection to `serial` until
+/// QEMU disconnects. For hosts without Unix sockets, which have no console to
+/// attach to; elsewhere `serve_console` does this and more.
+///
+/// The log file is created before waiting for QEMU, telling the launcher
+/// that `serial` listens.
+#[cfg_attr(unix, allow(dead_code))]
+pub async fn record_serial(
+    log: &SerialLog,
+    serial: tokio::net::TcpListener,
+    connect_timeout: Duration,
+) -> io::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let mut recorder = log.recorder()?;
+    let (mut qemu, _) = tokio::time::timeout(connect_timeout, serial.accept())
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "QEMU did not connect to the serial console port",
+            )
+        })??;
+    drop(serial);
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = if recorder.has_partial() {
+            match tokio::time::timeout(PARTIAL_LINE_FLUSH, qemu.read(&mut buf)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    recorder.flush_partial()?;
+                    continue;
+                }
+            }
+        } else {
+            qemu.read(&mut buf).await
+        };
+        match read? {
+            0 => break,
+            n => recorder.feed(&buf[..n])?,
+        }
+    }
+    recorder.flush_partial()
+}
+
+/// Splits console output into timestamped lines in the current log file,
+/// rotating at the size cap.
+pub struct Recorder<'a> {
+    log: &'a SerialLog,
+    file: File,
+    size: u64,
+    /// Output received since the last newline.
+    line: Vec<u8>,
+}
+
+impl Recorder<'_> {
+    /// Record console output; complete lines are written out immediately.
+    pub fn feed(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for &byte in bytes {
+            match byte {
+                b'\n' => self.end_line()?,
+                b'\r' => {}
+                _ => {
+                    self.line.push(byte);
+                    if self.line.len() >= MAX_LINE {
+                        self.end_line()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether output without a trailing newline is waiting to be written.
+    pub fn has_partial(&self) -> bool {
+        !self.line.is_empty()
+    }
+
+    /// Write out pending output as a line of its own (e.g. a login prompt).
+    pub fn flush_partial(&mut self) -> io::Result<()> {
+        if self.has_partial() {
+            self.end_line()?;
+        }
+        Ok(())
+    }
+
+    fn end_line(&mut self) -> io::Result<()> {
+        let entry = format!(
+            "{} {}\n",
+            format_timestamp(SystemTime::now()),
+            String::from_utf8_lossy(&self.line)
+        );
+        self.line.clear();
+        if self.size > 0 && self.size + entry.len() as u64 > self.log.max_bytes {
+            self.log.rotate()?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.log.path)?;
+            self.size = 0;
+        }
+        self.file.write_all(entry.as_bytes())?;
+        self.size += entry.len() as u64;
+        Ok(())
+    }
+}
+
+/// Whether `path` no longer names the file behind `file`.
+fn replaced(file: &File, path: &Path) -> bool {
+    let (Ok(open), Ok(named)) = (file.metadata(), std::fs::metadata(path)) else {
+        return true;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        (open.dev(), open.ino()) != (named.dev(), named.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        named.len() < open.len()
+    }
+}
+
+fn print_lines(buf: &[u8], since: Option<&str>, out: &mut impl Write) -> io::Result<()> {
+    for line in String::from_utf8_lossy(buf).lines() {
+        let (timestamp, text) = split_timestamp(line);
+        // Lines without a timestamp (e.g. written by QEMU directly) are always shown
+        if since.is_some_and(|since| timestamp.is_some_and(|ts| ts < since)) {
+            continue;
+        }
+        writeln!(out, "{text}")?;
+    }
+    Ok(())
+}
+
+/// Split a recorded line into its timestamp, if it has one, and the console text.
+fn split_timestamp(line: &str) -> (Option<&str>, &str) {
+    let bytes = line.as_bytes();
+    let stamped = bytes.len() > TIMESTAMP_LEN
+        && bytes[TIMESTAMP_LEN] == b' '
+        && bytes[10] == b'T'
+        && bytes[TIMESTAMP_LEN - 1] == b'Z'
+        && bytes[..4].iter().all(u8::is_ascii_digit);
+    if stamped {
+        (Some(&line[..TIMESTAMP_LEN]), &line[TIMESTAMP_LEN + 1..])
+    } else {
+        (None, line)
+    }
+}
+
+/// Format as RFC 3339 UTC with milliseconds; the fixed width makes these sort as strings.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Return the last `count` non-empty lines of a serial console log, without timestamps.
+///
+/// Missing or unreadable logs yield an empty list — the tail is diagnostic only.
+pub fn tail_lines(path: &Path, count: usize) -> Vec<String> {
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return Vec::new();
+    };
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    if file
+        .seek(SeekFrom::Start(len.saturating_sub(TAIL_WINDOW)))
+        .is_err()
+    {
+        return Vec::new();
+    }
+
+    let mut buf = Vec::new();
+    if file.read_to_end(&mut buf).is_err() {
+        return Vec::new();
+    }
+
+    let text = String::from_utf8_lossy(&buf);
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| split_timestamp(line).1.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    lines[lines.len().saturating_sub(count)..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn record_serial_writes_timestamped_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = SerialLog::new(dir.path().join("serial.log"), 1024 * 1024, 1);
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let qemu = tokio::spawn(async move {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+            stream.write_all(b"booting\r\nlogin: ").await.unwrap();
+        });
+        record_serial(&log, listener, Duration::from_secs(5))
+            .await
+            .unwrap();
+        qemu.await.unwrap();
+
+        let mut out = Vec::new();
+        log.print(None, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "booting\nlogin: \n");
+        let recorded = std::fs::read_to_string(dir.path().join("serial.log")).unwrap();
+        assert!(recorded
+            .lines()
+            .all(|line| split_timestamp(line).0.is_some()));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::error::{VirtualGhostError, VmError};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+use super::models::CpusConfig;
+use super::qmp::QmpClient;
+
+/// Delay between migration progress checks.
+const MIGRATION_POLL: Duration = Duration::from_millis(100);
+
+/// Loading a saved state is bounded by disk speed; this only catches a stuck QEMU.
+const INCOMING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What a saved VM state depends on: QEMU only loads a state into an
+/// identically configured machine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MachineSignature {
+    pub qemu_bin: PathBuf,
+    pub accel: String,
+    pub cpus: CpusConfig,
+    pub memory_mib: u32,
+    pub max_memory_mib: u32,
+    pub memory_slots: u32,
+    pub kernel: String,
+    pub rootfs: String,
+    /// UEFI firmware code, when booting through it instead of the kernel directly.
+    #[serde(default)]
+    pub firmware: Option<PathBuf>,
+    /// `-device` arguments, without host-side details that may change between runs.
+    pub devices: Vec<String>,
+}
+
+impl MachineSignature {
+    /// What differs from the machine a state was saved on, one entry per setting.
+    pub fn differences(&self, saved: &Self) -> Vec<String> {
+        let mut diffs = Vec::new();
+        let mut check = |what: &str, saved: String, now: String| {
+            if saved != now {
+                diffs.push(format!("{what}: saved with {saved}, now {now}"));
+            }
+        };
+        check(
+            "QEMU binary",
+            saved.qemu_bin.display().to_string(),
+            self.qemu_bin.display().to_string(),
+        );
+        check("accelerator", saved.accel.clone(), self.accel.clone());
+        let cpus = |cpus: &CpusConfig| format!("{} (max {})", cpus.boot_vcpus, cpus.max_vcpus);
+        check("vCPUs", cpus(&saved.cpus), cpus(&self.cpus));
+        let memory = |machine: &Self| {
+            format!(
+                "{} MiB (max {} MiB, {} slots)",
+                machine.memory_mib, machine.max_memory_mib, machine.memory_slots
+            )
+        };
+        check("memory", memory(saved), memory(self));
+        check("kernel", saved.kernel.clone(), self.kernel.clone());
+        check("rootfs", saved.rootfs.clone(), self.rootfs.clone());
+        let firmware = |firmware: &Option<PathBuf>| match firmware {
+            Some(path) => path.display().to_string(),
+            None => "direct kernel boot".to_string(),
+        };
+        check("firmware", firmware(&saved.firmware), firmware(&self.firmware));
+        check("devices", saved.devices.join(" "), self.devices.join(" "));
+        diffs
+    }
+}
+
+/// Stored next to a suspended VM's state file to check it can be resumed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuspendInfo {
+    pub machine: MachineSignature,
+    /// QEMU version that wrote the state; loading needs the same one.
+    pub qemu_version: String,
+    /// Client key the guest agent still accepts.
+    pub identity: PathBuf,
+    /// CID the guest had, reused on resume when still free.
+    pub vsock_cid: Option<u32>,
+    /// Rootfs modification time when the state was saved, to catch changes in between.
+    pub rootfs_modified: Option<SystemTime>,
+    /// When the state was saved, in seconds since the Unix epoch.
+    pub saved_at: u64,
+}
+
+impl SuspendInfo {
+    /// Refuse to resume on a machine that differs from the saved one, or a
+    /// rootfs that changed underneath the saved guest.
+    pub fn check(&self, machine: &MachineSignature) -> Result<(), VirtualGhostError> {
+        let mut problems = machine.differences(&self.machine);
+        if modified(Path::new(&machine.rootfs)) != self.rootfs_modified {
+            problems.push(format!(
+                "rootfs {} changed since the VM was suspended",
+                machine.rootfs
+            ));
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(VmError::Suspend(format!(
+            "the saved state does not match this configuration:\n  - {}",
+            problems.join("\n  - ")
+        ))
+        .into())
+    }
+}
+
+/// Modification time of a file, if it can be read.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Migration URI reading or writing `path`. The `file:` transport arrived in QEMU 8.2.
+pub fn file_uri(qemu_version: &str, path: &Path) -> Result<String, VirtualGhostError> {
+    let mut parts = qemu_version
+        .split('.')
+        .map(|n| n.parse::<u32>().unwrap_or(0));
+    let version = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    if version < (8, 2) {
+        return Err(VmError::Suspend(format!(
+            "QEMU {qemu_version} cannot save a VM to a file (8.2 or newer is needed)"
+        ))
+        .into());
+    }
+    Ok(format!("file:{}", path.display()))
+}
+
+/// Pause the VM and write its RAM and device state to `image`.
+///
+/// Returns the number of RAM bytes written. On failure the guest is resumed.
+pub async fn save_state(qmp: &QmpClient, image: &Path) -> Result<u64, VirtualGhostError> {
+    let uri = file_uri(&qmp.version(), image)?;
+    qmp.stop().await?;
+    let result = match qmp.migrate(&uri).await {
+        Ok(()) => wait_for_migration(qmp).await,
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = std::fs::remove_file(image);
+        let _ = qmp.cont().await;
+    }
+    result
+}
+
+async fn wait_for_migration(qmp: &QmpClient) -> Result<u64, VirtualGhostError> {
+    loop {
+        let info = qmp.query_migrate().await?;
+        match info.status.as_str() {
+            "completed" => return Ok(info.transferred),
+            "failed" | "cancelled" => {
+                return Err(VmError::Suspend(info.error.unwrap_or(info.status)).into())
+            }
+            status => debug!(status, transferred = info.transferred, "Saving VM state"),
+        }
+        tokio::time::sleep(MIGRATION_POLL).await;
+    }
+}
+
+/// Wait for a QEMU started with `-incoming` to load the saved state, then
+/// let the guest run again.
+pub async fn finish_incoming(qmp: &QmpClient) -> Result<(), VirtualGhostError> {
+    let deadline = tokio::time::Instant::now() + INCOMING_TIMEOUT;
+    loop {
+        match qmp.query_status().await?.as_str() {
+            "inmigrate" => {}
+            "running" => return Ok(()),
+            // The state was saved paused
+            _ => return qmp.cont().await,
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(VmError::Suspend("timed out loading the saved state".into()).into());
+        }
+        tokio::time::sleep(MIGRATION_POLL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine() -> MachineSignature {
+        MachineSignature {
+            qemu_bin: "/usr/bin/qemu-system-x86_64".into(),
+            accel: "kvm".into(),
+            cpus: CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 4,
+            },
+            memory_mib: 2048,
+            max_memory_mib: 4096,
+            memory_slots: 4,
+            kernel: "/cache/vmlinux".into(),
+            rootfs: "/cache/rootfs.ext4".into(),
+            firmware: None,
+            devices: vec!["virtio-vga-gl".into(), "vhost-vsock-pci".into()],
+        }
+    }
+
+    #[test]
+    fn identical_machines_have_no_differences() {
+        assert!(machine().differences(&machine()).is_empty());
+    }
+
+    #[test]
+    fn each_changed_setting_is_one_difference() {
+        let saved = machine();
+        let mut now = machine();
+        now.cpus.boot_vcpus = 4;
+        now.memory_slots = 8;
+        now.firmware = Some("/usr/share/qemu/edk2-x86_64-code.fd".into());
+        now.devices.pop();
+        assert_eq!(
+            now.differences(&saved),
+            [
+                "vCPUs: saved with 2 (max 4), now 4 (max 4)",
+                concat!(
+                    "memory: saved with 2048 MiB (max 4096 MiB, 4 slots), ",
+                    "now 2048 MiB (max 4096 MiB, 8 slots)"
+                ),
+                "firmware: saved with direct kernel boot, now /usr/share/qemu/edk2-x86_64-code.fd",
+                "devices: saved with virtio-vga-gl vhost-vsock-pci, now virtio-vga-gl",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_catches_a_rootfs_changed_since_the_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs.ext4");
+        std::fs::write(&rootfs, b"").unwrap();
+        let mut machine = machine();
+        machine.rootfs = rootfs.display().to_string();
+        let mut info = SuspendInfo {
+            machine: machine.clone(),
+            qemu_version: "9.2.0".into(),
+            identity: dir.path().join("id_ed25519"),
+            vsock_cid: Some(3),
+            rootfs_modified: modified(&rootfs),
+            saved_at: 0,
+        };
+        assert!(info.check(&machine).is_ok());
+
+        info.rootfs_modified = Some(SystemTime::UNIX_EPOCH);
+        let error = info.check(&machine).unwrap_err().to_string();
+        assert!(
+            error.contains("changed since the VM was suspended"),
+            "{error}"
+        );
+
+        machine.accel = "tcg".into();
+        let error = info.check(&machine).unwrap_err().to_string();
+        assert!(
+            error.contains("accelerator: saved with kvm, now tcg"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn file_uri_needs_qemu_8_2() {
+        let path = Path::new("/data/suspend.img");
+        for version in ["8.2.0", "8.2.1", "9.0.0", "10.0.0"] {
+            assert_eq!(file_uri(version, path).unwrap(), "file:/data/suspend.img");
+        }
+        for version in ["8.1.5", "7.2.0", "6.2", ""] {
+            assert!(file_uri(version, path).is_err(), "{version}");
+        }
+    }
+}